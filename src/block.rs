@@ -3,11 +3,17 @@ use log::info;
 use sha2::{Sha256, Digest};
 use crate::errors::Result;
 use crate::transaction::Transaction;
+use crate::tx::TXOutput;
+use crate::encoding::{Reader, Writer, BLOCK_VERSION};
+use crate::util::decode_hex;
 use serde::{Serialize, Deserialize};
 use merkle_cbt::merkle_tree::Merge;
 use merkle_cbt::merkle_tree::CBMT;
 
-const TARGET_HEXT: usize = 2;
+pub const TARGET_HEXT: usize = 2;
+
+// Start of the coinbase data output committing to the witnesses of the block.
+pub const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
 
 #[derive(Debug,Clone,Serialize,Deserialize)]
 pub struct Block {
@@ -37,6 +43,11 @@ impl Block {
         return self.prev_block_hash.clone();
     }
 
+    // Milliseconds since the unix epoch.
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
     pub fn new_genesis_block(coinbase: Transaction) -> Block {
         Block::new(vec![coinbase], String::new(), 0).unwrap()
     }
@@ -55,55 +66,192 @@ impl Block {
             height,
             nonce: 0,
         };
+        block.add_witness_commitment()?;
         block.run_proof_if_work()?;
         Ok(block)
     }
 
-    fn run_proof_if_work(&mut self) -> Result<()> {
+    // Assemble an unsolved block from a template handed out by the node.
+    // The coinbase is built by the miner and goes in front of the template transactions.
+    // Caller need to run the proof of work before submitting it.
+    pub fn from_template(template: &BlockTemplate, coinbase: Transaction) -> Result<Block> {
+        let mut transactions = vec![coinbase];
+        transactions.extend(template.transactions.iter().cloned());
+        let mut block = Block {
+            timestamp: template.timestamp,
+            transactions,
+            prev_block_hash: template.prev_block_hash.clone(),
+            hash: String::new(),
+            height: template.height,
+            nonce: 0,
+        };
+        block.add_witness_commitment()?;
+        Ok(block)
+    }
+
+    // When a transaction has a witness, the coinbase commits to the witness merkle root
+    // in a data output: WITNESS_COMMITMENT_HEADER || root.
+    fn add_witness_commitment(&mut self) -> Result<()> {
+        if !self.transactions.iter().any(|tx| tx.has_witness()) {
+            return Ok(());
+        }
+        let mut commitment = WITNESS_COMMITMENT_HEADER.to_vec();
+        commitment.extend(self.witness_root()?);
+        let coinbase = &mut self.transactions[0];
+        coinbase.vout.push(TXOutput::new_data(&commitment)?);
+        coinbase.id = coinbase.hash()?;
+        Ok(())
+    }
+
+    // Check the coinbase commitment, a block without one can not carry witnesses.
+    pub fn check_witness_commitment(&self) -> Result<()> {
+        let committed = self.transactions.first().and_then(|coinbase| {
+            coinbase.vout.iter().rev().find_map(|out| match out.script_pub_key.data() {
+                Some(data) if data.len() == 36 && data.starts_with(&WITNESS_COMMITMENT_HEADER) => Some(data[4..].to_vec()),
+                _ => None,
+            })
+        });
+        match committed {
+            Some(root) if root != self.witness_root()? => {
+                anyhow::bail!("ERROR: Witness commitment does not match the witnesses of the block")
+            }
+            None if self.transactions.iter().any(|tx| tx.has_witness()) => {
+                anyhow::bail!("ERROR: Block has witnesses but its coinbase does not commit to them")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Merkle root of the raw wtxids, the coinbase counts as zeros since it holds the commitment.
+    pub fn witness_root(&self) -> Result<Vec<u8>> {
+        let mut leaves = vec![vec![0; 32]];
+        for tx in self.transactions.iter().skip(1) {
+            leaves.push(decode_hex(&tx.wtxid()?)?);
+        }
+        let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&leaves);
+        Ok(tree.root())
+    }
+
+    // Used when the nonce was found somewhere else, e.g. by a pool worker.
+    pub fn set_nonce(&mut self, nonce: i32) -> Result<()> {
+        self.nonce = nonce;
+        self.hash = self.calculate_hash()?;
+        Ok(())
+    }
+
+    pub fn run_proof_if_work(&mut self) -> Result<()> {
         info!("Mining the block");
 
         // This is the place need power machine.
-        while !self.validate()? {
+        // The transactions do not change between tries, hash them once.
+        let tx_hash = self.hash_transactions()?;
+        while !hash_meets_target(&self.prepare_hash_data_with(&tx_hash)?, TARGET_HEXT) {
             self.nonce += 1;
         }
 
-        let data: Vec<u8> = self.prepare_hash_data()?;
-        let mut hasher: Sha256 = Sha256::new();
-        hasher.update(&data[..]);
-        self.hash = format!("{:X}", hasher.finalize());
+        self.hash = self.calculate_hash()?;
         Ok(())
     }
 
+    // Check the block carries a hash which is really the hash of its content
+    // and that the hash meets the PoW target.
+    pub fn is_solved(&self) -> Result<bool> {
+        Ok(self.hash == self.calculate_hash()? && self.validate()?)
+    }
+
     fn validate(&self) -> Result<bool> {
+        self.meets_target(TARGET_HEXT)
+    }
+
+    // Dummy PoW: the first `target` bytes of the hash must all be '0'.
+    pub fn meets_target(&self, target: usize) -> Result<bool> {
+        Ok(hash_meets_target(&self.prepare_hash_data()?, target))
+    }
+
+    fn calculate_hash(&self) -> Result<String> {
         let data: Vec<u8> = self.prepare_hash_data()?;
         let mut hasher: Sha256 = Sha256::new();
         hasher.update(&data[..]);
-
-        // Dummy PoW
-        let mut vec1: Vec<u8> = vec![];
-        vec1.resize(TARGET_HEXT, '0' as u8);
-
-        //Compare hash.
-        Ok(&hasher.finalize()[0..TARGET_HEXT] == &vec1[0..TARGET_HEXT])
+        Ok(format!("{:X}", hasher.finalize()))
     }
 
     // Decide the properties that needs include in the hash.
     fn prepare_hash_data(&self) -> Result<Vec<u8>> {
-        let content = (
-            self.prev_block_hash.clone(),
-            self.hash_transactions()?,
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce
-        );
-        let bytes: Vec<u8> = bincode::serialize(&content)?;
-        Ok(bytes)
+        self.prepare_hash_data_with(&self.hash_transactions()?)
+    }
+
+    // The block header, see encoding.rs.
+    fn prepare_hash_data_with(&self, tx_hash: &[u8]) -> Result<Vec<u8>> {
+        let mut writer = Writer::new();
+        self.write_header(&mut writer, tx_hash)?;
+        Ok(writer.into_bytes())
+    }
+
+    fn write_header(&self, writer: &mut Writer, tx_hash: &[u8]) -> Result<()> {
+        writer.u32(BLOCK_VERSION);
+        writer.hash(&self.prev_block_hash)?;
+        writer.bytes(tx_hash);
+        writer.u64(self.timestamp as u64);
+        writer.u32(self.height as u32);
+        writer.u32(TARGET_HEXT as u32);
+        writer.u32(self.nonce as u32);
+        Ok(())
+    }
+
+    // Canonical encoding of the whole block, which is also how it is stored.
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut writer = Writer::new();
+        self.write_header(&mut writer, &self.hash_transactions()?)?;
+        writer.var_int(self.transactions.len() as u64);
+        for tx in &self.transactions {
+            tx.write(&mut writer)?;
+        }
+        Ok(writer.into_bytes())
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Block> {
+        let mut reader = Reader::new(data);
+        let version = reader.u32()?;
+        if version != BLOCK_VERSION {
+            anyhow::bail!("ERROR: Unknown block version {}", version)
+        }
+        let prev_block_hash = reader.hash()?;
+        let tx_hash = reader.take(32)?.to_vec();
+        let timestamp = reader.u64()? as u128;
+        let height = reader.u32()? as i32;
+        let target = reader.u32()?;
+        if target != TARGET_HEXT as u32 {
+            anyhow::bail!("ERROR: Unexpected block target {}", target)
+        }
+        let nonce = reader.u32()? as i32;
+        let mut transactions = Vec::new();
+        for _ in 0..reader.var_int()? {
+            transactions.push(Transaction::read(&mut reader)?);
+        }
+        if !reader.is_empty() {
+            anyhow::bail!("ERROR: Trailing data after block at height {}", height)
+        }
+
+        let mut block = Block {
+            timestamp,
+            transactions,
+            prev_block_hash,
+            hash: String::new(),
+            height,
+            nonce,
+        };
+        if block.hash_transactions()? != tx_hash {
+            anyhow::bail!("ERROR: Merkle root does not match the transactions of block at height {}", height)
+        }
+        block.hash = block.calculate_hash()?;
+        Ok(block)
     }
 
+    // Merkle root of the raw txids.
     fn hash_transactions(&self) -> Result<Vec<u8>> {
         let mut transactions = Vec::new();
         for tx in &self.transactions {
-            transactions.push(tx.hash()?.as_bytes().to_owned());
+            transactions.push(decode_hex(&tx.hash()?)?);
         }
         let tree = CBMT::<Vec<u8>, MergeTX>::build_merkle_tree(&*transactions);
 
@@ -111,6 +259,29 @@ impl Block {
     }
 }
 
+// Everything an external miner needs to build and solve the next block.
+// The miner creates its own coinbase paying `coinbase_value`.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct BlockTemplate {
+    pub prev_block_hash: String,
+    pub height: i32,
+    pub timestamp: u128,
+    pub target: usize,
+    pub transactions: Vec<Transaction>,
+    pub coinbase_value: i32,
+}
+
+fn hash_meets_target(data: &[u8], target: usize) -> bool {
+    let mut hasher: Sha256 = Sha256::new();
+    hasher.update(data);
+
+    let mut vec1: Vec<u8> = vec![];
+    vec1.resize(target, b'0');
+
+    //Compare hash.
+    hasher.finalize()[0..target] == vec1[0..target]
+}
+
 struct MergeTX {}
 
 impl Merge for MergeTX {
@@ -123,4 +294,69 @@ impl Merge for MergeTX {
         hasher.update(&data[..]);
         return hasher.finalize().to_vec();
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::tx::TXInput;
+    use crate::util::encode_hex;
+
+    #[test]
+    fn test_golden_header() {
+        let coinbase = Transaction::new_coinbase_outputs(
+            301,
+            0,
+            String::from("golden"),
+            vec![TXOutput { value: 100, script_pub_key: Script::new_p2pkh(&[0x33; 20]) }],
+        )
+        .unwrap();
+        let mut block = Block {
+            timestamp: 1_700_000_000_000,
+            transactions: vec![coinbase],
+            prev_block_hash: String::from("00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF"),
+            hash: String::new(),
+            height: 301,
+            nonce: 0,
+        };
+        block.set_nonce(12345).unwrap();
+        assert_eq!(
+            encode_hex(&block.prepare_hash_data().unwrap()),
+            "0100000000112233445566778899aabbccddeeff00112233445566778899aabbccddeeff56f9dbbf1fe988ca718f52e0f29d\
+             ecd063f0c024475457c1dc4e484c140b3bdc0068e5cf8b0100002d0100000200000039300000"
+        );
+        assert_eq!(block.get_hash(), "BE75365AD6B277E870E8A7D4221C604F503D96E965DC67DB08F333383A0EB5B2");
+
+        let decoded = Block::deserialize(&block.serialize().unwrap()).unwrap();
+        assert_eq!(decoded.get_hash(), block.get_hash());
+        assert_eq!(decoded.get_transactions()[0].id, block.get_transactions()[0].id);
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let coinbase = Transaction::new_coinbase_outputs(1, 0, String::new(), Vec::new()).unwrap();
+        let mut spend = Transaction {
+            id: String::new(),
+            vin: vec![TXInput::new(coinbase.id.clone(), 0)],
+            vout: vec![TXOutput { value: 100, script_pub_key: Script::new_p2pkh(&[0x33; 20]) }],
+            lock_time: 0,
+        };
+        spend.vin[0].witness = vec![vec![1; 65], vec![2; 32]];
+        spend.id = spend.hash().unwrap();
+        let mut block = Block {
+            timestamp: 0,
+            transactions: vec![coinbase, spend],
+            prev_block_hash: String::new(),
+            hash: String::new(),
+            height: 1,
+            nonce: 0,
+        };
+        assert!(block.check_witness_commitment().is_err());
+        block.add_witness_commitment().unwrap();
+        block.check_witness_commitment().unwrap();
+
+        // Swapping the witness keeps the txid, not the commitment.
+        block.transactions[1].vin[0].witness[0] = vec![3; 65];
+        assert!(block.check_witness_commitment().is_err());
+    }
+}
@@ -2,14 +2,11 @@ use std::collections::HashMap;
 use log::info;
 
 use crate::errors::Result;
+use crate::chainparams::{data_dir, params};
 use crate::block::Block;
-use crate::transaction::{Transaction};
+use crate::transaction::{Transaction, SUBSIDY};
 use crate::tx::{TXOutputs};
 
-const TARGET_HEXT: usize = 4;
-const DB_NAME: &str = "data/blocks";
-const GENESIS_COINBASE_DATA: &str = "Initial Coin";
-
 #[derive(Debug)]
 pub struct Blockchain {
     current_hash: String,
@@ -25,7 +22,7 @@ impl Blockchain {
     pub fn new() -> Result<Blockchain> {
         info!("open blockchain");
 
-        let db = sled::open(DB_NAME)?;
+        let db = sled::open(db_name())?;
         let hash = db
             .get("LAST")?
             .expect("Must create a new block database first");
@@ -39,15 +36,15 @@ impl Blockchain {
     }
     pub fn create_blockchain(address: String) -> Result<Blockchain> {
         info!("Creating new blockchain");
-        if let Err(_) = std::fs::remove_dir_all(DB_NAME) {
+        if std::fs::remove_dir_all(db_name()).is_err() {
             info!("Blockchain does not exists.");
         }
-        let db = sled::open(DB_NAME)?;
+        let db = sled::open(db_name())?;
         info!("Create new block database");
 
-        let cbtx = Transaction::new_coinbase(address, String::from(GENESIS_COINBASE_DATA))?;
+        let cbtx = Transaction::new_coinbase(address, String::from(params().genesis_coinbase_data), 0)?;
         let genesis = Block::new_genesis_block(cbtx);
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
+        db.insert(genesis.get_hash(), genesis.serialize()?)?;
         db.insert("LAST", genesis.get_hash().as_bytes())?;
         let bc = Blockchain {
             current_hash: genesis.get_hash(),
@@ -69,9 +66,49 @@ impl Blockchain {
     //     Ok(new_block)
     // }
 
+    pub fn get_tip(&self) -> String {
+        self.current_hash.clone()
+    }
+
+    // Whether the block is stored, on the current chain or not.
+    pub fn has_block(&self, block_hash: &str) -> Result<bool> {
+        Ok(self.db.contains_key(block_hash)?)
+    }
+
+    // Store the genesis block of another chain, e.g. of a peer, to switch to its chain.
+    pub fn store_genesis(&mut self, block: &Block) -> Result<()> {
+        let transactions = block.get_transactions();
+        if block.get_height() != 0 || !block.get_prev_block_hash().is_empty() || transactions.len() != 1 {
+            anyhow::bail!("ERROR: Block {} is not a genesis block", block.get_hash())
+        }
+        if !block.is_solved()? {
+            anyhow::bail!("ERROR: Invalid proof of work")
+        }
+        check_coinbase_height(&transactions[0], 0)?;
+        let reward: i32 = transactions[0].vout.iter().map(|out| out.value).sum();
+        if reward > SUBSIDY {
+            anyhow::bail!("ERROR: Coinbase pays {} but only {} is allowed", reward, SUBSIDY)
+        }
+        self.db.insert(block.get_hash(), block.serialize()?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    // Make a stored block the tip, e.g. the fork point of a longer branch.
+    // The blocks after it stay stored, the UTXO set must be reindexed.
+    pub fn set_tip(&mut self, block_hash: &str) -> Result<()> {
+        if !self.has_block(block_hash)? {
+            anyhow::bail!("ERROR: Block {} is not stored", block_hash)
+        }
+        self.db.insert("LAST", block_hash.as_bytes())?;
+        self.db.flush()?;
+        self.current_hash = block_hash.to_string();
+        Ok(())
+    }
+
     pub fn get_block(&self, block_hash: &str) -> Result<Block> {
         let data = self.db.get(block_hash)?.unwrap();
-        let block = bincode::deserialize(&data.to_vec())?;
+        let block = Block::deserialize(&data)?;
         Ok(block)
     }
 
@@ -84,32 +121,17 @@ impl Blockchain {
         list
     }
 
-    // block suppose to be the last/newest block
-    pub fn add_block(&mut self, block: Block) -> Result<()> {
-        let data = bincode::serialize(&block)?;
-        if let Some(_) = self.db.get(block.get_hash())? {
-            return Ok(());
-        }
-        self.db.insert(block.get_hash(), data)?;
-
-        let lastheight = self.get_best_height()?;
-        if block.get_height() > lastheight {
-            self.db.insert("LAST", block.get_hash().as_bytes())?;
-            self.current_hash = block.get_hash();
-            self.db.flush()?;
-        }
-
-        Ok(())
-    }
-
+    // The inputs are checked against the UTXO set by UTXOSet::mine_block.
     pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
         info!("mine a new block");
 
+        let height = self.get_best_height()? + 1;
         for tx in &transactions {
             // Verify if transactions are valid.
-            if !self.verify_transaction(tx)? {
-                anyhow::bail!("ERROR: Invalid transaction")
+            if tx.is_coinbase() {
+                check_coinbase_height(tx, height)?;
             }
+            self.verify_transaction(tx)?;
         }
 
         let lasthash = self.db.get("LAST")?.unwrap();
@@ -117,14 +139,34 @@ impl Blockchain {
         let newblock = Block::new(
             transactions,
             String::from_utf8(lasthash.to_vec())?,
-            self.get_best_height()? + 1,
+            height,
         )?;
-        self.db.insert(newblock.get_hash(), bincode::serialize(&newblock)?)?;
-        self.db.insert("LAST", newblock.get_hash().as_bytes())?;
+        self.connect_block(&newblock)?;
+        Ok(newblock)
+    }
+
+    // Store the block and make it the tip of the chain.
+    pub fn connect_block(&mut self, block: &Block) -> Result<()> {
+        self.db.insert(block.get_hash(), block.serialize()?)?;
+        self.db.insert("LAST", block.get_hash().as_bytes())?;
         self.db.flush()?;
 
-        self.current_hash = newblock.get_hash();
-        Ok(newblock)
+        self.current_hash = block.get_hash();
+        Ok(())
+    }
+
+    // Value of the referenced outputs minus value of the new outputs.
+    pub fn get_transaction_fee(&self, tx: &Transaction) -> Result<i32> {
+        if tx.is_coinbase() {
+            return Ok(0);
+        }
+        let prev_txs = self.get_prev_TXs(tx)?;
+        let mut input = 0;
+        for vin in &tx.vin {
+            input += prev_txs[&vin.txid].vout[vin.vout as usize].value;
+        }
+        let output: i32 = tx.vout.iter().map(|out| out.value).sum();
+        Ok(input - output)
     }
 
     pub fn get_best_height(&self) -> Result<i32> {
@@ -134,7 +176,7 @@ impl Blockchain {
             return Ok(-1);
         };
         let last_data = self.db.get(lasthash)?.unwrap();
-        let last_block: Block = bincode::deserialize(&last_data.to_vec())?;
+        let last_block = Block::deserialize(&last_data)?;
         Ok(last_block.get_height())
     }
 
@@ -204,20 +246,16 @@ impl Blockchain {
                             continue;
                         }
                     }
-
-                    match utxos.get_mut(&tx.id) {
-                        Some(v) => {
-                            v.outputs.push(tx.vout[idx].clone());
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.id.clone(),
-                                TXOutputs {
-                                    outputs: vec![tx.vout[idx].clone()],
-                                }
-                            );
-                        }
+                    // Data outputs can not be spent, they are not kept.
+                    if tx.vout[idx].script_pub_key.is_unspendable() {
+                        continue;
                     }
+
+                    utxos
+                        .entry(tx.id.clone())
+                        .or_insert_with(|| TXOutputs::new(tx.is_coinbase(), block.get_height()))
+                        .outputs
+                        .insert(idx as i32, tx.vout[idx].clone());
                 }
                 if !tx.is_coinbase() {
                     for i in &tx.vin {
@@ -238,11 +276,23 @@ impl Blockchain {
 
     // Return a Transaction with associated id
     pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
+        let block = self.find_transaction_block(id)?;
+        Ok(block.get_transactions().iter().find(|tx| tx.id == id).unwrap().clone())
+    }
+
+    // Block of the current chain at `height`, found walking back from the tip.
+    pub fn get_block_at_height(&self, height: i32) -> Result<Block> {
+        match self.iter().find(|block| block.get_height() <= height) {
+            Some(block) if block.get_height() == height => Ok(block),
+            _ => anyhow::bail!("ERROR: No block at height {}", height),
+        }
+    }
+
+    // Return the block containing the transaction with associated id
+    pub fn find_transaction_block(&self, id: &str) -> Result<Block> {
         for b in self.iter() {
-            for tx in b.get_transactions() {
-                if tx.id == id {
-                    return Ok(tx.clone());
-                }
+            if b.get_transactions().iter().any(|tx| tx.id == id) {
+                return Ok(b);
             }
         }
         anyhow::bail!("Transaction is not found")
@@ -255,7 +305,7 @@ impl Blockchain {
         private_key: &[u8]
     ) -> Result<()> {
         let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.sign(private_key, prev_TXs);
+        tx.sign(private_key, prev_TXs)?;
         Ok(())
     }
 
@@ -269,11 +319,30 @@ impl Blockchain {
         Ok(prev_TXs)
     }
 
-    // Verify transaction input signature.
-    pub fn verify_transaction(&self, tx: &Transaction) -> Result<bool> {
-        let prev_TXs = self.get_prev_TXs(tx)?;
-        tx.verify(prev_TXs)
+    // Verify transaction inputs, the error tells what is wrong.
+    pub fn verify_transaction(&self, tx: &Transaction) -> Result<()> {
+        if tx.id != tx.hash()? {
+            anyhow::bail!("ERROR: Transaction id {} does not match its content", tx.id)
+        }
+        // Coinbase has no previous transaction to look up, only its outputs are checked.
+        if tx.is_coinbase() {
+            return tx.verify(&HashMap::new());
+        }
+        let prev_txs = self.get_prev_TXs(tx)?;
+        tx.verify(&prev_txs)
+    }
+}
+
+fn db_name() -> String {
+    format!("{}/blocks", data_dir())
+}
+
+// The coinbase must commit to the height of its block, see Transaction::new_coinbase_outputs.
+pub fn check_coinbase_height(tx: &Transaction, height: i32) -> Result<()> {
+    if tx.coinbase_height() != Some(height) {
+        anyhow::bail!("ERROR: Coinbase {} does not commit to the block height {}", tx.id, height)
     }
+    Ok(())
 }
 
 impl<'a> Iterator for BlockchainIter<'a> {
@@ -283,7 +352,7 @@ impl<'a> Iterator for BlockchainIter<'a> {
         if let Ok(encode_block) = self.bc.db.get(&self.current_hash) {
             return match encode_block {
                 Some(bytes) => {
-                    if let Ok(block) = bincode::deserialize::<Block>(&bytes) {
+                    if let Ok(block) = Block::deserialize(&bytes) {
                         self.current_hash = block.get_prev_block_hash();
                         Some(block)
                     } else {
@@ -298,13 +367,30 @@ impl<'a> Iterator for BlockchainIter<'a> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::Wallet;
+
+    // A chain in data_root() mined to `wallet`, `blocks` blocks after the genesis.
+    pub fn new_chain(wallet: &Wallet, blocks: i32) -> UTXOSet {
+        let mut utxo_set = UTXOSet::new(Blockchain::create_blockchain(wallet.get_address()).unwrap());
+        utxo_set.reindex().unwrap();
+        for height in 1..=blocks {
+            let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), height).unwrap();
+            let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+            utxo_set.update(&block).unwrap();
+        }
+        utxo_set
+    }
+
     #[test]
+    #[ignore = "needs a chain in data/blocks, run `create` first"]
     fn test_blockchain() {
         let mut block_chain = Blockchain::new().unwrap();
-        block_chain.add_block("data".to_string());
+        let height = block_chain.get_best_height().unwrap() + 1;
+        let cbtx = Transaction::new_coinbase(Wallet::new().get_address(), String::from("data"), height).unwrap();
+        block_chain.mine_block(vec![cbtx]).unwrap();
         dbg!(block_chain);
     }
 }
\ No newline at end of file
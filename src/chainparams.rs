@@ -0,0 +1,110 @@
+use std::sync::OnceLock;
+
+use crate::errors::Result;
+
+// Parameters telling apart independent instances of the chain,
+// e.g. two chains used for a cross-chain atomic swap.
+// Wallets are shared by every chain, a key can be used on each of them.
+#[derive(Debug)]
+pub struct ChainParams {
+    pub name: &'static str,
+    // Directory of the block and UTXO databases, under data_root().
+    pub data_dir: &'static str,
+    // Different genesis blocks keep the chains apart.
+    pub genesis_coinbase_data: &'static str,
+    // Central node, transactions are sent to it and new nodes sync from it.
+    pub known_node: &'static str,
+    // Blocks needed on top of a coinbase before its outputs can be spent,
+    // so that a reorg can not erase coins already spent downstream.
+    pub coinbase_maturity: i32,
+    // First byte of exported secret keys (Wallet::export_secret_key),
+    // so that a key of one chain is not imported into another by mistake.
+    pub secret_key_prefix: u8,
+}
+
+pub const MAIN: ChainParams = ChainParams {
+    name: "main",
+    data_dir: "",
+    genesis_coinbase_data: "Initial Coin",
+    known_node: "localhost:3000",
+    coinbase_maturity: 10,
+    secret_key_prefix: 0x80,
+};
+
+pub const TEST: ChainParams = ChainParams {
+    name: "test",
+    data_dir: "test",
+    genesis_coinbase_data: "Initial Test Coin",
+    known_node: "localhost:4000",
+    coinbase_maturity: 5,
+    secret_key_prefix: 0xef,
+};
+
+const CHAINS: [&ChainParams; 2] = [&MAIN, &TEST];
+
+static SELECTED: OnceLock<&'static ChainParams> = OnceLock::new();
+
+// Pick the chain this process works on, before anything opens a database.
+pub fn select(name: &str) -> Result<()> {
+    let params = match CHAINS.iter().find(|c| c.name == name) {
+        Some(params) => *params,
+        None => anyhow::bail!("ERROR: Unknown chain `{}`", name),
+    };
+    if SELECTED.set(params).is_err() {
+        anyhow::bail!("ERROR: Chain is already selected")
+    }
+    Ok(())
+}
+
+// The selected chain, main unless another one was selected.
+pub fn params() -> &'static ChainParams {
+    SELECTED.get_or_init(|| &MAIN)
+}
+
+// Directory of every database: the chains, in their data_dir, and the wallet files.
+pub fn data_root() -> String {
+    #[cfg(test)]
+    if let Some(root) = TEST_DATA_ROOT.with(|root| root.borrow().clone()) {
+        return root;
+    }
+    String::from("data")
+}
+
+// Directory of the block and UTXO databases of the selected chain.
+pub fn data_dir() -> String {
+    match params().data_dir {
+        "" => data_root(),
+        dir => format!("{}/{}", data_root(), dir),
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_DATA_ROOT: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+// Tests run in parallel threads, each one gets its own data_root() in the temporary
+// directory while the guard lives, and the directory is removed after.
+#[cfg(test)]
+pub struct TestDataRoot(String);
+
+#[cfg(test)]
+impl TestDataRoot {
+    pub fn new(name: &str) -> TestDataRoot {
+        let root = std::env::temp_dir()
+            .join(format!("blockchain_rust_{}_{}", name, std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let _ = std::fs::remove_dir_all(&root);
+        TEST_DATA_ROOT.with(|current| *current.borrow_mut() = Some(root.clone()));
+        TestDataRoot(root)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDataRoot {
+    fn drop(&mut self) {
+        TEST_DATA_ROOT.with(|current| *current.borrow_mut() = None);
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
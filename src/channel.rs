@@ -0,0 +1,216 @@
+use std::fs;
+use serde::{Serialize, Deserialize};
+
+use crate::errors::Result;
+use crate::script::Script;
+use crate::transaction::{Transaction, SEQUENCE_FINAL};
+use crate::tx::{TXInput, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallet;
+
+// Unidirectional payment channel (Script::new_channel), kept by both sides as a json file.
+// The payer locks the capacity in a funding output, then pays off-chain by signing
+// commitments which give the payee a higher share of it, and hands over the file.
+// The payee closes with the latest commitment, adding their own signature, before the
+// timeout. After it, the payer can take back whatever was not closed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PaymentChannel {
+    pub payer_pub_key: Vec<u8>,
+    pub payee_pub_key: Vec<u8>,
+    pub funding_txid: String,
+    pub funding_vout: i32,
+    pub capacity: i32,
+    pub timeout: u32,
+    // Paid to the payee by the latest commitment.
+    pub paid: i32,
+    // Payer signature of the latest commitment, empty before the first payment.
+    pub payer_signature: Vec<u8>,
+}
+
+impl PaymentChannel {
+    // Payer side: the funding transaction locking `capacity` until `timeout`.
+    pub fn open(
+        payer: &Wallet,
+        payee_pub_key: Vec<u8>,
+        capacity: i32,
+        timeout: u32,
+        utxo_set: &UTXOSet,
+    ) -> Result<(PaymentChannel, Transaction)> {
+        let script = Script::new_channel(&payer.public_key, &payee_pub_key, timeout);
+        let tx = Transaction::new_utxo_with_outputs(
+            payer,
+            vec![TXOutput { value: capacity, script_pub_key: script }],
+            0,
+            utxo_set,
+        )?;
+        let channel = PaymentChannel {
+            payer_pub_key: payer.public_key.clone(),
+            payee_pub_key,
+            funding_txid: tx.id.clone(),
+            funding_vout: 0,
+            capacity,
+            timeout,
+            paid: 0,
+            payer_signature: Vec::new(),
+        };
+        Ok((channel, tx))
+    }
+
+    pub fn load(path: &str) -> Result<PaymentChannel> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn payer(&self) -> String {
+        Wallet::get_pub_key_address(&self.payer_pub_key)
+    }
+
+    pub fn payee(&self) -> String {
+        Wallet::get_pub_key_address(&self.payee_pub_key)
+    }
+
+    pub fn script(&self) -> Script {
+        Script::new_channel(&self.payer_pub_key, &self.payee_pub_key, self.timeout)
+    }
+
+    // Payer side: raise the payee's share by `amount`, no transaction is mined.
+    pub fn pay(&mut self, payer: &Wallet, amount: i32) -> Result<()> {
+        if payer.public_key != self.payer_pub_key {
+            anyhow::bail!("ERROR: `{}` is not the payer of this channel", payer.get_address())
+        }
+        if amount <= 0 || self.paid + amount > self.capacity {
+            anyhow::bail!(
+                "ERROR: Can not pay {}, {} of {} is left in the channel",
+                amount, self.capacity - self.paid, self.capacity
+            )
+        }
+        let tx = self.commitment(self.paid + amount)?;
+        self.payer_signature = tx.sign_input(0, &payer.secret_key, &self.script())?;
+        self.paid += amount;
+        Ok(())
+    }
+
+    // Payee side: spend the funding output with the latest commitment.
+    pub fn close(&self, payee: &Wallet) -> Result<Transaction> {
+        if payee.public_key != self.payee_pub_key {
+            anyhow::bail!("ERROR: `{}` is not the payee of this channel", payee.get_address())
+        }
+        if self.paid == 0 {
+            anyhow::bail!("ERROR: Nothing was paid in this channel")
+        }
+        let mut tx = self.commitment(self.paid)?;
+        if !tx.check_signature(0, &self.payer_signature, &self.payer_pub_key, &self.script()) {
+            anyhow::bail!("ERROR: Invalid payer signature for the latest payment")
+        }
+        let payee_signature = tx.sign_input(0, &payee.secret_key, &self.script())?;
+        tx.vin[0].witness = Script::new_channel_close(&self.payer_signature, &payee_signature).to_witness()?;
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    // Payer side: take the whole funding output back, valid from the timeout.
+    pub fn refund(&self, payer: &Wallet) -> Result<Transaction> {
+        if payer.public_key != self.payer_pub_key {
+            anyhow::bail!("ERROR: `{}` is not the payer of this channel", payer.get_address())
+        }
+        let mut input = TXInput::new(self.funding_txid.clone(), self.funding_vout);
+        input.sequence = SEQUENCE_FINAL - 1;
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![input],
+            vout: vec![TXOutput::new(self.capacity, self.payer())?],
+            lock_time: self.timeout,
+        };
+        tx.id = tx.hash()?;
+        let signature = tx.sign_input(0, &payer.secret_key, &self.script())?;
+        tx.vin[0].witness = Script::new_channel_refund(&signature).to_witness()?;
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    // Unsigned transaction paying `paid` to the payee and the rest back to the payer.
+    fn commitment(&self, paid: i32) -> Result<Transaction> {
+        let mut vout = vec![TXOutput::new(paid, self.payee())?];
+        if self.capacity > paid {
+            vout.push(TXOutput::new(self.capacity - paid, self.payer())?);
+        }
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput::new(self.funding_txid.clone(), self.funding_vout)],
+            vout,
+            lock_time: 0,
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Channel of capacity 100 timing out at height 50, funded by a coinbase.
+    fn setup(payer: &Wallet, payee: &Wallet) -> (PaymentChannel, HashMap<String, Transaction>) {
+        let mut funding = Transaction::new_coinbase(payer.get_address(), String::from("test"), 1).unwrap();
+        funding.vout[0].script_pub_key = Script::new_channel(&payer.public_key, &payee.public_key, 50);
+        funding.id = funding.hash().unwrap();
+        let channel = PaymentChannel {
+            payer_pub_key: payer.public_key.clone(),
+            payee_pub_key: payee.public_key.clone(),
+            funding_txid: funding.id.clone(),
+            funding_vout: 0,
+            capacity: funding.vout[0].value,
+            timeout: 50,
+            paid: 0,
+            payer_signature: Vec::new(),
+        };
+        (channel, HashMap::from([(funding.id.clone(), funding)]))
+    }
+
+    #[test]
+    fn test_close_with_latest_payment() {
+        let (payer, payee) = (Wallet::new(), Wallet::new());
+        let (mut channel, prev_txs) = setup(&payer, &payee);
+        channel.pay(&payer, 10).unwrap();
+        channel.pay(&payer, 15).unwrap();
+        assert!(channel.pay(&payer, 100).is_err());
+
+        let tx = channel.close(&payee).unwrap();
+        tx.verify(&prev_txs).unwrap();
+        assert_eq!(tx.vout[0].value, 25);
+        assert_eq!(tx.vout[1].value, channel.capacity - 25);
+    }
+
+    #[test]
+    fn test_payee_can_not_raise_the_payment() {
+        let (payer, payee) = (Wallet::new(), Wallet::new());
+        let (mut channel, prev_txs) = setup(&payer, &payee);
+        channel.pay(&payer, 10).unwrap();
+
+        channel.paid = 20;
+        assert!(channel.close(&payee).is_err());
+
+        // Same with a hand made transaction.
+        let mut tx = channel.commitment(20).unwrap();
+        let payee_signature = tx.sign_input(0, &payee.secret_key, &channel.script()).unwrap();
+        tx.vin[0].script_sig = Script::new_channel_close(&channel.payer_signature, &payee_signature);
+        assert!(tx.verify(&prev_txs).is_err());
+    }
+
+    #[test]
+    fn test_refund_after_timeout() {
+        let (payer, payee) = (Wallet::new(), Wallet::new());
+        let (channel, prev_txs) = setup(&payer, &payee);
+        let tx = channel.refund(&payer).unwrap();
+        tx.verify(&prev_txs).unwrap();
+        // The refund can not be mined before the timeout.
+        assert!(!tx.is_final(50, 0));
+        assert!(tx.is_final(51, 0));
+        assert!(channel.refund(&payee).is_err());
+    }
+}
@@ -1,13 +1,31 @@
-use clap::{ Command, arg };
+use clap::{ ArgMatches, Command, arg };
+use std::collections::HashSet;
 use std::process::exit;
+use std::thread;
+use std::time::Duration;
 use bitcoincash_addr::{Address};
+use rand::Rng;
 
+use crate::block::Block;
 use crate::blockchain::Blockchain;
 use crate::errors::Result;
-use crate::wallet::Wallets;
-use crate::transaction::Transaction;
+use crate::wallet::{self, Wallet, Wallets};
+use crate::transaction::{Transaction, SIGHASH_ALL};
+use crate::tx::TXOutput;
+use crate::script::Script;
+use crate::multisig::MultisigTx;
+use crate::psbt::{self, Psbt};
+use crate::history::History;
+use crate::coinselect::{CoinSelection, Strategy};
+use crate::payments::Payment;
+use crate::htlc;
+use crate::mnemonic;
+use crate::channel::PaymentChannel;
+use crate::chainparams;
+use crate::util::{decode_hex, encode_hex};
 use crate::utxoset::UTXOSet;
 use crate::server::Server;
+use crate::pool::{Pool, ShareResult};
 
 pub struct Cli {
 }
@@ -23,6 +41,8 @@ impl Cli {
             .version("0.1")
             .author("xuerong@nanopay.net")
             .about("blockchain in rust: a simple blockchain for learning")
+            .arg(arg!(--chain <NAME> " 'chain to work on: main (default) or test'").global(true))
+            .arg(arg!(--wallet <NAME> " 'loaded wallet file to work on, the default one otherwise'").global(true))
             .subcommand(
                 Command::new("print-chain")
                     .about("print all the chain blocks.")
@@ -44,10 +64,66 @@ impl Cli {
                     .arg(arg!(<TO> " 'Destination wallet address'"))
                     .arg(arg!(<AMOUNT> " 'Amount to send'"))
                     .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .arg(arg!(--"lock-time" <LOCK> " 'not mined before this height, or unix time'"))
+                    .arg(arg!(--"vest-until" <LOCK> " 'TO can only spend it from this height, or unix time'"))
+                    .arg(arg!(--"vest-for" <BLOCKS> " 'TO can only spend it BLOCKS blocks after it is mined'"))
+                    .arg(arg!(--data <HEX> " 'also anchor this data in an unspendable output'"))
+                    .arg(arg!(--"coin-selection" <STRATEGY> " 'bnb (default), largest-first or oldest-first'"))
+                    .arg(arg!(--utxo <COIN> ... " 'spend this txid:vout output, repeat for more'"))
+                    .arg(arg!(--"fee-rate" <RATE> " 'fee per 1000 bytes, 0 by default'"))
+            )
+            .subcommand(
+                Command::new("send-many")
+                    .about("pay many addresses in one transaction")
+                    .arg(arg!(<FROM>" 'Source wallet address'"))
+                    .arg(arg!([PAYMENTS] ... " 'ADDRESS:AMOUNT payments'"))
+                    .arg(arg!(--file <FILE> " 'also the payments of a CSV (address,amount lines) or JSON file'"))
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+                    .arg(arg!(--"coin-selection" <STRATEGY> " 'bnb (default), largest-first or oldest-first'"))
+                    .arg(arg!(--utxo <COIN> ... " 'spend this txid:vout output, repeat for more'"))
+                    .arg(arg!(--"fee-rate" <RATE> " 'fee per 1000 bytes, 0 by default'"))
+            )
+            .subcommand(
+                Command::new("generate")
+                    .about("mine COUNT empty blocks, e.g. to let coinbase outputs mature")
+                    .arg(arg!(<COUNT>" 'number of blocks'"))
+                    .arg(arg!(<ADDRESS>" 'wallet address receiving the rewards'"))
             )
             .subcommand(
                 Command::new("create-wallet")
-                    .about("create a wallet")
+                    .about("create a wallet, the next address derived from the wallet seed")
+                    .arg(arg!(--account <ACCOUNT>" 'account of the address, 0 by default'"))
+                    .arg(arg!(--mnemonic " 'make the wallet seed from a new mnemonic, printed for backup'"))
+                    .arg(arg!(--passphrase " 'also read a mnemonic passphrase from stdin'"))
+            )
+            .subcommand(
+                Command::new("restore-wallet")
+                    .about("restore the wallet seed from a mnemonic read from stdin and rescan the chain")
+                    .arg(arg!(--passphrase " 'also read the mnemonic passphrase from stdin'"))
+                    .arg(arg!(--seed " 'read a hex seed printed by export-seed instead of a mnemonic'"))
+            )
+            .subcommand(
+                Command::new("export-seed")
+                    .about("print the wallet seed in hex, restore-wallet --seed derives every address again from it")
+            )
+            .subcommand(
+                Command::new("create-wallet-file")
+                    .about("create a new wallet file NAME, apart from the others, and load it")
+                    .arg(arg!(<NAME>" 'wallet name'"))
+            )
+            .subcommand(
+                Command::new("load-wallet")
+                    .about("load the wallet file NAME, for --wallet NAME")
+                    .arg(arg!(<NAME>" 'wallet name'"))
+            )
+            .subcommand(
+                Command::new("unload-wallet")
+                    .about("unload the wallet file NAME, it is also locked")
+                    .arg(arg!(<NAME>" 'wallet name'"))
+            )
+            .subcommand(
+                Command::new("list-wallets")
+                    .about("list the wallet files, loaded or not")
             )
             .subcommand(
                 Command::new("list-addresses")
@@ -68,11 +144,222 @@ impl Cli {
                     .arg(arg!(<PORT>" 'the port server bind to locally'"))
                     .arg(arg!(<ADDRESS>" 'wallet address'"))
             )
+            .subcommand(
+                Command::new("start-external-miner")
+                    .about("mine blocks from the templates of a node, without a local chain")
+                    .arg(arg!(<NODE>" 'address of the node, e.g. localhost:3000'"))
+                    .arg(arg!(<ADDRESS>" 'wallet address receiving the reward'"))
+            )
+            .subcommand(
+                Command::new("start-pool")
+                    .about("start a mining pool in front of a node")
+                    .arg(arg!(<PORT>" 'the port pool bind to locally'"))
+                    .arg(arg!(<NODE>" 'address of the node, e.g. localhost:3000'"))
+                    .arg(arg!(<ADDRESS>" 'pool operator wallet address'"))
+            )
+            .subcommand(
+                Command::new("start-pool-worker")
+                    .about("mine shares for a pool")
+                    .arg(arg!(<POOL>" 'address of the pool, e.g. localhost:3333'"))
+                    .arg(arg!(<ADDRESS>" 'wallet address credited with the shares'"))
+            )
+            .subcommand(
+                Command::new("create-multisig")
+                    .about("create a M-of-N multisig address")
+                    .arg(arg!(<M>" 'number of signatures needed'"))
+                    .arg(arg!(<KEYS> ... " 'wallet addresses or hex public keys'"))
+            )
+            .subcommand(
+                Command::new("sign-multisig")
+                    .about("sign a transaction spending from a multisig address")
+                    .arg(arg!(<FILE>" 'transaction file shared between keyholders'"))
+                    .arg(arg!(<SIGNER>" 'wallet address of the signing key'"))
+                    .arg(arg!(--from <ADDRESS> " 'create FILE spending from this multisig address'"))
+                    .arg(arg!(--to <ADDRESS> " 'destination of the new transaction'"))
+                    .arg(arg!(--amount <AMOUNT> " 'amount of the new transaction'"))
+            )
+            .subcommand(
+                Command::new("combine-signatures")
+                    .about("combine the signatures of multisig transaction files and send it")
+                    .arg(arg!(<FILES> ... " 'signed transaction files'"))
+                    .arg(arg!(-m --mine <ADDRESS> " 'mine immediately with the reward to ADDRESS'"))
+            )
+            .subcommand(
+                Command::new("create-psbt")
+                    .about("write an unsigned transaction to FILE, for signing elsewhere")
+                    .arg(arg!(<FROM>" 'Source address, a wallet or multisig address'"))
+                    .arg(arg!(<TO>" 'Destination wallet address'"))
+                    .arg(arg!(<AMOUNT>" 'Amount to send'"))
+                    .arg(arg!(<FILE>" 'partially signed transaction file'"))
+                    .arg(arg!(--sighash <TYPE> " 'ALL (default), NONE or SINGLE, optionally |ANYONECANPAY'"))
+            )
+            .subcommand(
+                Command::new("sign-psbt")
+                    .about("sign the inputs of SIGNER in a partially signed transaction, needs no chain")
+                    .arg(arg!(<FILE>" 'partially signed transaction file'"))
+                    .arg(arg!(<SIGNER>" 'wallet address of the signing key'"))
+            )
+            .subcommand(
+                Command::new("combine-psbt")
+                    .about("merge the signatures of partially signed transaction files into OUT")
+                    .arg(arg!(<OUT>" 'combined file'"))
+                    .arg(arg!(<FILES> ... " 'signed transaction files'"))
+            )
+            .subcommand(
+                Command::new("finalize-and-broadcast")
+                    .about("build the unlocking scripts of a signed transaction file and send it")
+                    .arg(arg!(<FILE>" 'partially signed transaction file'"))
+                    .arg(arg!(-m --mine <ADDRESS> " 'mine immediately with the reward to ADDRESS'"))
+            )
+            .subcommand(
+                Command::new("claim-vesting")
+                    .about("spend a vesting output to its owner once unlocked")
+                    .arg(arg!(<TXID>" 'transaction of the vesting output'"))
+                    .arg(arg!(<VOUT>" 'index of the vesting output'"))
+                    .arg(arg!(<ADDRESS>" 'owner wallet address'"))
+                    .arg(arg!(-m --mine " 'the owner address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("htlc-create")
+                    .about("lock coins to TO under a hash, refundable to FROM after TIMEOUT")
+                    .arg(arg!(<FROM>" 'Source wallet address'"))
+                    .arg(arg!(<TO>" 'address which can redeem with the preimage'"))
+                    .arg(arg!(<AMOUNT>" 'Amount to lock'"))
+                    .arg(arg!(<TIMEOUT>" 'refund height, or unix time'"))
+                    .arg(arg!(--hash <HASH> " 'hex sha256 hash, a new secret is made without it'"))
+                    .arg(arg!(-m --mine " 'the from address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("htlc-redeem")
+                    .about("redeem an HTLC output with its preimage")
+                    .arg(arg!(<TXID>" 'transaction of the HTLC output'"))
+                    .arg(arg!(<VOUT>" 'index of the HTLC output'"))
+                    .arg(arg!(<ADDRESS>" 'recipient wallet address'"))
+                    .arg(arg!(<PREIMAGE>" 'hex preimage'"))
+                    .arg(arg!(-m --mine " 'the recipient address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("htlc-refund")
+                    .about("take an HTLC output back after its timeout")
+                    .arg(arg!(<TXID>" 'transaction of the HTLC output'"))
+                    .arg(arg!(<VOUT>" 'index of the HTLC output'"))
+                    .arg(arg!(<ADDRESS>" 'sender wallet address'"))
+                    .arg(arg!(-m --mine " 'the sender address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("htlc-preimage")
+                    .about("print the preimage revealed by the redeem of an HTLC output")
+                    .arg(arg!(<TXID>" 'transaction of the HTLC output'"))
+                    .arg(arg!(<VOUT>" 'index of the HTLC output'"))
+            )
+            .subcommand(
+                Command::new("channel-open")
+                    .about("fund a payment channel from FROM to TO, written to FILE")
+                    .arg(arg!(<FROM>" 'payer wallet address'"))
+                    .arg(arg!(<TO>" 'payee wallet address or hex public key'"))
+                    .arg(arg!(<AMOUNT>" 'capacity of the channel'"))
+                    .arg(arg!(<TIMEOUT>" 'height, or unix time, the payer can take the channel back'"))
+                    .arg(arg!(<FILE>" 'channel file shared with the payee'"))
+                    .arg(arg!(-m --mine " 'the payer address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("channel-pay")
+                    .about("pay through a channel off-chain, hand FILE over to the payee")
+                    .arg(arg!(<FILE>" 'channel file'"))
+                    .arg(arg!(<AMOUNT>" 'amount to pay'"))
+            )
+            .subcommand(
+                Command::new("channel-close")
+                    .about("close a channel with the latest payment, as the payee")
+                    .arg(arg!(<FILE>" 'channel file'"))
+                    .arg(arg!(--refund " 'take the channel back after the timeout, as the payer'"))
+                    .arg(arg!(-m --mine " 'the closing address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("find-data")
+                    .about("list the data outputs starting with PREFIX")
+                    .arg(arg!(<PREFIX>" 'hex prefix of the data, e.g. a document hash'"))
+            )
+            .subcommand(
+                Command::new("get-pubkey")
+                    .about("print the public key of a wallet address")
+                    .arg(arg!(<ADDRESS>"'The address in the wallet.'"))
+            )
+            .subcommand(
+                Command::new("dump-privkey")
+                    .about("print the secret key of a wallet address")
+                    .arg(arg!(<ADDRESS>" 'wallet address'"))
+            )
+            .subcommand(
+                Command::new("import-privkey")
+                    .about("add a secret key printed by dump-privkey to the wallet")
+                    .arg(arg!(<KEY>" 'exported secret key'"))
+                    .arg(arg!(--rescan " 'look for the outputs paid to it in the chain'"))
+            )
+            .subcommand(
+                Command::new("import-address")
+                    .about("watch an address, without its secret key")
+                    .arg(arg!(<ADDRESS>" 'address to watch'"))
+                    .arg(arg!(--rescan " 'look for the outputs paid to it in the chain'"))
+            )
+            .subcommand(
+                Command::new("import-pubkey")
+                    .about("watch the address of a public key, without its secret key")
+                    .arg(arg!(<PUBKEY>" 'hex public key'"))
+                    .arg(arg!(--rescan " 'look for the outputs paid to it in the chain'"))
+            )
+            .subcommand(
+                Command::new("list-balances")
+                    .about("print the balance of every address of the wallet, watch-only ones included")
+            )
+            .subcommand(
+                Command::new("list-transactions")
+                    .about("list the transactions of the wallet, oldest first")
+                    .arg(arg!(--address <ADDRESS>" 'only those of this address'"))
+                    .arg(arg!(--since <HEIGHT>" 'only those confirmed above this height'"))
+            )
+            .subcommand(
+                Command::new("get-transaction")
+                    .about("print a transaction of the wallet")
+                    .arg(arg!(<TXID>" 'transaction id'"))
+            )
+            .subcommand(
+                Command::new("sweep-privkey")
+                    .about("move every coin of a secret key to a wallet address, without importing it")
+                    .arg(arg!(<KEY>" 'exported secret key'"))
+                    .arg(arg!(<TO>" 'destination wallet address'"))
+                    .arg(arg!(-m --mine " 'the destination address mine immediately'"))
+            )
+            .subcommand(
+                Command::new("encrypt-wallet")
+                    .about("encrypt the wallet keys with a passphrase read from stdin")
+            )
+            .subcommand(
+                Command::new("unlock")
+                    .about("decrypt the wallet keys for the following commands")
+                    .arg(arg!(--timeout <SECONDS>" 'lock again after SECONDS, 300 by default'"))
+            )
+            .subcommand(
+                Command::new("lock")
+                    .about("forget the decrypted wallet keys")
+            )
+            .subcommand(
+                Command::new("change-passphrase")
+                    .about("re-encrypt the wallet keys, the old then the new passphrase are read from stdin")
+            )
             .get_matches();
 
+        if let Some(chain) = matches.get_one::<String>("chain") {
+            chainparams::select(chain)?;
+        }
+        if let Some(name) = matches.get_one::<String>("wallet") {
+            wallet::select(name)?;
+        }
+        wallet::sweep_sessions()?;
+
         if let Some(ref matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let blockchain = Blockchain::create_blockchain(String::from(address))?;
+                let blockchain = Blockchain::create_blockchain(reward_address(address)?)?;
                 let utxo_set = UTXOSet::new(blockchain);
                 utxo_set.reindex()?;
                 println!("create blockchain");
@@ -87,7 +374,7 @@ impl Cli {
                 let utxos = utxo_set.find_UTXO(&pub_key_hash)?;
 
                 let mut balance = 0;
-                for out in utxos.outputs {
+                for out in utxos {
                     balance += out.value;
                 }
                 println!("Balance of `{}`; {}", address, balance)
@@ -116,11 +403,66 @@ impl Cli {
                 exit(1)
             };
 
-            if matches.contains_id("mine") {
-                cmd_send(from, to, amount, true)?;
+            let lock_time: u32 = match matches.get_one::<String>("lock-time") {
+                Some(lock) => lock.parse()?,
+                None => 0,
+            };
+            let vest_until = matches.get_one::<String>("vest-until").map(|l| l.parse()).transpose()?;
+            let vest_for = matches.get_one::<String>("vest-for").map(|l| l.parse()).transpose()?;
+
+            let to_script = match (vest_until, vest_for) {
+                (Some(lock), _) => Some(Script::new_cltv_p2pkh(lock, &address_hash(to)?)),
+                (None, Some(blocks)) => Some(Script::new_csv_p2pkh(blocks, &address_hash(to)?)),
+                (None, None) => None,
+            };
+            let data = matches.get_one::<String>("data").map(|d| decode_hex(d)).transpose()?;
+            let outputs = send_outputs(to, amount, to_script, data)?;
+            cmd_send(from, outputs, lock_time, &coin_selection(matches)?, matches.get_flag("mine"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("send-many") {
+            let from = if let Some(address) = matches.get_one::<String>("FROM") {
+                address
+            } else {
+                println!("`from` not supply!: usage");
+                exit(1)
+            };
+            let mut payments = Vec::new();
+            for payment in matches.get_many::<String>("PAYMENTS").unwrap_or_default() {
+                payments.push(Payment::parse(payment)?);
+            }
+            if let Some(file) = matches.get_one::<String>("file") {
+                payments.extend(Payment::load(file)?);
+            }
+            let outputs = Payment::to_outputs(&payments)?;
+            cmd_send(from, outputs, 0, &coin_selection(matches)?, matches.get_flag("mine"))?;
+            println!("{} payments, {} in total", payments.len(), payments.iter().map(|p| p.amount).sum::<i32>());
+        }
+
+        if let Some(matches) = matches.subcommand_matches("generate") {
+            let count: i32 = if let Some(count) = matches.get_one::<String>("COUNT") {
+                count.parse()?
+            } else {
+                println!("`count` not supply!: usage");
+                exit(1)
+            };
+            let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                address
             } else {
-                cmd_send(from, to, amount, false)?;
+                println!("`address` not supply!: usage");
+                exit(1)
+            };
+
+            let address = &reward_address(address)?;
+            let mut utxo_set = UTXOSet::new(Blockchain::new()?);
+            for _ in 0..count {
+                let height = utxo_set.blockchain.get_best_height()? + 1;
+                let cbtx = Transaction::new_coinbase(address.clone(), String::from("generate"), height)?;
+                let block = utxo_set.blockchain.mine_block(vec![cbtx])?;
+                utxo_set.update(&block)?;
             }
+            History::update(&utxo_set.blockchain)?;
+            println!("best height: {}", utxo_set.blockchain.get_best_height()?);
         }
 
         if let Some(_) = matches.subcommand_matches("reindex") {
@@ -138,19 +480,225 @@ impl Cli {
             }
         }
 
-        if let Some(_) = matches.subcommand_matches("create-wallet") {
+        if let Some(matches) = matches.subcommand_matches("create-wallet") {
+            let account: u32 = match matches.get_one::<String>("account") {
+                Some(account) => account.parse()?,
+                None => 0,
+            };
             let mut ws = Wallets::new()?;
-            let address = ws.create_wallet();
+            if matches.get_flag("mnemonic") {
+                let phrase = mnemonic::generate(mnemonic::DEFAULT_WORDS)?;
+                let passphrase = if matches.get_flag("passphrase") {
+                    read_passphrase("mnemonic passphrase")?
+                } else {
+                    String::new()
+                };
+                ws.set_seed(mnemonic::to_seed(&phrase, &passphrase)?)?;
+                println!("mnemonic: {}", phrase);
+                println!("Write it down, it restores every address of the wallet");
+            }
+            let address = ws.create_wallet(account)?;
             ws.save_all()?;
             println!("Wallet create successed with address `{}`", address);
         }
 
+        if let Some(matches) = matches.subcommand_matches("restore-wallet") {
+            cmd_restore_wallet(matches.get_flag("passphrase"), matches.get_flag("seed"))?;
+        }
+
+        if matches.subcommand_matches("export-seed").is_some() {
+            let wallets = Wallets::new()?;
+            match wallets.get_seed()? {
+                Some(seed) => println!("{}", encode_hex(seed)),
+                None => anyhow::bail!("ERROR: Wallet has no seed yet, run create-wallet first"),
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("dump-privkey") {
+            let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                address
+            } else {
+                println!("`address` not supply!: usage");
+                exit(1)
+            };
+            let wallets = Wallets::new()?;
+            let wallet = match wallets.get_wallet(address) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for `{}`", address),
+            };
+            println!("{}", wallet.export_secret_key()?);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("import-privkey") {
+            let key = if let Some(key) = matches.get_one::<String>("KEY") {
+                key
+            } else {
+                println!("`key` not supply!: usage");
+                exit(1)
+            };
+            let mut wallets = Wallets::new()?;
+            let address = wallets.import_wallet(Wallet::import_secret_key(key)?)?;
+            wallets.save_all()?;
+            println!("Imported `{}`", address);
+            if matches.get_flag("rescan") {
+                cmd_rescan(&address)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("import-address") {
+            let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                address
+            } else {
+                println!("`address` not supply!: usage");
+                exit(1)
+            };
+            let mut wallets = Wallets::new()?;
+            wallets.add_watch_only(address, None)?;
+            wallets.save_all()?;
+            println!("Watching `{}`", address);
+            if matches.get_flag("rescan") {
+                cmd_rescan(address)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("import-pubkey") {
+            let public_key = if let Some(public_key) = matches.get_one::<String>("PUBKEY") {
+                decode_hex(public_key)?
+            } else {
+                println!("`pubkey` not supply!: usage");
+                exit(1)
+            };
+            if public_key.len() != 32 {
+                anyhow::bail!("ERROR: A public key is 32 bytes, not {}", public_key.len())
+            }
+            let address = Wallet::get_pub_key_address(&public_key);
+            let mut wallets = Wallets::new()?;
+            wallets.add_watch_only(&address, Some(public_key))?;
+            wallets.save_all()?;
+            println!("Watching `{}`", address);
+            if matches.get_flag("rescan") {
+                cmd_rescan(&address)?;
+            }
+        }
+
+        if matches.subcommand_matches("list-balances").is_some() {
+            cmd_list_balances()?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("list-transactions") {
+            let since: i32 = match matches.get_one::<String>("since") {
+                Some(height) => height.parse()?,
+                None => -1,
+            };
+            cmd_list_transactions(matches.get_one::<String>("address").map(|a| a.as_str()), since)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("get-transaction") {
+            let txid = if let Some(txid) = matches.get_one::<String>("TXID") {
+                txid
+            } else {
+                println!("`txid` not supply!: usage");
+                exit(1)
+            };
+            cmd_get_transaction(txid)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sweep-privkey") {
+            let key = if let Some(key) = matches.get_one::<String>("KEY") {
+                key
+            } else {
+                println!("`key` not supply!: usage");
+                exit(1)
+            };
+            let to = if let Some(address) = matches.get_one::<String>("TO") {
+                address
+            } else {
+                println!("`to` not supply!: usage");
+                exit(1)
+            };
+            cmd_sweep_privkey(key, to, matches.get_flag("mine"))?;
+        }
+
+        if matches.subcommand_matches("encrypt-wallet").is_some() {
+            let mut ws = Wallets::new()?;
+            let passphrase = read_new_passphrase("passphrase")?;
+            ws.encrypt(&passphrase)?;
+            println!("Wallet encrypted, run unlock before signing");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("unlock") {
+            let timeout: u64 = match matches.get_one::<String>("timeout") {
+                Some(timeout) => timeout.parse()?,
+                None => 300,
+            };
+            let mut ws = Wallets::new()?;
+            let passphrase = read_passphrase("passphrase")?;
+            ws.unlock(&passphrase, timeout)?;
+            println!("Wallet unlocked for {} seconds", timeout);
+        }
+
+        if matches.subcommand_matches("lock").is_some() {
+            let mut ws = Wallets::new()?;
+            ws.lock()?;
+            println!("Wallet locked");
+        }
+
+        if matches.subcommand_matches("change-passphrase").is_some() {
+            let mut ws = Wallets::new()?;
+            let old = read_passphrase("old passphrase")?;
+            let new = read_new_passphrase("new passphrase")?;
+            ws.change_passphrase(&old, &new)?;
+            println!("Passphrase changed, run unlock before signing");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("create-wallet-file") {
+            let name = if let Some(name) = matches.get_one::<String>("NAME") {
+                name
+            } else {
+                println!("`name` not supply!: usage");
+                exit(1)
+            };
+            wallet::create_wallet_file(name)?;
+            println!("Wallet `{}` created and loaded, use it with --wallet {}", name, name);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("load-wallet") {
+            let name = if let Some(name) = matches.get_one::<String>("NAME") {
+                name
+            } else {
+                println!("`name` not supply!: usage");
+                exit(1)
+            };
+            wallet::load_wallet_file(name)?;
+            println!("Wallet `{}` loaded", name);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("unload-wallet") {
+            let name = if let Some(name) = matches.get_one::<String>("NAME") {
+                name
+            } else {
+                println!("`name` not supply!: usage");
+                exit(1)
+            };
+            wallet::unload_wallet_file(name)?;
+            println!("Wallet `{}` unloaded", name);
+        }
+
+        if matches.subcommand_matches("list-wallets").is_some() {
+            for (name, loaded) in wallet::list_wallet_files()? {
+                println!("{}{}", name, if loaded { " (loaded)" } else { "" });
+            }
+        }
+
         if let Some(_) = matches.subcommand_matches("list-addresses") {
             let ws = Wallets::new()?;
             let addresses = ws.get_all_address();
             println!("addresses: ");
             for ad in addresses {
-                println!("{}", ad);
+                println!("{} ({})", ad, if ws.is_change(&ad) { "change" } else { "receiving" });
+            }
+            for ad in ws.get_watch_only_addresses() {
+                println!("{} (watch-only)", ad);
             }
         }
 
@@ -180,30 +728,891 @@ impl Cli {
 
             let bc = Blockchain::new()?;
             let utxo_set = UTXOSet { blockchain: bc };
-            let server = Server::new(port, address, utxo_set)?;
+            let server = Server::new(port, &reward_address(address)?, utxo_set)?;
             server.start_server()?;
         }
 
-        Ok(())
-    }
-}
+        if let Some(matches) = matches.subcommand_matches("start-external-miner") {
+            let node = if let Some(node) = matches.get_one::<String>("NODE") {
+                node
+            } else {
+                println!("NODE not supply!: usage");
+                exit(1)
+            };
 
-fn cmd_send(from: &str, to: &str, amount: i32, mine_now: bool) -> Result<()> {
-    let bc = Blockchain::new()?;
-    let mut utxo_set = UTXOSet {blockchain: bc};
-    let wallets = Wallets::new()?;
-    let wallet = wallets.get_wallet(from).unwrap();
-    let tx = Transaction::new_UTXO(wallet, to, amount, &utxo_set)?;
-    if mine_now {
-        let cbtx = Transaction::new_coinbase(from.to_string(), String::from("reward!"))?;
-        let new_block = utxo_set.blockchain.mine_block(vec![cbtx, tx])?;
+            let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                address
+            } else {
+                println!("ADDRESS not supply!: usage");
+                exit(1)
+            };
 
-        utxo_set.update(&new_block)?;
-    } else {
-        // Forward to miner node.
-        Server::send_transaction(&tx, utxo_set)?;
-    }
+            cmd_external_miner(node, &reward_address(address)?)?;
+        }
 
-    println!("success!");
-    Ok(())
-}
\ No newline at end of file
+        if let Some(matches) = matches.subcommand_matches("start-pool") {
+            let port = if let Some(port) = matches.get_one::<String>("PORT") {
+                port
+            } else {
+                println!("PORT not supply!: usage");
+                exit(1)
+            };
+
+            let node = if let Some(node) = matches.get_one::<String>("NODE") {
+                node
+            } else {
+                println!("NODE not supply!: usage");
+                exit(1)
+            };
+
+            let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                address
+            } else {
+                println!("ADDRESS not supply!: usage");
+                exit(1)
+            };
+
+            let pool = Pool::new(port, node, &reward_address(address)?)?;
+            pool.start_pool()?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("start-pool-worker") {
+            let pool = if let Some(pool) = matches.get_one::<String>("POOL") {
+                pool
+            } else {
+                println!("POOL not supply!: usage");
+                exit(1)
+            };
+
+            let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                address
+            } else {
+                println!("ADDRESS not supply!: usage");
+                exit(1)
+            };
+
+            cmd_pool_worker(pool, &reward_address(address)?)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("create-multisig") {
+            let m: usize = if let Some(m) = matches.get_one::<String>("M") {
+                m.parse()?
+            } else {
+                println!("M not supply!: usage");
+                exit(1)
+            };
+            let keys = matches.get_many::<String>("KEYS").unwrap_or_default().collect();
+            cmd_create_multisig(m, keys)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sign-multisig") {
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("FILE not supply!: usage");
+                exit(1)
+            };
+
+            let signer = if let Some(signer) = matches.get_one::<String>("SIGNER") {
+                signer
+            } else {
+                println!("SIGNER not supply!: usage");
+                exit(1)
+            };
+
+            let spend = match (
+                matches.get_one::<String>("from"),
+                matches.get_one::<String>("to"),
+                matches.get_one::<String>("amount"),
+            ) {
+                (Some(from), Some(to), Some(amount)) => Some((from, to, amount.parse()?)),
+                (None, None, None) => None,
+                _ => {
+                    println!("--from, --to and --amount go together!: usage");
+                    exit(1)
+                }
+            };
+            cmd_sign_multisig(file, signer, spend)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("combine-signatures") {
+            let files = matches.get_many::<String>("FILES").unwrap_or_default().collect();
+            let mine_to = matches.get_one::<String>("mine").map(|a| a.as_str());
+            cmd_combine_signatures(files, mine_to)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("create-psbt") {
+            let from = if let Some(from) = matches.get_one::<String>("FROM") {
+                from
+            } else {
+                println!("`from` not supply!: usage");
+                exit(1)
+            };
+            let to = if let Some(to) = matches.get_one::<String>("TO") {
+                to
+            } else {
+                println!("`to` not supply!: usage");
+                exit(1)
+            };
+            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                amount.parse()?
+            } else {
+                println!("`amount` not supply!: usage");
+                exit(1)
+            };
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("`file` not supply!: usage");
+                exit(1)
+            };
+            let sighash_type = match matches.get_one::<String>("sighash") {
+                Some(name) => psbt::parse_sighash(name)?,
+                None => SIGHASH_ALL,
+            };
+            cmd_create_psbt(from, to, amount, file, sighash_type)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sign-psbt") {
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("`file` not supply!: usage");
+                exit(1)
+            };
+            let signer = if let Some(signer) = matches.get_one::<String>("SIGNER") {
+                signer
+            } else {
+                println!("`signer` not supply!: usage");
+                exit(1)
+            };
+
+            let wallets = Wallets::new()?;
+            let wallet = match wallets.get_wallet(signer) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for `{}`", signer),
+            };
+            let mut psbt = Psbt::load(file)?;
+            let signed = psbt.sign(wallet)?;
+            psbt.save(file)?;
+            println!("signed {} inputs of {} by {}", signed, psbt.tx.id, signer);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("combine-psbt") {
+            let out = if let Some(out) = matches.get_one::<String>("OUT") {
+                out
+            } else {
+                println!("`out` not supply!: usage");
+                exit(1)
+            };
+            let files: Vec<&String> = matches.get_many::<String>("FILES").unwrap_or_default().collect();
+            let mut psbt = Psbt::load(files[0])?;
+            for file in &files[1..] {
+                psbt.combine(&Psbt::load(file)?)?;
+            }
+            psbt.save(out)?;
+            println!("combined {} files into {}", files.len(), out);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("finalize-and-broadcast") {
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("`file` not supply!: usage");
+                exit(1)
+            };
+            let mine_to = matches.get_one::<String>("mine").map(|a| a.as_str());
+
+            let tx = Psbt::load(file)?.finalize()?;
+            let utxo_set = UTXOSet::new(Blockchain::new()?);
+            utxo_set.blockchain.verify_transaction(&tx)?;
+            submit_tx(tx, utxo_set, mine_to)?;
+            println!("success!");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("claim-vesting") {
+            let (txid, vout, address) = outpoint_args(matches)?;
+
+            let wallets = Wallets::new()?;
+            let wallet = match wallets.get_wallet(address) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for `{}`", address),
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new()?);
+            let tx = Transaction::new_vesting_claim(wallet, txid, vout, &utxo_set.blockchain)?;
+            let mine_to = if matches.get_flag("mine") { Some(address.as_str()) } else { None };
+            submit_tx(tx, utxo_set, mine_to)?;
+            println!("success!");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlc-create") {
+            let from = if let Some(address) = matches.get_one::<String>("FROM") {
+                address
+            } else {
+                println!("`from` not supply!: usage");
+                exit(1)
+            };
+            let to = if let Some(address) = matches.get_one::<String>("TO") {
+                address
+            } else {
+                println!("`to` not supply!: usage");
+                exit(1)
+            };
+            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                amount.parse()?
+            } else {
+                println!("`amount` not supply!: usage");
+                exit(1)
+            };
+            let timeout: u32 = if let Some(timeout) = matches.get_one::<String>("TIMEOUT") {
+                timeout.parse()?
+            } else {
+                println!("`timeout` not supply!: usage");
+                exit(1)
+            };
+            let hash = matches.get_one::<String>("hash").map(|h| decode_hex(h)).transpose()?;
+            cmd_htlc_create(from, to, amount, timeout, hash, matches.get_flag("mine"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlc-redeem") {
+            let (txid, vout, address) = outpoint_args(matches)?;
+            let preimage = if let Some(preimage) = matches.get_one::<String>("PREIMAGE") {
+                decode_hex(preimage)?
+            } else {
+                println!("`preimage` not supply!: usage");
+                exit(1)
+            };
+
+            let wallets = Wallets::new()?;
+            let wallet = match wallets.get_wallet(address) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for `{}`", address),
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new()?);
+            let tx = htlc::new_redeem_tx(wallet, txid, vout, &preimage, &utxo_set.blockchain)?;
+            let mine_to = if matches.get_flag("mine") { Some(address.as_str()) } else { None };
+            submit_tx(tx, utxo_set, mine_to)?;
+            println!("success!");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlc-refund") {
+            let (txid, vout, address) = outpoint_args(matches)?;
+
+            let wallets = Wallets::new()?;
+            let wallet = match wallets.get_wallet(address) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for `{}`", address),
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new()?);
+            let tx = htlc::new_refund_tx(wallet, txid, vout, &utxo_set.blockchain)?;
+            let mine_to = if matches.get_flag("mine") { Some(address.as_str()) } else { None };
+            submit_tx(tx, utxo_set, mine_to)?;
+            println!("success!");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("htlc-preimage") {
+            let txid = if let Some(txid) = matches.get_one::<String>("TXID") {
+                txid
+            } else {
+                println!("`txid` not supply!: usage");
+                exit(1)
+            };
+            let vout: i32 = if let Some(vout) = matches.get_one::<String>("VOUT") {
+                vout.parse()?
+            } else {
+                println!("`vout` not supply!: usage");
+                exit(1)
+            };
+            let bc = Blockchain::new()?;
+            println!("preimage: {}", encode_hex(&htlc::find_preimage(txid, vout, &bc)?));
+        }
+
+        if let Some(matches) = matches.subcommand_matches("channel-open") {
+            let from = if let Some(address) = matches.get_one::<String>("FROM") {
+                address
+            } else {
+                println!("`from` not supply!: usage");
+                exit(1)
+            };
+            let to = if let Some(address) = matches.get_one::<String>("TO") {
+                address
+            } else {
+                println!("`to` not supply!: usage");
+                exit(1)
+            };
+            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                amount.parse()?
+            } else {
+                println!("`amount` not supply!: usage");
+                exit(1)
+            };
+            let timeout: u32 = if let Some(timeout) = matches.get_one::<String>("TIMEOUT") {
+                timeout.parse()?
+            } else {
+                println!("`timeout` not supply!: usage");
+                exit(1)
+            };
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("`file` not supply!: usage");
+                exit(1)
+            };
+            cmd_channel_open(from, to, amount, timeout, file, matches.get_flag("mine"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("channel-pay") {
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("`file` not supply!: usage");
+                exit(1)
+            };
+            let amount: i32 = if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                amount.parse()?
+            } else {
+                println!("`amount` not supply!: usage");
+                exit(1)
+            };
+
+            let mut channel = PaymentChannel::load(file)?;
+            let wallets = Wallets::new()?;
+            let payer = match wallets.get_wallet(&channel.payer()) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for the payer `{}`", channel.payer()),
+            };
+            channel.pay(payer, amount)?;
+            channel.save(file)?;
+            println!("paid {} of {} in total", channel.paid, channel.capacity);
+        }
+
+        if let Some(matches) = matches.subcommand_matches("channel-close") {
+            let file = if let Some(file) = matches.get_one::<String>("FILE") {
+                file
+            } else {
+                println!("`file` not supply!: usage");
+                exit(1)
+            };
+
+            let channel = PaymentChannel::load(file)?;
+            let wallets = Wallets::new()?;
+            let address = if matches.get_flag("refund") { channel.payer() } else { channel.payee() };
+            let wallet = match wallets.get_wallet(&address) {
+                Some(wallet) => wallet,
+                None => anyhow::bail!("ERROR: No wallet for `{}`", address),
+            };
+            let tx = if matches.get_flag("refund") {
+                channel.refund(wallet)?
+            } else {
+                channel.close(wallet)?
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new()?);
+            submit_tx(tx, utxo_set, if matches.get_flag("mine") { Some(&address) } else { None })?;
+            println!("success!");
+        }
+
+        if let Some(matches) = matches.subcommand_matches("find-data") {
+            if let Some(prefix) = matches.get_one::<String>("PREFIX") {
+                let utxo_set = UTXOSet::new(Blockchain::new()?);
+                for found in utxo_set.find_data(&decode_hex(prefix)?)? {
+                    println!("{}:{} height {} data {}", found.txid, found.vout, found.height, encode_hex(&found.data));
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("get-pubkey") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                let ws = Wallets::new()?;
+                match ws.get_wallet(address) {
+                    Some(wallet) => println!("{}", encode_hex(&wallet.public_key)),
+                    None => println!("No wallet for `{}`", address),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Look for shares on the jobs of `pool`, getting a fresh job after each share
+// so the coinbase keeps up with the shares of the round.
+fn cmd_pool_worker(pool: &str, address: &str) -> Result<()> {
+    let mut rng = rand::thread_rng();
+    loop {
+        let work = Pool::request_work(pool, address)?;
+        let mut block = work.block;
+        let start: i32 = rng.gen_range(0..i32::MAX / 2);
+
+        for nonce in start..start + 1_000_000 {
+            block.set_nonce(nonce)?;
+            if !block.meets_target(work.share_target)? {
+                continue;
+            }
+            match Pool::submit_share(pool, address, work.job_id, nonce)? {
+                ShareResult::Accepted => println!("share {}:{} accepted", work.job_id, nonce),
+                ShareResult::BlockFound(hash) => println!("share {}:{} found block {}", work.job_id, nonce, hash),
+                ShareResult::Rejected(reason) => println!("share {}:{} rejected: {}", work.job_id, nonce, reason),
+            }
+            break;
+        }
+    }
+}
+
+// Do the PoW on templates from `node` and hand the solved blocks back.
+// Only blocks with transactions are mined, like the miner node does.
+fn cmd_external_miner(node: &str, address: &str) -> Result<()> {
+    loop {
+        let template = Server::request_block_template(node)?;
+        if template.transactions.is_empty() {
+            thread::sleep(Duration::from_millis(2000));
+            continue;
+        }
+
+        let cbtx = Transaction::new_coinbase_with_value(
+            address.to_string(),
+            String::new(),
+            template.coinbase_value,
+            template.height,
+        )?;
+        let mut block = Block::from_template(&template, cbtx)?;
+        block.run_proof_if_work()?;
+
+        match Server::submit_block_to(node, &block) {
+            Ok(()) => println!("block {} at height {} accepted", block.get_hash(), block.get_height()),
+            Err(e) => println!("block {} rejected: {}", block.get_hash(), e),
+        }
+    }
+}
+
+// `to_script` replaces the usual locking script of `to`, e.g. for vesting.
+fn cmd_send(
+    from: &str,
+    outputs: Vec<TXOutput>,
+    lock_time: u32,
+    selection: &CoinSelection,
+    mine_now: bool,
+) -> Result<()> {
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet {blockchain: bc};
+    let mut wallets = Wallets::new()?;
+    let from = wallets.resolve_address(from);
+    let from = from.as_str();
+    let outputs = outputs.into_iter().map(|out| wallets.resolve_output(out)).collect::<Result<Vec<_>>>()?;
+    let wallet = match wallets.get_wallet(from) {
+        Some(wallet) => wallet.clone(),
+        // The key is elsewhere, hand it the transaction to sign.
+        None if wallets.is_watch_only(from) => {
+            let redeem_script = wallets.spending_script(from)?;
+            let tx = Transaction::new_unsigned_with_selection(from, outputs, lock_time, selection, from, &utxo_set)?;
+            let psbt = Psbt::new(tx, redeem_script, SIGHASH_ALL, &utxo_set.blockchain)?;
+            let file = format!("{}.psbt", psbt.tx.id);
+            psbt.save(&file)?;
+            println!("`{}` is watch-only, unsigned transaction written to {}, sign it with sign-psbt", from, file);
+            return Ok(());
+        }
+        None => anyhow::bail!("ERROR: No wallet for `{}`", from),
+    };
+    if wallet.is_locked() {
+        anyhow::bail!("ERROR: Wallet is locked, run unlock first")
+    }
+    // The change goes to a new address of the account, not back to `from`.
+    let account = wallets.get_account(from).unwrap_or(0);
+    let change_to = wallets.next_change_address(account)?.unwrap_or_else(|| from.to_string());
+    let tx = Transaction::new_utxo_with_selection(&wallet, outputs, lock_time, selection, &change_to, &utxo_set)?;
+    let has_change = tx.vout.iter().any(|out| Wallet::get_output_address(&out.script_pub_key).as_deref() == Some(change_to.as_str()));
+    if has_change && change_to != from {
+        wallets.create_change_address(account)?;
+        wallets.save_all()?;
+    }
+    let fee = utxo_set.blockchain.get_transaction_fee(&tx)?;
+    if fee > 0 {
+        println!("fee: {}", fee);
+    }
+    submit_tx(tx, utxo_set, if mine_now { Some(from) } else { None })?;
+
+    println!("success!");
+    Ok(())
+}
+
+// Address paid by coinbases, in the current format when it is one of the wallet's,
+// see Wallets::resolve_address.
+fn reward_address(address: &str) -> Result<String> {
+    Ok(Wallets::new()?.resolve_address(address))
+}
+
+// The --coin-selection, --utxo and --fee-rate options of a send.
+fn coin_selection(matches: &ArgMatches) -> Result<CoinSelection> {
+    let mut selection = CoinSelection::default();
+    if let Some(strategy) = matches.get_one::<String>("coin-selection") {
+        selection.strategy = Strategy::parse(strategy)?;
+    }
+    for utxo in matches.get_many::<String>("utxo").unwrap_or_default() {
+        selection.utxos.push(CoinSelection::parse_utxo(utxo)?);
+    }
+    if let Some(fee_rate) = matches.get_one::<String>("fee-rate") {
+        selection.fee_rate = fee_rate.parse()?;
+    }
+    Ok(selection)
+}
+
+// The hash an address given on the command line pays to.
+fn address_hash(address: &str) -> Result<Vec<u8>> {
+    match Address::decode(address) {
+        Ok(decoded) => Ok(decoded.body),
+        Err(_) => anyhow::bail!("ERROR: Invalid address `{}`", address),
+    }
+}
+
+fn send_outputs(to: &str, amount: i32, to_script: Option<Script>, data: Option<Vec<u8>>) -> Result<Vec<TXOutput>> {
+    let mut outputs = vec![match to_script {
+        Some(script_pub_key) => TXOutput { value: amount, script_pub_key },
+        None => TXOutput::new(amount, to.to_string())?,
+    }];
+    if let Some(data) = data {
+        outputs.push(TXOutput::new_data(&data)?);
+    }
+    Ok(outputs)
+}
+
+// Mine the transaction right away with the reward to `mine_to`,
+// or forward it to the miner node.
+fn submit_tx(tx: Transaction, mut utxo_set: UTXOSet, mine_to: Option<&str>) -> Result<()> {
+    if let Some(address) = mine_to {
+        let height = utxo_set.blockchain.get_best_height()? + 1;
+        let cbtx = Transaction::new_coinbase(address.to_string(), String::from("reward!"), height)?;
+        let new_block = utxo_set.mine_block(vec![cbtx, tx])?;
+
+        utxo_set.update(&new_block)?;
+        History::update(&utxo_set.blockchain)?;
+    } else {
+        // Forward to miner node.
+        Server::send_transaction(&tx, utxo_set)?;
+    }
+    Ok(())
+}
+
+// `to` is a wallet address we hold or the hex public key of the payee.
+fn cmd_channel_open(from: &str, to: &str, amount: i32, timeout: u32, file: &str, mine_now: bool) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let payer = match wallets.get_wallet(from) {
+        Some(wallet) => wallet,
+        None => anyhow::bail!("ERROR: No wallet for `{}`", from),
+    };
+    let payee_pub_key = match wallets.get_wallet(to) {
+        Some(wallet) => wallet.public_key.clone(),
+        None => decode_hex(to)?,
+    };
+
+    let utxo_set = UTXOSet::new(Blockchain::new()?);
+    let (channel, tx) = PaymentChannel::open(payer, payee_pub_key, amount, timeout, &utxo_set)?;
+    channel.save(file)?;
+    submit_tx(tx, utxo_set, if mine_now { Some(from) } else { None })?;
+    println!("channel {} open", file);
+    Ok(())
+}
+
+// A new secret is made when `hash` is not given, the other side of a swap uses its hash.
+fn cmd_htlc_create(from: &str, to: &str, amount: i32, timeout: u32, hash: Option<Vec<u8>>, mine_now: bool) -> Result<()> {
+    let hash = match hash {
+        Some(hash) => hash,
+        None => {
+            let mut secret = [0u8; 32];
+            rand::thread_rng().fill(&mut secret);
+            println!("secret: {}", encode_hex(&secret));
+            htlc::hash_preimage(&secret)
+        }
+    };
+
+    let utxo_set = UTXOSet::new(Blockchain::new()?);
+    let wallets = Wallets::new()?;
+    let wallet = match wallets.get_wallet(from) {
+        Some(wallet) => wallet,
+        None => anyhow::bail!("ERROR: No wallet for `{}`", from),
+    };
+    let tx = htlc::new_htlc_tx(wallet, to, amount, &hash, timeout, &utxo_set)?;
+    println!("hash: {}", encode_hex(&hash));
+    println!("htlc: {} 0", tx.id);
+    submit_tx(tx, utxo_set, if mine_now { Some(from) } else { None })?;
+    Ok(())
+}
+
+// TXID, VOUT and ADDRESS of the commands spending one output.
+fn outpoint_args(matches: &ArgMatches) -> Result<(&String, i32, &String)> {
+    let txid = if let Some(txid) = matches.get_one::<String>("TXID") {
+        txid
+    } else {
+        println!("`txid` not supply!: usage");
+        exit(1)
+    };
+    let vout: i32 = if let Some(vout) = matches.get_one::<String>("VOUT") {
+        vout.parse()?
+    } else {
+        println!("`vout` not supply!: usage");
+        exit(1)
+    };
+    let address = if let Some(address) = matches.get_one::<String>("ADDRESS") {
+        address
+    } else {
+        println!("`address` not supply!: usage");
+        exit(1)
+    };
+    Ok((txid, vout, address))
+}
+
+// Keys are wallet addresses we hold or hex public keys of the other keyholders.
+fn cmd_create_multisig(m: usize, keys: Vec<&String>) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    let mut pub_keys = Vec::new();
+    for key in keys {
+        match wallets.get_wallet(key) {
+            Some(wallet) => pub_keys.push(wallet.public_key.clone()),
+            None => pub_keys.push(decode_hex(key)?),
+        }
+    }
+    let redeem_script = Script::new_multisig(m, &pub_keys)?;
+    let address = wallets.add_script(redeem_script.clone());
+    wallets.save_all()?;
+
+    println!("address: {}", address);
+    println!("redeem script: {}", encode_hex(&redeem_script.0));
+    Ok(())
+}
+
+// Without `from`, FILE holds a transaction made by another keyholder.
+// Otherwise a new transaction spending from the multisig address is written to FILE.
+fn cmd_sign_multisig(file: &str, signer: &str, spend: Option<(&String, &String, i32)>) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    let mut multisig_tx = match spend {
+        Some((from, to, amount)) => {
+            let redeem_script = match wallets.get_script(from) {
+                Some(script) => script.clone(),
+                None => anyhow::bail!("ERROR: Unknown multisig address `{}`, run create-multisig first", from),
+            };
+            let utxo_set = UTXOSet::new(Blockchain::new()?);
+            MultisigTx::new(from, to, amount, redeem_script, &utxo_set)?
+        }
+        None => {
+            let multisig_tx = MultisigTx::load(file)?;
+            // Remember the multisig we are signing for.
+            wallets.add_script(multisig_tx.redeem_script.clone());
+            wallets.save_all()?;
+            multisig_tx
+        }
+    };
+
+    let wallet = match wallets.get_wallet(signer) {
+        Some(wallet) => wallet,
+        None => anyhow::bail!("ERROR: No wallet for `{}`", signer),
+    };
+    multisig_tx.sign(wallet)?;
+    multisig_tx.save(file)?;
+
+    println!("signed {} by {}", multisig_tx.tx.id, signer);
+    Ok(())
+}
+
+// FROM needs no key here, only the chain. A multisig address needs its redeem script.
+fn cmd_create_psbt(from: &str, to: &str, amount: i32, file: &str, sighash_type: u8) -> Result<()> {
+    let wallets = Wallets::new()?;
+    let redeem_script = wallets.spending_script(from)?;
+    let utxo_set = UTXOSet::new(Blockchain::new()?);
+    let tx = Transaction::new_unsigned(from, vec![TXOutput::new(amount, to.to_string())?], 0, &utxo_set)?;
+    let psbt = Psbt::new(tx, redeem_script, sighash_type, &utxo_set.blockchain)?;
+    psbt.save(file)?;
+
+    println!("created {} with {} inputs in {}", psbt.tx.id, psbt.inputs.len(), file);
+    Ok(())
+}
+
+fn cmd_combine_signatures(files: Vec<&String>, mine_to: Option<&str>) -> Result<()> {
+    let mut multisig_tx = MultisigTx::load(files[0])?;
+    for file in &files[1..] {
+        multisig_tx.combine(&MultisigTx::load(file)?)?;
+    }
+    let tx = multisig_tx.finalize()?;
+
+    let utxo_set = UTXOSet::new(Blockchain::new()?);
+    utxo_set.blockchain.verify_transaction(&tx)?;
+    submit_tx(tx, utxo_set, mine_to)?;
+
+    println!("success!");
+    Ok(())
+}
+
+// Restore the seed of a mnemonic, or a hex seed, and the addresses paid in the chain.
+fn cmd_restore_wallet(with_passphrase: bool, hex_seed: bool) -> Result<()> {
+    let seed = if hex_seed {
+        if with_passphrase {
+            anyhow::bail!("ERROR: --passphrase goes with a mnemonic, not a seed")
+        }
+        let seed = decode_hex(&read_passphrase("seed")?)?;
+        if seed.len() < 16 || seed.len() > 64 {
+            anyhow::bail!("ERROR: A seed is 16 to 64 bytes, not {}", seed.len())
+        }
+        seed
+    } else {
+        let phrase = read_passphrase("mnemonic")?;
+        let passphrase = if with_passphrase { read_passphrase("mnemonic passphrase")? } else { String::new() };
+        mnemonic::to_seed(&phrase, &passphrase)?
+    };
+
+    let bc = Blockchain::new()?;
+    let used = paid_hashes(&bc);
+
+    let mut wallets = Wallets::new()?;
+    let addresses = wallets.restore(seed, |address| used.contains(&Address::decode(address).unwrap().body))?;
+    if addresses.is_empty() {
+        let address = wallets.create_wallet(0)?;
+        println!("No address of this seed was used, first address `{}`", address);
+    }
+    wallets.save_all()?;
+
+    let utxo_set = UTXOSet::new(bc);
+    for address in &addresses {
+        let utxos = utxo_set.find_UTXO(&Address::decode(address).unwrap().body)?;
+        println!("{} {}", address, utxos.iter().map(|out| out.value).sum::<i32>());
+    }
+    println!("Restored {} addresses", addresses.len());
+    Ok(())
+}
+
+// Hashes the chain paid to: of keys with P2PKH outputs, possibly time locked, and of scripts with P2SH ones.
+fn paid_hashes(bc: &Blockchain) -> HashSet<Vec<u8>> {
+    let mut paid = HashSet::new();
+    for block in bc.iter() {
+        for tx in block.get_transactions() {
+            for out in &tx.vout {
+                if let Some(hash) = out.script_pub_key.p2pkh_hash().or(out.script_pub_key.p2sh_hash()) {
+                    paid.insert(hash.to_vec());
+                } else if let Some((_, _, hash)) = out.script_pub_key.locked_p2pkh() {
+                    paid.insert(hash);
+                }
+            }
+        }
+    }
+    paid
+}
+
+// What the chain holds for an imported address.
+fn cmd_rescan(address: &str) -> Result<()> {
+    let hash = address_hash(address)?;
+    let bc = Blockchain::new()?;
+    if !paid_hashes(&bc).contains(&hash) {
+        println!("`{}` was never paid", address);
+        return Ok(());
+    }
+    let utxo_set = UTXOSet::new(bc);
+    let balance: i32 = utxo_set.find_UTXO(&hash)?.iter().map(|out| out.value).sum();
+    println!("Balance of `{}`; {}", address, balance);
+    Ok(())
+}
+
+fn cmd_list_balances() -> Result<()> {
+    let wallets = Wallets::new()?;
+    let utxo_set = UTXOSet::new(Blockchain::new()?);
+    let (mut spendable, mut watched) = (0, 0);
+    let mut addresses: Vec<(String, bool)> = wallets.get_all_address().into_iter().map(|a| (a, false)).collect();
+    addresses.extend(wallets.get_watch_only_addresses().into_iter().map(|a| (a, true)));
+    for (address, watch_only) in addresses {
+        let balance: i32 = utxo_set.find_UTXO(&address_hash(&address)?)?.iter().map(|out| out.value).sum();
+        if watch_only {
+            watched += balance;
+            println!("{} {} (watch-only)", address, balance);
+        } else {
+            spendable += balance;
+            println!("{} {}", address, balance);
+        }
+    }
+    println!("total: {}, watch-only: {}", spendable, watched);
+    Ok(())
+}
+
+// The wallet history, caught up with the chain, and the height of the chain.
+fn synced_history() -> Result<(History, i32)> {
+    let bc = Blockchain::new()?;
+    let history = History::update(&bc)?;
+    Ok((history, bc.get_best_height()?))
+}
+
+fn cmd_list_transactions(address: Option<&str>, since: i32) -> Result<()> {
+    let (history, best_height) = synced_history()?;
+    for tx in history.list_transactions()? {
+        if tx.height <= since || address.is_some_and(|address| !tx.touches(address)) {
+            continue;
+        }
+        let fee = tx.fee.map(|fee| fee.to_string()).unwrap_or_else(|| String::from("-"));
+        println!(
+            "{} amount {:+} fee {} confirmations {} block {} time {}",
+            tx.txid,
+            tx.amount(address),
+            fee,
+            tx.confirmations(best_height),
+            tx.block_hash,
+            tx.timestamp / 1000,
+        );
+    }
+    Ok(())
+}
+
+fn cmd_get_transaction(txid: &str) -> Result<()> {
+    let (history, best_height) = synced_history()?;
+    let tx = match history.get_transaction(txid)? {
+        Some(tx) => tx,
+        None => anyhow::bail!("ERROR: Transaction {} does not touch the wallet", txid),
+    };
+    println!("txid: {}", tx.txid);
+    println!("amount: {:+}", tx.amount(None));
+    if let Some(fee) = tx.fee {
+        println!("fee: {}", fee);
+    }
+    println!("confirmations: {}", tx.confirmations(best_height));
+    println!("block: {} at height {}", tx.block_hash, tx.height);
+    println!("time: {}", tx.timestamp / 1000);
+    if tx.coinbase {
+        println!("coinbase");
+    }
+    for (vout, address, value) in &tx.received {
+        println!("received: {} to `{}` (output {})", value, address, vout);
+    }
+    for (prev_txid, vout, address, value) in &tx.spent {
+        println!("spent: {} from `{}` ({}:{})", value, address, prev_txid, vout);
+    }
+    Ok(())
+}
+
+// Spend every mature coin of `key` to `to`, signed with the key, which is not kept.
+fn cmd_sweep_privkey(key: &str, to: &str, mine_now: bool) -> Result<()> {
+    let wallet = Wallet::import_secret_key(key)?;
+    let from = wallet.get_address();
+    let bc = Blockchain::new()?;
+    let utxo_set = UTXOSet { blockchain: bc };
+
+    let mut pub_key_hash = wallet.public_key.clone();
+    Wallet::hash_pub_key(&mut pub_key_hash);
+    let (balance, _) = utxo_set.find_spendable_outputs(&pub_key_hash, i32::MAX)?;
+    if balance == 0 {
+        anyhow::bail!("ERROR: `{}` has no coin to sweep", from)
+    }
+    let mut tx = Transaction::new_unsigned(&from, vec![TXOutput::new(balance, to.to_string())?], 0, &utxo_set)?;
+    utxo_set.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
+    let txid = tx.id.clone();
+    submit_tx(tx, utxo_set, if mine_now { Some(to) } else { None })?;
+    println!("Swept {} from `{}` in {}", balance, from, txid);
+    Ok(())
+}
+
+// One line of stdin, the prompt goes to stderr so that it can be piped.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    eprint!("{}: ", prompt);
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_new_passphrase(prompt: &str) -> Result<String> {
+    let passphrase = read_passphrase(prompt)?;
+    if passphrase.is_empty() {
+        anyhow::bail!("ERROR: Empty passphrase")
+    }
+    Ok(passphrase)
+}
@@ -0,0 +1,253 @@
+use std::cmp::Reverse;
+
+use crate::errors::Result;
+use crate::encoding::Writer;
+use crate::tx::TXOutput;
+
+// Coin selection: which unspent outputs of an address pay for a transaction.
+// Branch and bound looks for coins adding up to the payment and its fee with no
+// change, or so little over it that a change output would cost more than it is
+// worth. It falls back to largest first when there is no such set of coins.
+// Fees are estimated from the size of the transaction and a rate per 1000 bytes.
+
+// Branch and bound gives up after visiting that many sets of coins.
+const BNB_MAX_TRIES: usize = 100_000;
+// Version, witness marker and flag, input and output counts, lock time.
+const TX_OVERHEAD_SIZE: usize = 12;
+// P2PKH input: outpoint, empty script_sig, sequence, witness <signature> <pub_key>.
+const INPUT_SIZE: usize = 32 + 4 + 1 + 4 + 1 + 66 + 33;
+// P2PKH change output: value and a 25 byte script.
+const CHANGE_OUTPUT_SIZE: usize = 8 + 1 + 25;
+
+// An unspent output the transaction may spend.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coin {
+    pub txid: String,
+    pub vout: i32,
+    pub value: i32,
+    // Height of the block containing it.
+    pub height: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    BranchAndBound,
+    LargestFirst,
+    OldestFirst,
+}
+
+impl Strategy {
+    pub fn parse(name: &str) -> Result<Strategy> {
+        match name {
+            "bnb" => Ok(Strategy::BranchAndBound),
+            "largest-first" => Ok(Strategy::LargestFirst),
+            "oldest-first" => Ok(Strategy::OldestFirst),
+            _ => anyhow::bail!("ERROR: Unknown coin selection `{}`, use bnb, largest-first or oldest-first", name),
+        }
+    }
+}
+
+// How a send picks its coins.
+#[derive(Debug, Clone)]
+pub struct CoinSelection {
+    pub strategy: Strategy,
+    // Fee per 1000 bytes of transaction.
+    pub fee_rate: i32,
+    // Coin control: spend exactly these outputs (txid, vout), whatever the strategy.
+    pub utxos: Vec<(String, i32)>,
+}
+
+impl Default for CoinSelection {
+    fn default() -> Self {
+        CoinSelection {
+            strategy: Strategy::BranchAndBound,
+            fee_rate: 0,
+            utxos: Vec::new(),
+        }
+    }
+}
+
+// The coins picked and the change left, 0 for none. The rest is the fee.
+#[derive(Debug)]
+pub struct Selection {
+    pub coins: Vec<Coin>,
+    pub change: i32,
+}
+
+impl CoinSelection {
+    // Parse a `txid:vout` coin for `utxos`.
+    pub fn parse_utxo(text: &str) -> Result<(String, i32)> {
+        match text.split_once(':') {
+            Some((txid, vout)) if !txid.is_empty() => Ok((txid.to_string(), vout.parse()?)),
+            _ => anyhow::bail!("ERROR: Coin `{}` is not txid:vout", text),
+        }
+    }
+
+    // Pick coins among `available` to pay `outputs`.
+    pub fn select(&self, available: Vec<Coin>, outputs: &[TXOutput]) -> Result<Selection> {
+        let target: i32 = outputs.iter().map(|out| out.value).sum();
+        let base_fee = self.fee(TX_OVERHEAD_SIZE + outputs.iter().map(output_size).sum::<usize>());
+        if !self.utxos.is_empty() {
+            let mut coins = Vec::new();
+            for (txid, vout) in &self.utxos {
+                match available.iter().find(|coin| &coin.txid == txid && coin.vout == *vout) {
+                    Some(coin) => coins.push(coin.clone()),
+                    None => anyhow::bail!("ERROR: Output {}:{} is not a spendable coin of the address", txid, vout),
+                }
+            }
+            return self.with_change(coins, target, base_fee);
+        }
+
+        // Coins worth less than the fee to spend them.
+        let input_fee = self.fee(INPUT_SIZE);
+        let mut coins: Vec<Coin> = available.into_iter().filter(|coin| coin.value > input_fee).collect();
+        match self.strategy {
+            Strategy::BranchAndBound => {
+                if let Some(selection) = self.branch_and_bound(&coins, target, base_fee) {
+                    return Ok(selection);
+                }
+                coins.sort_by_key(|coin| Reverse(coin.value));
+            }
+            Strategy::LargestFirst => coins.sort_by_key(|coin| Reverse(coin.value)),
+            Strategy::OldestFirst => coins.sort_by_key(|coin| coin.height),
+        }
+        let mut picked = Vec::new();
+        let mut total = 0;
+        for coin in coins {
+            if total >= target + base_fee + picked.len() as i32 * input_fee {
+                break;
+            }
+            total += coin.value;
+            picked.push(coin);
+        }
+        self.with_change(picked, target, base_fee)
+    }
+
+    // Fee of `size` bytes, rounded up.
+    fn fee(&self, size: usize) -> i32 {
+        (size as i32 * self.fee_rate + 999) / 1000
+    }
+
+    // Change, unless it is worth less than the fee to spend it later.
+    fn with_change(&self, coins: Vec<Coin>, target: i32, base_fee: i32) -> Result<Selection> {
+        let total: i32 = coins.iter().map(|coin| coin.value).sum();
+        let fee = base_fee + coins.len() as i32 * self.fee(INPUT_SIZE);
+        if total < target + fee {
+            anyhow::bail!("Not enough blance: current balance {}, {} needed with the fee", total, target + fee)
+        }
+        let change = total - target - fee - self.fee(CHANGE_OUTPUT_SIZE);
+        Ok(Selection { coins, change: if change > self.fee(INPUT_SIZE) { change } else { 0 } })
+    }
+
+    // Depth first search over the coins, largest first, for the set whose value after
+    // the fee to spend it lands in [target, target + cost of a change], the least over.
+    fn branch_and_bound(&self, coins: &[Coin], target: i32, base_fee: i32) -> Option<Selection> {
+        let input_fee = self.fee(INPUT_SIZE);
+        let mut coins: Vec<&Coin> = coins.iter().collect();
+        coins.sort_by_key(|coin| Reverse(coin.value));
+        let values: Vec<i32> = coins.iter().map(|coin| coin.value - input_fee).collect();
+        let target = target + base_fee;
+        let upper = target + self.fee(CHANGE_OUTPUT_SIZE) + input_fee;
+
+        let mut best: Option<(Vec<bool>, i32)> = None;
+        let mut picked = vec![false; values.len()];
+        let mut value = 0;
+        let mut remaining: i32 = values.iter().sum();
+        let mut depth = 0;
+        for _ in 0..BNB_MAX_TRIES {
+            let backtrack = if value + remaining < target || value > upper {
+                true
+            } else if value >= target {
+                if best.as_ref().is_none_or(|(_, excess)| value - target < *excess) {
+                    best = Some((picked.clone(), value - target));
+                }
+                true
+            } else {
+                false
+            };
+
+            if backtrack || depth == values.len() {
+                // Back to the last coin picked, try without it.
+                while depth > 0 && !picked[depth - 1] {
+                    depth -= 1;
+                    remaining += values[depth];
+                }
+                if depth == 0 {
+                    break;
+                }
+                picked[depth - 1] = false;
+                value -= values[depth - 1];
+            } else {
+                picked[depth] = true;
+                value += values[depth];
+                remaining -= values[depth];
+                depth += 1;
+            }
+        }
+
+        let (picked, _) = best?;
+        let coins = coins.iter().zip(picked).filter(|(_, p)| *p).map(|(coin, _)| (*coin).clone()).collect();
+        Some(Selection { coins, change: 0 })
+    }
+}
+
+fn output_size(out: &TXOutput) -> usize {
+    let mut writer = Writer::new();
+    writer.var_bytes(&out.script_pub_key.0);
+    8 + writer.into_bytes().len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coin(id: &str, value: i32, height: i32) -> Coin {
+        Coin { txid: id.to_string(), vout: 0, value, height }
+    }
+
+    fn pay(amount: i32) -> Vec<TXOutput> {
+        vec![TXOutput::new(amount, String::from("1AAWzX5RhGAm3nvBQJKppywCxn4wx7KRGR")).unwrap()]
+    }
+
+    fn txids(selection: &Selection) -> Vec<&str> {
+        selection.coins.iter().map(|coin| coin.txid.as_str()).collect()
+    }
+
+    #[test]
+    fn test_strategies() {
+        let coins = vec![coin("a", 50, 1), coin("b", 30, 2), coin("c", 20, 3), coin("d", 70, 4)];
+        let selection = CoinSelection::default();
+
+        // 30 + 20 pays 50 exactly, no change.
+        let bnb = selection.select(coins.clone(), &pay(50)).unwrap();
+        let mut picked = txids(&bnb);
+        picked.sort();
+        assert!(picked == vec!["a"] || picked == vec!["b", "c"]);
+        assert_eq!(bnb.change, 0);
+        // No exact match, largest first.
+        let fallback = selection.select(coins.clone(), &pay(75)).unwrap();
+        assert_eq!(txids(&fallback), vec!["d", "a"]);
+        assert_eq!(fallback.change, 45);
+
+        let largest = CoinSelection { strategy: Strategy::LargestFirst, ..CoinSelection::default() };
+        assert_eq!(txids(&largest.select(coins.clone(), &pay(50)).unwrap()), vec!["d"]);
+        let oldest = CoinSelection { strategy: Strategy::OldestFirst, ..CoinSelection::default() };
+        assert_eq!(txids(&oldest.select(coins.clone(), &pay(60)).unwrap()), vec!["a", "b"]);
+        let manual = CoinSelection { utxos: vec![(String::from("c"), 0)], ..CoinSelection::default() };
+        assert_eq!(manual.select(coins.clone(), &pay(15)).unwrap().change, 5);
+        assert!(manual.select(coins.clone(), &pay(25)).is_err());
+        assert!(selection.select(coins, &pay(171)).is_err());
+    }
+
+    #[test]
+    fn test_fees() {
+        let selection = CoinSelection { fee_rate: 100, ..CoinSelection::default() };
+        // One input, one output and the change: 12 + 34 + 141 + 34 bytes.
+        let with_change = selection.select(vec![coin("a", 100, 1)], &pay(50)).unwrap();
+        assert_eq!(with_change.change, 100 - 50 - 24);
+        // The change would not be worth the fee to spend it, it goes to the fee.
+        let changeless = selection.select(vec![coin("a", 100, 1)], &pay(80)).unwrap();
+        assert_eq!(changeless.change, 0);
+        assert!(selection.select(vec![coin("a", 100, 1)], &pay(82)).is_err());
+    }
+}
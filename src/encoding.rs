@@ -0,0 +1,189 @@
+use crate::errors::Result;
+use crate::util::{decode_hex, encode_hex};
+
+// Canonical byte encoding of transactions and blocks, which their hashes are computed over.
+// Integers are little endian. Counts and lengths are var ints: below 0xfd one byte,
+// otherwise 0xfd then u16, 0xfe then u32 or 0xff then u64, always the shortest form.
+// Hashes (txids, block hashes) are 32 raw bytes, the empty hash of the coinbase input
+// and of the genesis parent is all zeros.
+//
+// Transaction, version TX_VERSION:
+//   u32 version
+//   if any input has a witness: WITNESS_MARKER, WITNESS_FLAG
+//   var int input count, then for each input:
+//     hash txid, u32 vout (0xffffffff for the coinbase), var bytes script_sig, u32 sequence
+//   var int output count, then for each output:
+//     i64 value, var bytes script_pub_key
+//   if any input has a witness, for each input:
+//     var int item count, then var bytes of each item
+//   u32 lock_time
+// The txid is the SHA-256 of it without the witnesses (nor the marker and flag),
+// the wtxid the SHA-256 of all of it. Both are shown as upper case hex.
+// The id field is not encoded.
+//
+// Block header, version BLOCK_VERSION:
+//   u32 version, hash prev_block_hash, hash merkle root of the txids,
+//   u64 timestamp (milliseconds), u32 height, u32 target, u32 nonce
+// The block hash is the SHA-256 of the header. A block is its header,
+// a var int transaction count and the transactions.
+// The coinbase commits to the witnesses, see Block::witness_root.
+
+pub const TX_VERSION: u32 = 1;
+pub const BLOCK_VERSION: u32 = 1;
+// In place of the input count, which is never zero.
+pub const WITNESS_MARKER: u8 = 0x00;
+pub const WITNESS_FLAG: u8 = 0x01;
+
+#[derive(Default)]
+pub struct Writer(Vec<u8>);
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer(Vec::new())
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, n: u64) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, n: i64) {
+        self.0.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn var_int(&mut self, n: u64) {
+        match n {
+            0..=0xfc => self.0.push(n as u8),
+            0xfd..=0xffff => {
+                self.0.push(0xfd);
+                self.0.extend_from_slice(&(n as u16).to_le_bytes());
+            }
+            0x10000..=0xffffffff => {
+                self.0.push(0xfe);
+                self.0.extend_from_slice(&(n as u32).to_le_bytes());
+            }
+            _ => {
+                self.0.push(0xff);
+                self.0.extend_from_slice(&n.to_le_bytes());
+            }
+        }
+    }
+
+    pub fn var_bytes(&mut self, bytes: &[u8]) {
+        self.var_int(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    // `hash` is hex, empty for the null hash.
+    pub fn hash(&mut self, hash: &str) -> Result<()> {
+        if hash.is_empty() {
+            self.0.extend_from_slice(&[0; 32]);
+            return Ok(());
+        }
+        let bytes = decode_hex(hash)?;
+        if bytes.len() != 32 {
+            anyhow::bail!("ERROR: Invalid hash `{}`", hash)
+        }
+        self.0.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() - self.pos {
+            anyhow::bail!("ERROR: Unexpected end of data at byte {}", self.pos)
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn var_int(&mut self) -> Result<u64> {
+        let (n, min) = match self.take(1)?[0] {
+            0xfd => (u16::from_le_bytes(self.take(2)?.try_into()?) as u64, 0xfd),
+            0xfe => (self.u32()? as u64, 0x10000),
+            0xff => (self.u64()?, 0x100000000),
+            n => return Ok(n as u64),
+        };
+        if n < min {
+            anyhow::bail!("ERROR: Non canonical var int at byte {}", self.pos)
+        }
+        Ok(n)
+    }
+
+    pub fn var_bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.var_int()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            anyhow::bail!("ERROR: Unexpected end of data at byte {}", self.pos)
+        }
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    // Upper case hex, the null hash is empty.
+    pub fn hash(&mut self) -> Result<String> {
+        let bytes = self.take(32)?;
+        if bytes.iter().all(|b| *b == 0) {
+            return Ok(String::new());
+        }
+        Ok(encode_hex(bytes).to_uppercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_var_int() {
+        for (n, hex) in [(0, "00"), (0xfc, "fc"), (0xfd, "fdfd00"), (0x10000, "fe00000100"), (1 << 32, "ff0000000001000000")] {
+            let mut writer = Writer::new();
+            writer.var_int(n);
+            let bytes = writer.into_bytes();
+            assert_eq!(encode_hex(&bytes), hex);
+            assert_eq!(Reader::new(&bytes).var_int().unwrap(), n);
+        }
+        // 0xfc in three bytes is not the shortest form.
+        assert!(Reader::new(&[0xfd, 0xfc, 0x00]).var_int().is_err());
+        assert!(Reader::new(&[0xfd, 0xfc]).var_int().is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use crypto::{
+    ed25519,
+    hmac::Hmac,
+    mac::Mac,
+    sha2::Sha512,
+};
+use serde::{Serialize, Deserialize};
+
+use crate::errors::Result;
+use crate::wallet::Wallet;
+
+// Hierarchical deterministic keys, SLIP-0010 for ed25519: every key of the wallet
+// is derived from one seed, so a backup of the seed is a backup of every address.
+// ed25519 only has hardened derivation, a child can not be derived from a public key.
+// Receiving addresses live at m/44'/COIN_TYPE'/account'/0'/index', the change
+// addresses of the account at m/44'/COIN_TYPE'/account'/1'/index'.
+
+const HARDENED: u32 = 0x80000000;
+pub const PURPOSE: u32 = 44;
+pub const COIN_TYPE: u32 = 0;
+// Unused addresses in a row after which a restore stops looking further.
+pub const GAP_LIMIT: u32 = 20;
+
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedKey {
+    pub fn master(seed: &[u8]) -> ExtendedKey {
+        Self::from_hmac(b"ed25519 seed", seed)
+    }
+
+    // Hardened child `index`, the hardened bit is added.
+    pub fn child(&self, index: u32) -> ExtendedKey {
+        let mut data = vec![0];
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        Self::from_hmac(&self.chain_code, &data)
+    }
+
+    pub fn derive(seed: &[u8], path: &[u32]) -> ExtendedKey {
+        let mut key = Self::master(seed);
+        for index in path {
+            key = key.child(*index);
+        }
+        key
+    }
+
+    pub fn to_wallet(&self) -> Wallet {
+        let (secret_key, public_key) = ed25519::keypair(&self.key);
+        Wallet {
+            secret_key: secret_key.to_vec(),
+            public_key: public_key.to_vec(),
+        }
+    }
+
+    fn from_hmac(key: &[u8], data: &[u8]) -> ExtendedKey {
+        let mut mac = Hmac::new(Sha512::new(), key);
+        mac.input(data);
+        let code = mac.result();
+        let (left, right) = code.code().split_at(32);
+        ExtendedKey {
+            key: left.try_into().unwrap(),
+            chain_code: right.try_into().unwrap(),
+        }
+    }
+}
+
+// Where a derived address sits in the tree.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KeyPath {
+    pub account: u32,
+    // Internal chain of the account, for the change of its transactions.
+    pub change: bool,
+    pub index: u32,
+}
+
+impl KeyPath {
+    pub fn indices(&self) -> Vec<u32> {
+        vec![PURPOSE, COIN_TYPE, self.account, self.change as u32, self.index]
+    }
+
+    // A path stored in the wallet file. Those stored before change addresses
+    // existed are {account, index}, 8 bytes, and are receiving addresses.
+    pub fn from_record(data: &[u8]) -> Result<KeyPath> {
+        if data.len() == 8 {
+            let (account, index): (u32, u32) = bincode::deserialize(data)?;
+            return Ok(KeyPath { account, change: false, index });
+        }
+        Ok(bincode::deserialize(data)?)
+    }
+
+    pub fn derive(&self, seed: &[u8]) -> Result<Wallet> {
+        if self.account >= HARDENED || self.index >= HARDENED {
+            anyhow::bail!("ERROR: Derivation index out of range in {}", self)
+        }
+        Ok(ExtendedKey::derive(seed, &self.indices()).to_wallet())
+    }
+}
+
+impl std::fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "m")?;
+        for index in self.indices() {
+            write!(f, "/{}'", index)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{decode_hex, encode_hex};
+
+    // Test vector 1 for ed25519 of SLIP-0010.
+    #[test]
+    fn test_slip10_vector() {
+        let seed = decode_hex("000102030405060708090a0b0c0d0e0f").unwrap();
+        let vectors = [
+            (
+                vec![],
+                "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+                "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+                "a4b2856bfec510abab89753fac1ac0e1112364e7d250545963f135f2a33188ed",
+            ),
+            (
+                vec![0],
+                "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+                "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+                "8c8a13df77a28f3445213a0f432fde644acaa215fc72dcdf300d5efaa85d350c",
+            ),
+            (
+                vec![0, 1],
+                "a320425f77d1b5c2505a6b1b27382b37368ee640e3557c315416801243552f14",
+                "b1d0bad404bf35da785a64ca1ac54b2617211d2777696fbffaf208f746ae84f2",
+                "1932a5270f335bed617d5b935c80aedb1a35bd9fc1e31acafd5372c30f5c1187",
+            ),
+        ];
+        for (path, chain_code, key, public_key) in vectors {
+            let extended = ExtendedKey::derive(&seed, &path);
+            assert_eq!(encode_hex(&extended.chain_code), chain_code);
+            assert_eq!(encode_hex(&extended.key), key);
+            assert_eq!(encode_hex(&extended.to_wallet().public_key), public_key);
+        }
+    }
+
+    #[test]
+    fn test_addresses_follow_the_seed() {
+        let path = KeyPath { account: 1, change: false, index: 7 };
+        assert_eq!(path.to_string(), "m/44'/0'/1'/0'/7'");
+        let seed = [7; 32];
+        assert_eq!(path.derive(&seed).unwrap(), path.derive(&seed).unwrap());
+        assert_ne!(path.derive(&seed).unwrap(), KeyPath { index: 8, ..path }.derive(&seed).unwrap());
+        let change = KeyPath { change: true, ..path };
+        assert_eq!(change.to_string(), "m/44'/0'/1'/1'/7'");
+        assert_ne!(path.derive(&seed).unwrap(), change.derive(&seed).unwrap());
+        assert!(KeyPath { account: HARDENED, ..path }.derive(&seed).is_err());
+    }
+
+    #[test]
+    fn test_old_path_records() {
+        let path = KeyPath { account: 1, change: true, index: 7 };
+        assert_eq!(KeyPath::from_record(&bincode::serialize(&path).unwrap()).unwrap(), path);
+        let old = bincode::serialize(&(1u32, 7u32)).unwrap();
+        assert_eq!(old.len(), 8);
+        assert_eq!(KeyPath::from_record(&old).unwrap(), KeyPath { change: false, ..path });
+        assert!(KeyPath::from_record(&[0; 3]).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::HashSet;
+use serde::{Serialize, Deserialize};
+use log::info;
+
+use crate::errors::Result;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::chainparams::params;
+use crate::transaction::Transaction;
+use crate::wallet::{wallets_db, Wallet, Wallets};
+
+// Transactions touching the addresses of the wallet, kept in the wallet file
+// for each chain. A node updates it as it connects blocks and switches branches, and
+// it catches up with the chain whenever it is read, and after a command mined blocks:
+// blocks which left the chain in a reorg are disconnected, those connected since
+// the last update are added. When the wallet follows new addresses, e.g. imported
+// ones, the whole chain is scanned again.
+
+// A transaction paying to, or spending from, wallet addresses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletTx {
+    pub txid: String,
+    pub block_hash: String,
+    pub height: i32,
+    // Position in the block.
+    pub index: usize,
+    // Block timestamp in milliseconds.
+    pub timestamp: u128,
+    pub coinbase: bool,
+    // Outputs paying the wallet: (vout, address, value).
+    pub received: Vec<(i32, String, i32)>,
+    // Wallet outputs it spends: (txid, vout, address, value).
+    pub spent: Vec<(String, i32, String, i32)>,
+    // Known when every input is a wallet output.
+    pub fee: Option<i32>,
+}
+
+impl WalletTx {
+    // What the transaction adds to the wallet, or to `address` only.
+    pub fn amount(&self, address: Option<&str>) -> i32 {
+        let matches = |a: &String| address.is_none_or(|address| a == address);
+        let received: i32 = self.received.iter().filter(|r| matches(&r.1)).map(|r| r.2).sum();
+        let spent: i32 = self.spent.iter().filter(|s| matches(&s.2)).map(|s| s.3).sum();
+        received - spent
+    }
+
+    pub fn touches(&self, address: &str) -> bool {
+        self.received.iter().any(|r| r.1 == address) || self.spent.iter().any(|s| s.2 == address)
+    }
+
+    pub fn confirmations(&self, best_height: i32) -> i32 {
+        best_height - self.height + 1
+    }
+}
+
+// A block the history went through, to take it out again in a reorg.
+#[derive(Serialize, Deserialize, Debug)]
+struct BlockRecord {
+    prev_hash: String,
+    txids: Vec<String>,
+}
+
+pub struct History {
+    txs: sled::Tree,
+    blocks: sled::Tree,
+    meta: sled::Tree,
+}
+
+impl History {
+    pub fn open() -> Result<History> {
+        let db = wallets_db()?;
+        let chain = params().name;
+        Ok(History {
+            txs: db.open_tree(format!("history_txs_{}", chain))?,
+            blocks: db.open_tree(format!("history_blocks_{}", chain))?,
+            meta: db.open_tree(format!("history_meta_{}", chain))?,
+        })
+    }
+
+    // The history of the selected wallet file, caught up with the chain `bc`.
+    pub fn update(bc: &Blockchain) -> Result<History> {
+        let tracked = Wallets::new()?.get_tracked_addresses();
+        let history = History::open()?;
+        history.sync(bc, &tracked)?;
+        Ok(history)
+    }
+
+    // Catch up with the chain `bc`, for the wallet following `tracked` addresses.
+    pub fn sync(&self, bc: &Blockchain, tracked: &HashSet<String>) -> Result<()> {
+        let known: HashSet<String> = match self.meta.get("tracked")? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => HashSet::new(),
+        };
+        if !tracked.is_subset(&known) {
+            info!("history: new addresses, rescan the chain");
+            self.txs.clear()?;
+            self.blocks.clear()?;
+            self.meta.clear()?;
+            self.meta.insert("tracked", bincode::serialize(tracked)?)?;
+        }
+
+        // Blocks of the chain not seen yet, down to the last one the history went through.
+        let mut new_blocks = Vec::new();
+        let mut fork = String::new();
+        for block in bc.iter() {
+            if self.blocks.contains_key(block.get_hash())? {
+                fork = block.get_hash();
+                break;
+            }
+            new_blocks.push(block);
+        }
+
+        // Take out the blocks above the fork, no longer in the chain.
+        let mut tip = match self.meta.get("tip")? {
+            Some(hash) => String::from_utf8(hash.to_vec())?,
+            None => String::new(),
+        };
+        while !tip.is_empty() && tip != fork {
+            let record: BlockRecord = match self.blocks.get(&tip)? {
+                Some(data) => bincode::deserialize(&data)?,
+                None => break,
+            };
+            info!("history: disconnect block {}", tip);
+            for txid in record.txids.iter().rev() {
+                self.txs.remove(txid)?;
+            }
+            self.blocks.remove(&tip)?;
+            tip = record.prev_hash;
+        }
+
+        for block in new_blocks.iter().rev() {
+            self.connect_block(block, tracked)?;
+        }
+        self.meta.insert("tip", bc.iter().next().map(|b| b.get_hash()).unwrap_or_default().as_bytes())?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    fn connect_block(&self, block: &Block, tracked: &HashSet<String>) -> Result<()> {
+        let mut txids = Vec::new();
+        for (index, tx) in block.get_transactions().iter().enumerate() {
+            if let Some(wallet_tx) = self.wallet_tx(block, index, tx, tracked)? {
+                self.txs.insert(&tx.id, bincode::serialize(&wallet_tx)?)?;
+                txids.push(tx.id.clone());
+            }
+        }
+        let record = BlockRecord { prev_hash: block.get_prev_block_hash(), txids };
+        self.blocks.insert(block.get_hash(), bincode::serialize(&record)?)?;
+        Ok(())
+    }
+
+    fn wallet_tx(&self, block: &Block, index: usize, tx: &Transaction, tracked: &HashSet<String>) -> Result<Option<WalletTx>> {
+        let mut received = Vec::new();
+        for (vout, out) in tx.vout.iter().enumerate() {
+            if let Some(address) = Wallet::get_output_address(&out.script_pub_key) {
+                if tracked.contains(&address) {
+                    received.push((vout as i32, address, out.value));
+                }
+            }
+        }
+        // Wallet outputs are all in the history, being received first.
+        let mut spent = Vec::new();
+        if !tx.is_coinbase() {
+            for vin in &tx.vin {
+                if let Some(prev) = self.get_transaction(&vin.txid)? {
+                    if let Some((_, address, value)) = prev.received.iter().find(|r| r.0 == vin.vout) {
+                        spent.push((vin.txid.clone(), vin.vout, address.clone(), *value));
+                    }
+                }
+            }
+        }
+        if received.is_empty() && spent.is_empty() {
+            return Ok(None);
+        }
+        let fee = if !tx.is_coinbase() && spent.len() == tx.vin.len() {
+            let outputs: i32 = tx.vout.iter().map(|out| out.value).sum();
+            Some(spent.iter().map(|s| s.3).sum::<i32>() - outputs)
+        } else {
+            None
+        };
+        Ok(Some(WalletTx {
+            txid: tx.id.clone(),
+            block_hash: block.get_hash(),
+            height: block.get_height(),
+            index,
+            timestamp: block.get_timestamp(),
+            coinbase: tx.is_coinbase(),
+            received,
+            spent,
+            fee,
+        }))
+    }
+
+    pub fn get_transaction(&self, txid: &str) -> Result<Option<WalletTx>> {
+        match self.txs.get(txid)? {
+            Some(data) => Ok(Some(bincode::deserialize(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Oldest first.
+    pub fn list_transactions(&self) -> Result<Vec<WalletTx>> {
+        let mut txs = Vec::new();
+        for item in self.txs.iter() {
+            let (_, data) = item?;
+            txs.push(bincode::deserialize::<WalletTx>(&data)?);
+        }
+        txs.sort_by_key(|tx| (tx.height, tx.index));
+        Ok(txs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::new_chain;
+    use crate::chainparams::TestDataRoot;
+    use crate::tx::TXOutput;
+
+    #[test]
+    fn test_amount_per_address() {
+        let tx = WalletTx {
+            txid: String::from("tx"),
+            block_hash: String::from("block"),
+            height: 5,
+            index: 1,
+            timestamp: 0,
+            coinbase: false,
+            received: vec![(0, String::from("to"), 7), (1, String::from("from"), 93)],
+            spent: vec![(String::from("prev"), 0, String::from("from"), 100)],
+            fee: Some(0),
+        };
+        assert_eq!(tx.amount(None), 0);
+        assert_eq!(tx.amount(Some("to")), 7);
+        assert_eq!(tx.amount(Some("from")), -7);
+        assert!(tx.touches("from") && !tx.touches("other"));
+        assert_eq!(tx.confirmations(5), 1);
+    }
+
+    #[test]
+    fn test_sync_and_reorg() {
+        let _root = TestDataRoot::new("history_sync");
+        let (wallet, other) = (Wallet::new(), Wallet::new());
+        let maturity = params().coinbase_maturity;
+        let mut utxo_set = new_chain(&wallet, maturity - 1);
+        let fork = utxo_set.blockchain.get_tip();
+        let to = TXOutput::new(30, other.get_address()).unwrap();
+        let spend = Transaction::new_utxo_with_outputs(&wallet, vec![to], 0, &utxo_set).unwrap();
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), maturity).unwrap();
+        let block = utxo_set.mine_block(vec![cbtx, spend.clone()]).unwrap();
+
+        let tracked = HashSet::from([wallet.get_address()]);
+        let history = History::open().unwrap();
+        history.sync(&utxo_set.blockchain, &tracked).unwrap();
+        assert_eq!(history.list_transactions().unwrap().len(), maturity as usize + 2);
+        let sent = history.get_transaction(&spend.id).unwrap().unwrap();
+        assert_eq!((sent.amount(None), sent.fee, sent.height), (-30, Some(0), maturity));
+
+        // A longer branch without the spend.
+        let bc = &mut utxo_set.blockchain;
+        bc.set_tip(&fork).unwrap();
+        for height in maturity..maturity + 2 {
+            let cbtx = Transaction::new_coinbase(other.get_address(), String::new(), height).unwrap();
+            bc.mine_block(vec![cbtx]).unwrap();
+        }
+        history.sync(bc, &tracked).unwrap();
+        assert_eq!(history.list_transactions().unwrap().len(), maturity as usize);
+        assert!(history.get_transaction(&spend.id).unwrap().is_none());
+
+        // And back.
+        bc.set_tip(&block.get_hash()).unwrap();
+        history.sync(bc, &tracked).unwrap();
+        assert_eq!(history.list_transactions().unwrap().len(), maturity as usize + 2);
+        assert_eq!(history.get_transaction(&spend.id).unwrap().unwrap().block_hash, block.get_hash());
+
+        // A new address is looked for in the blocks already gone through.
+        let tracked = HashSet::from([wallet.get_address(), other.get_address()]);
+        history.sync(bc, &tracked).unwrap();
+        let sent = history.get_transaction(&spend.id).unwrap().unwrap();
+        assert_eq!(sent.amount(Some(&other.get_address())), 30);
+        assert_eq!(sent.amount(None), 0);
+    }
+}
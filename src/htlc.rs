@@ -0,0 +1,187 @@
+use sha2::{Sha256, Digest};
+use bitcoincash_addr::Address;
+
+use crate::errors::Result;
+use crate::blockchain::Blockchain;
+use crate::script::{Htlc, Instruction, Script};
+use crate::transaction::{Transaction, SEQUENCE_FINAL};
+use crate::tx::{TXInput, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::wallet::Wallet;
+
+// Hash time-locked contracts (Script::new_htlc).
+// For an atomic swap between two chains, Alice picks a secret and locks coins to Bob
+// on the first chain under its hash. Bob locks coins to Alice on the second chain under
+// the same hash, with an earlier timeout. Alice redeems them, which reveals the secret
+// on the second chain, and Bob uses it to redeem on the first chain.
+// If either side walks away, the coins go back to their owner after the timeout.
+
+pub fn hash_preimage(preimage: &[u8]) -> Vec<u8> {
+    Sha256::digest(preimage).to_vec()
+}
+
+// Lock `amount` of `wallet` to `recipient` under `hash` until `timeout`.
+pub fn new_htlc_tx(
+    wallet: &Wallet,
+    recipient: &str,
+    amount: i32,
+    hash: &[u8],
+    timeout: u32,
+    utxo_set: &UTXOSet,
+) -> Result<Transaction> {
+    let recipient_hash = Address::decode(recipient).unwrap().body;
+    let mut sender_hash = wallet.public_key.clone();
+    Wallet::hash_pub_key(&mut sender_hash);
+
+    let script = Script::new_htlc(hash, &recipient_hash, timeout, &sender_hash);
+    Transaction::new_utxo_with_outputs(
+        wallet,
+        vec![TXOutput { value: amount, script_pub_key: script }],
+        0,
+        utxo_set,
+    )
+}
+
+// Recipient side: spend the HTLC output `txid`:`vout` with the preimage.
+pub fn new_redeem_tx(wallet: &Wallet, txid: &str, vout: i32, preimage: &[u8], bc: &Blockchain) -> Result<Transaction> {
+    let (prev_out, htlc) = find_htlc(txid, vout, bc)?;
+    if hash_preimage(preimage) != htlc.hash {
+        anyhow::bail!("ERROR: Preimage does not match the hash of the HTLC")
+    }
+    check_owner(wallet, &htlc.recipient_hash, "recipient")?;
+
+    let mut tx = new_spend(wallet, txid, vout, &prev_out, 0)?;
+    let signature = tx.sign_input(0, &wallet.secret_key, &prev_out.script_pub_key)?;
+    tx.vin[0].witness = Script::new_htlc_redeem(&signature, &wallet.public_key, preimage).to_witness()?;
+    tx.id = tx.hash()?;
+    Ok(tx)
+}
+
+// Sender side: take the HTLC output `txid`:`vout` back once the timeout is reached.
+pub fn new_refund_tx(wallet: &Wallet, txid: &str, vout: i32, bc: &Blockchain) -> Result<Transaction> {
+    let (prev_out, htlc) = find_htlc(txid, vout, bc)?;
+    check_owner(wallet, &htlc.sender_hash, "sender")?;
+
+    let mut tx = new_spend(wallet, txid, vout, &prev_out, htlc.timeout)?;
+    let signature = tx.sign_input(0, &wallet.secret_key, &prev_out.script_pub_key)?;
+    tx.vin[0].witness = Script::new_htlc_refund(&signature, &wallet.public_key).to_witness()?;
+    tx.id = tx.hash()?;
+    Ok(tx)
+}
+
+// The preimage revealed by the transaction which redeemed the HTLC output `txid`:`vout`.
+pub fn find_preimage(txid: &str, vout: i32, bc: &Blockchain) -> Result<Vec<u8>> {
+    for block in bc.iter() {
+        for tx in block.get_transactions() {
+            for vin in &tx.vin {
+                if vin.txid != txid || vin.vout != vout {
+                    continue;
+                }
+                // <signature> <pub_key> <preimage> OP_1, a refund ends with OP_0.
+                return match vin.unlocking_script().instructions()?.as_slice() {
+                    [_, _, Instruction::Push(preimage), Instruction::Push(branch)] if branch == &[1] => {
+                        Ok(preimage.clone())
+                    }
+                    _ => anyhow::bail!("ERROR: HTLC {}:{} was refunded", txid, vout),
+                };
+            }
+        }
+    }
+    anyhow::bail!("ERROR: HTLC {}:{} is not redeemed yet", txid, vout)
+}
+
+fn find_htlc(txid: &str, vout: i32, bc: &Blockchain) -> Result<(TXOutput, Htlc)> {
+    let prev_tx = bc.find_transaction(txid)?;
+    let prev_out = match prev_tx.vout.get(vout as usize) {
+        Some(out) => out.clone(),
+        None => anyhow::bail!("ERROR: Output {}:{} does not exist", txid, vout),
+    };
+    match prev_out.script_pub_key.htlc() {
+        Some(htlc) => Ok((prev_out, htlc)),
+        None => anyhow::bail!("ERROR: Output {}:{} is not an HTLC", txid, vout),
+    }
+}
+
+fn check_owner(wallet: &Wallet, pub_key_hash: &[u8], role: &str) -> Result<()> {
+    let mut wallet_hash = wallet.public_key.clone();
+    Wallet::hash_pub_key(&mut wallet_hash);
+    if wallet_hash != pub_key_hash {
+        anyhow::bail!("ERROR: `{}` is not the {} of this HTLC", wallet.get_address(), role)
+    }
+    Ok(())
+}
+
+// Unsigned transaction moving the whole HTLC output to `wallet`.
+fn new_spend(wallet: &Wallet, txid: &str, vout: i32, prev_out: &TXOutput, lock_time: u32) -> Result<Transaction> {
+    let mut input = TXInput::new(txid.to_string(), vout);
+    if lock_time != 0 {
+        input.sequence = SEQUENCE_FINAL - 1;
+    }
+    let mut tx = Transaction {
+        id: String::new(),
+        vin: vec![input],
+        vout: vec![TXOutput::new(prev_out.value, wallet.get_address())?],
+        lock_time,
+    };
+    tx.id = tx.hash()?;
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // HTLC output from `sender` to `recipient`, with the spend of it.
+    fn setup(sender: &Wallet, recipient: &Wallet, lock_time: u32) -> (Transaction, Transaction) {
+        let recipient_hash = Address::decode(&recipient.get_address()).unwrap().body;
+        let mut sender_hash = sender.public_key.clone();
+        Wallet::hash_pub_key(&mut sender_hash);
+
+        let mut prev = Transaction::new_coinbase(sender.get_address(), String::from("test"), 1).unwrap();
+        prev.vout[0].script_pub_key = Script::new_htlc(&hash_preimage(b"secret"), &recipient_hash, 10, &sender_hash);
+        prev.id = prev.hash().unwrap();
+        let tx = new_spend(recipient, &prev.id, 0, &prev.vout[0], lock_time).unwrap();
+        (prev, tx)
+    }
+
+    fn sign(tx: &mut Transaction, wallet: &Wallet, prev: &Transaction, branch: Option<&[u8]>) {
+        let signature = tx.sign_input(0, &wallet.secret_key, &prev.vout[0].script_pub_key).unwrap();
+        tx.vin[0].script_sig = match branch {
+            Some(preimage) => Script::new_htlc_redeem(&signature, &wallet.public_key, preimage),
+            None => Script::new_htlc_refund(&signature, &wallet.public_key),
+        };
+    }
+
+    #[test]
+    fn test_redeem_with_preimage() {
+        let (sender, recipient) = (Wallet::new(), Wallet::new());
+        let (prev, mut tx) = setup(&sender, &recipient, 0);
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        sign(&mut tx, &recipient, &prev, Some(b"wrong"));
+        assert!(tx.verify(&prev_txs).is_err());
+        sign(&mut tx, &sender, &prev, Some(b"secret"));
+        assert!(tx.verify(&prev_txs).is_err());
+        sign(&mut tx, &recipient, &prev, Some(b"secret"));
+        assert!(tx.verify(&prev_txs).is_ok());
+    }
+
+    #[test]
+    fn test_refund_after_timeout() {
+        let (sender, recipient) = (Wallet::new(), Wallet::new());
+        let (prev, mut tx) = setup(&sender, &recipient, 10);
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+
+        sign(&mut tx, &recipient, &prev, None);
+        assert!(tx.verify(&prev_txs).is_err());
+        sign(&mut tx, &sender, &prev, None);
+        assert!(tx.verify(&prev_txs).is_ok());
+
+        // The refund must be locked until the timeout.
+        let (prev, mut tx) = setup(&sender, &recipient, 9);
+        let prev_txs = HashMap::from([(prev.id.clone(), prev.clone())]);
+        sign(&mut tx, &sender, &prev, None);
+        assert!(tx.verify(&prev_txs).is_err());
+    }
+}
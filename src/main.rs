@@ -1,12 +1,26 @@
 mod block;
 mod errors;
+mod chainparams;
+mod util;
+mod encoding;
 mod blockchain;
 mod cli;
 mod transaction;
 mod tx;
+mod script;
+mod multisig;
+mod psbt;
+mod htlc;
+mod channel;
 mod wallet;
+mod hd;
+mod mnemonic;
+mod history;
+mod coinselect;
+mod payments;
 mod utxoset;
 mod server;
+mod pool;
 mod tcp;
 
 use errors::Result;
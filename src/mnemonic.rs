@@ -0,0 +1,140 @@
+use crypto::{
+    hmac::Hmac,
+    pbkdf2::pbkdf2,
+    sha2::Sha512,
+};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::{Sha256, Digest};
+
+use crate::errors::Result;
+
+// BIP-39 mnemonics, the wallet seed written down as words.
+// The entropy and a checksum of its first bits (one per 32 bits of entropy) are cut
+// into 11 bit groups, each picks a word of the list. The seed is PBKDF2 of the words,
+// salted with an optional passphrase, so a passphrase gives a whole other wallet.
+
+const WORDLIST: &str = include_str!("wordlist/english.txt");
+// 256 bits of entropy.
+pub const DEFAULT_WORDS: usize = 24;
+
+fn words() -> Vec<&'static str> {
+    WORDLIST.lines().collect()
+}
+
+// A new mnemonic of `count` words: 12, 15, 18, 21 or 24.
+pub fn generate(count: usize) -> Result<String> {
+    let mut entropy = vec![0; count * 11 * 32 / 33 / 8];
+    OsRng.fill_bytes(&mut entropy);
+    from_entropy(&entropy)
+}
+
+pub fn from_entropy(entropy: &[u8]) -> Result<String> {
+    if entropy.len() < 16 || entropy.len() > 32 || !entropy.len().is_multiple_of(4) {
+        anyhow::bail!("ERROR: Mnemonic entropy must be 16 to 32 bytes, by steps of 4")
+    }
+    let mut bits = to_bits(entropy);
+    bits.extend(to_bits(&Sha256::digest(entropy)).into_iter().take(entropy.len() / 4));
+
+    let list = words();
+    let phrase: Vec<&str> = bits
+        .chunks(11)
+        .map(|group| list[group.iter().fold(0, |n, bit| n << 1 | *bit as usize)])
+        .collect();
+    Ok(phrase.join(" "))
+}
+
+// The entropy of `mnemonic`, checking its words and checksum.
+pub fn to_entropy(mnemonic: &str) -> Result<Vec<u8>> {
+    let list = words();
+    let phrase: Vec<&str> = mnemonic.split_whitespace().collect();
+    if ![12, 15, 18, 21, 24].contains(&phrase.len()) {
+        anyhow::bail!("ERROR: A mnemonic has 12, 15, 18, 21 or 24 words, not {}", phrase.len())
+    }
+    let mut bits = Vec::new();
+    for word in phrase {
+        let index = match list.binary_search(&word) {
+            Ok(index) => index,
+            Err(_) => anyhow::bail!("ERROR: `{}` is not a mnemonic word", word),
+        };
+        bits.extend((0..11).rev().map(|i| (index >> i & 1) as u8));
+    }
+    let checksum_len = bits.len() / 33;
+    let (entropy_bits, checksum) = bits.split_at(bits.len() - checksum_len);
+    let entropy: Vec<u8> = entropy_bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0, |n, bit| n << 1 | bit))
+        .collect();
+    if to_bits(&Sha256::digest(&entropy))[..checksum_len] != *checksum {
+        anyhow::bail!("ERROR: Wrong mnemonic checksum, a word is mistyped")
+    }
+    Ok(entropy)
+}
+
+// The 64 byte wallet seed of a valid `mnemonic`.
+pub fn to_seed(mnemonic: &str, passphrase: &str) -> Result<Vec<u8>> {
+    to_entropy(mnemonic)?;
+    // The words and passphrase are NFKD normalized first, which leaves ASCII as is.
+    if !passphrase.is_ascii() {
+        anyhow::bail!("ERROR: Only ASCII mnemonic passphrases are supported")
+    }
+    let words: Vec<&str> = mnemonic.split_whitespace().collect();
+    let mut mac = Hmac::new(Sha512::new(), words.join(" ").as_bytes());
+    let mut seed = vec![0; 64];
+    pbkdf2(&mut mac, format!("mnemonic{}", passphrase).as_bytes(), 2048, &mut seed);
+    Ok(seed)
+}
+
+fn to_bits(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{decode_hex, encode_hex};
+
+    // Test vectors of the reference implementation, with the passphrase "TREZOR".
+    #[test]
+    fn test_vectors() {
+        let vectors = [
+            (
+                "00000000000000000000000000000000",
+                "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+                "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04",
+            ),
+            (
+                "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+                "legal winner thank year wave sausage worth useful legal winner thank yellow",
+                "2e8905819b8723fe2c1d161860e5ee1830318dbf49a83bd451cfb8440c28bd6fa457fe1296106559a3c80937a1c1069be3a3a5bd381ee6260e8d9739fce1f607",
+            ),
+            (
+                "80808080808080808080808080808080",
+                "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+                "d71de856f81a8acc65e6fc851a38d4d7ec216fd0796d0a6827a3ad6ed5511a30fa280f12eb2e47ed2ac03b5c462a0358d18d69fe4f985ec81778c1b370b652a8",
+            ),
+            (
+                "ffffffffffffffffffffffffffffffff",
+                "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+                "ac27495480225222079d7be181583751e86f571027b0497b5b5d11218e0a8a13332572917f0f8e5a589620c6f15b11c61dee327651a14c34e18231052e48c069",
+            ),
+        ];
+        for (entropy, mnemonic, seed) in vectors {
+            let entropy = decode_hex(entropy).unwrap();
+            assert_eq!(from_entropy(&entropy).unwrap(), mnemonic);
+            assert_eq!(to_entropy(mnemonic).unwrap(), entropy);
+            assert_eq!(encode_hex(&to_seed(mnemonic, "TREZOR").unwrap()), seed);
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        let mnemonic = generate(DEFAULT_WORDS).unwrap();
+        assert_eq!(mnemonic.split(' ').count(), 24);
+        assert_eq!(from_entropy(&to_entropy(&mnemonic).unwrap()).unwrap(), mnemonic);
+        // Two words swapped, the checksum no longer matches.
+        assert!(to_entropy("about abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon").is_err());
+        assert!(to_entropy("abandon abandon abandon").is_err());
+        assert!(to_seed("abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon bitcoin", "").is_err());
+    }
+}
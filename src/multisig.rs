@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+use std::fs;
+use serde::{Serialize, Deserialize};
+use bitcoincash_addr::Address;
+
+use crate::errors::Result;
+use crate::script::Script;
+use crate::transaction::Transaction;
+use crate::tx::{TXInput, TXOutput};
+use crate::utxoset::UTXOSet;
+use crate::util::encode_hex;
+use crate::wallet::Wallet;
+
+// Spending from a multisig (P2SH) address, passed around between keyholders as a json file.
+// The creator builds the transaction, which needs the chain. Every keyholder then adds
+// their signatures on their own machine, which only needs their wallet.
+// Once m keyholders signed, the unlocking scripts can be put together.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MultisigTx {
+    pub tx: Transaction,
+    pub redeem_script: Script,
+    // Signatures of every input, by hex public key.
+    pub signatures: Vec<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MultisigTx {
+    // Same as Transaction::new_utxo_with_outputs, the change goes back to the multisig address.
+    pub fn new(
+        from: &str,
+        to: &str,
+        amount: i32,
+        redeem_script: Script,
+        utxo_set: &UTXOSet,
+    ) -> Result<MultisigTx> {
+        let script_hash = redeem_script.hash();
+        if Address::decode(from).unwrap().body != script_hash {
+            anyhow::bail!("ERROR: Redeem script does not belong to `{}`", from)
+        }
+
+        let acc_v = utxo_set.find_spendable_outputs(&script_hash, amount)?;
+        if acc_v.0 < amount {
+            anyhow::bail!("Not enough blance: current balance {}", acc_v.0)
+        }
+
+        let mut vin = Vec::new();
+        for tx in acc_v.1 {
+            for out in tx.1 {
+                vin.push(TXInput::new(tx.0.clone(), out));
+            }
+        }
+
+        let mut vout = vec![TXOutput::new(amount, String::from(to))?];
+        if acc_v.0 > amount {
+            vout.push(TXOutput::new(acc_v.0 - amount, String::from(from))?)
+        }
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout,
+            lock_time: 0,
+        };
+        tx.id = tx.hash()?;
+
+        Ok(MultisigTx {
+            signatures: vec![BTreeMap::new(); tx.vin.len()],
+            tx,
+            redeem_script,
+        })
+    }
+
+    pub fn load(path: &str) -> Result<MultisigTx> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Sign every input with the key of `wallet`, which must be one of the multisig keys.
+    pub fn sign(&mut self, wallet: &Wallet) -> Result<()> {
+        let (_, pub_keys) = self.keys()?;
+        if !pub_keys.contains(&wallet.public_key) {
+            anyhow::bail!("ERROR: `{}` is not a key of this multisig", wallet.get_address())
+        }
+
+        for idx in 0..self.tx.vin.len() {
+            let signature = self.tx.sign_input(idx, &wallet.secret_key, &self.redeem_script)?;
+            self.signatures[idx].insert(encode_hex(&wallet.public_key), signature);
+        }
+        Ok(())
+    }
+
+    // Merge the signatures of another copy of the same transaction.
+    pub fn combine(&mut self, other: &MultisigTx) -> Result<()> {
+        if self.tx.id != other.tx.id || self.redeem_script != other.redeem_script {
+            anyhow::bail!("ERROR: Can not combine signatures of different transactions")
+        }
+        for (mine, theirs) in self.signatures.iter_mut().zip(&other.signatures) {
+            mine.extend(theirs.clone());
+        }
+        Ok(())
+    }
+
+    // Build the unlocking scripts: <sig_1> ... <sig_m> <redeem_script>
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for (idx, signatures) in self.signatures.iter().enumerate() {
+            tx.vin[idx].witness = multisig_unlock(idx, &self.redeem_script, signatures)?.to_witness()?;
+        }
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    fn keys(&self) -> Result<(usize, Vec<Vec<u8>>)> {
+        match self.redeem_script.multisig_keys() {
+            Some(keys) => Ok(keys),
+            None => anyhow::bail!("ERROR: Redeem script is not a multisig script"),
+        }
+    }
+}
+
+// Unlocking script of input `idx`: <sig_1> ... <sig_m> <redeem_script>, the signatures
+// in the order of the keys in the redeem script.
+pub fn multisig_unlock(idx: usize, redeem_script: &Script, signatures: &BTreeMap<String, Vec<u8>>) -> Result<Script> {
+    let (m, pub_keys) = match redeem_script.multisig_keys() {
+        Some(keys) => keys,
+        None => anyhow::bail!("ERROR: Input {}: redeem script is not a multisig script", idx),
+    };
+    let ordered: Vec<&Vec<u8>> = pub_keys
+        .iter()
+        .filter_map(|key| signatures.get(&encode_hex(key)))
+        .take(m)
+        .collect();
+    if ordered.len() < m {
+        anyhow::bail!("ERROR: Input {} has {} of the {} signatures needed", idx, ordered.len(), m)
+    }
+
+    let mut script_sig = Script::new();
+    for signature in ordered {
+        script_sig = script_sig.push_data(signature);
+    }
+    Ok(script_sig.push_data(&redeem_script.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // 2-of-3 multisig funded by a coinbase, and an unsigned spend of it.
+    fn setup() -> (Vec<Wallet>, Transaction, MultisigTx) {
+        let keyholders = vec![Wallet::new(), Wallet::new(), Wallet::new()];
+        let pub_keys: Vec<Vec<u8>> = keyholders.iter().map(|w| w.public_key.clone()).collect();
+        let redeem_script = Script::new_multisig(2, &pub_keys).unwrap();
+        let address = Wallet::get_script_address(&redeem_script);
+        let prev = Transaction::new_coinbase(address, String::from("test"), 1).unwrap();
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput::new(prev.id.clone(), 0)],
+            vout: vec![TXOutput::new(prev.vout[0].value, Wallet::new().get_address()).unwrap()],
+            lock_time: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        let multisig_tx = MultisigTx {
+            signatures: vec![BTreeMap::new()],
+            tx,
+            redeem_script,
+        };
+        (keyholders, prev, multisig_tx)
+    }
+
+    #[test]
+    fn test_two_of_three() {
+        let (keyholders, prev, mut first) = setup();
+        let mut second = first.clone();
+        // Signed on different machines, in any order.
+        second.sign(&keyholders[2]).unwrap();
+        first.sign(&keyholders[0]).unwrap();
+        first.combine(&second).unwrap();
+
+        let tx = first.finalize().unwrap();
+        tx.verify(&HashMap::from([(prev.id.clone(), prev)])).unwrap();
+    }
+
+    #[test]
+    fn test_not_enough_signatures() {
+        let (keyholders, _, mut multisig_tx) = setup();
+        multisig_tx.sign(&keyholders[1]).unwrap();
+        assert!(multisig_tx.finalize().is_err());
+    }
+
+    #[test]
+    fn test_same_key_twice_is_rejected() {
+        let (keyholders, prev, multisig_tx) = setup();
+        let sig = multisig_tx.tx.sign_input(0, &keyholders[0].secret_key, &multisig_tx.redeem_script).unwrap();
+        let mut tx = multisig_tx.tx.clone();
+        tx.vin[0].script_sig = Script::new()
+            .push_data(&sig)
+            .push_data(&sig)
+            .push_data(&multisig_tx.redeem_script.0);
+        assert!(tx.verify(&HashMap::from([(prev.id.clone(), prev)])).is_err());
+    }
+
+    #[test]
+    fn test_outsider_can_not_sign() {
+        let (_, _, mut multisig_tx) = setup();
+        assert!(multisig_tx.sign(&Wallet::new()).is_err());
+    }
+}
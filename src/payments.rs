@@ -0,0 +1,100 @@
+use std::fs;
+use serde::{Serialize, Deserialize};
+use bitcoincash_addr::Address;
+
+use crate::errors::Result;
+use crate::tx::TXOutput;
+
+// Payments batched in one transaction by send-many, given as ADDRESS:AMOUNT
+// arguments or in a file. A CSV file has an `address,amount` line per payment,
+// a JSON file is a list of {"address": ..., "amount": ...}.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Payment {
+    pub address: String,
+    pub amount: i32,
+}
+
+impl Payment {
+    // ADDRESS:AMOUNT
+    pub fn parse(text: &str) -> Result<Payment> {
+        match text.split_once(':') {
+            Some((address, amount)) => Payment::new(address, amount),
+            None => anyhow::bail!("ERROR: Payment `{}` is not ADDRESS:AMOUNT", text),
+        }
+    }
+
+    fn new(address: &str, amount: &str) -> Result<Payment> {
+        let amount: i32 = match amount.trim().parse() {
+            Ok(amount) if amount > 0 => amount,
+            _ => anyhow::bail!("ERROR: Invalid amount `{}` for `{}`", amount.trim(), address.trim()),
+        };
+        Ok(Payment { address: address.trim().to_string(), amount })
+    }
+
+    pub fn load(path: &str) -> Result<Vec<Payment>> {
+        let text = fs::read_to_string(path)?;
+        if text.trim_start().starts_with('[') {
+            let payments: Vec<Payment> = serde_json::from_str(&text)?;
+            if let Some(payment) = payments.iter().find(|payment| payment.amount <= 0) {
+                anyhow::bail!("ERROR: Invalid amount `{}` for `{}`", payment.amount, payment.address)
+            }
+            Ok(payments)
+        } else {
+            Payment::parse_csv(&text)
+        }
+    }
+
+    // Blank lines and an `address,amount` header are skipped.
+    fn parse_csv(text: &str) -> Result<Vec<Payment>> {
+        let mut payments = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || (number == 0 && line.eq_ignore_ascii_case("address,amount")) {
+                continue;
+            }
+            match line.split_once(',') {
+                Some((address, amount)) => payments.push(Payment::new(address, amount)?),
+                None => anyhow::bail!("ERROR: Line {} is not address,amount", number + 1),
+            }
+        }
+        Ok(payments)
+    }
+
+    pub fn to_outputs(payments: &[Payment]) -> Result<Vec<TXOutput>> {
+        if payments.is_empty() {
+            anyhow::bail!("ERROR: No payment to send")
+        }
+        let mut outputs = Vec::new();
+        for payment in payments {
+            if Address::decode(&payment.address).is_err() {
+                anyhow::bail!("ERROR: Invalid address `{}`", payment.address)
+            }
+            outputs.push(TXOutput::new(payment.amount, payment.address.clone())?);
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "1AAWzX5RhGAm3nvBQJKppywCxn4wx7KRGR";
+
+    #[test]
+    fn test_parse_payments() {
+        let payment = Payment::parse(&format!("{}:12", ADDRESS)).unwrap();
+        assert_eq!(payment, Payment { address: ADDRESS.to_string(), amount: 12 });
+        assert!(Payment::parse(ADDRESS).is_err());
+        assert!(Payment::parse(&format!("{}:0", ADDRESS)).is_err());
+
+        let csv = format!("address,amount\n{0},5\n\n {0} , 7 \n", ADDRESS);
+        let payments = Payment::parse_csv(&csv).unwrap();
+        assert_eq!(payments.iter().map(|p| p.amount).collect::<Vec<_>>(), vec![5, 7]);
+        assert_eq!(Payment::to_outputs(&payments).unwrap().len(), 2);
+        assert!(Payment::parse_csv(&format!("{},-1", ADDRESS)).is_err());
+        assert!(Payment::to_outputs(&[]).is_err());
+        assert!(Payment::to_outputs(&[Payment { address: String::from("nope"), amount: 1 }]).is_err());
+    }
+}
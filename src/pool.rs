@@ -0,0 +1,438 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::io::{Read, Write};
+use std::thread;
+use std::net::{TcpListener, TcpStream};
+
+use log::info;
+use serde::{Serialize, Deserialize};
+use bitcoincash_addr::Address;
+
+use crate::errors::Result;
+use crate::block::Block;
+use crate::transaction::Transaction;
+use crate::tx::TXOutput;
+use crate::server::{Server, call, cmd_to_bytes, CMD_LEN};
+
+// A share only needs the first byte of the block hash to be '0'.
+pub const SHARE_TARGET: usize = 1;
+
+// Mining pool sitting in front of a node.
+// Workers ask for work: a block built from the node's template, with a coinbase
+// paying the reward proportionally to the shares of the current round.
+// A share is a nonce meeting SHARE_TARGET. When it also meets the block target,
+// the block is submitted to the node and a new round starts.
+pub struct Pool {
+    pool_address: String,
+    node: String,
+    // Paid when the round has no share yet, and gets the rounding leftovers.
+    operator_address: String,
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+struct PoolInner {
+    // Unsolved blocks handed out to workers, by job id.
+    jobs: HashMap<u64, Block>,
+    next_job_id: u64,
+    // Tip the jobs are built on. Jobs are dropped once the node moves on.
+    tip: String,
+    // Shares per worker address in the current round.
+    shares: HashMap<String, u64>,
+    accepted: HashSet<(u64, i32)>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Work {
+    pub job_id: u64,
+    pub block: Block,
+    pub share_target: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ShareResult {
+    Accepted,
+    BlockFound(String),
+    Rejected(String),
+}
+
+impl Pool {
+    pub fn new(port: &str, node: &str, operator_address: &str) -> Result<Pool> {
+        Ok(Pool {
+            pool_address: String::from("localhost:") + port,
+            node: node.to_string(),
+            operator_address: operator_address.to_string(),
+            inner: Arc::new(Mutex::new(PoolInner {
+                jobs: HashMap::new(),
+                next_job_id: 0,
+                tip: String::new(),
+                shares: HashMap::new(),
+                accepted: HashSet::new(),
+            })),
+        })
+    }
+
+    // Worker side: ask the pool for a job.
+    pub fn request_work(pool: &str, worker: &str) -> Result<Work> {
+        let data = GetWorkmsg {
+            worker: worker.to_string(),
+        };
+        let data = bincode::serialize(&(cmd_to_bytes("getwork"), data))?;
+        let reply = call(pool, &data)?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    // Worker side: hand a nonce meeting the share target back to the pool.
+    pub fn submit_share(pool: &str, worker: &str, job_id: u64, nonce: i32) -> Result<ShareResult> {
+        let data = Sharemsg {
+            worker: worker.to_string(),
+            job_id,
+            nonce,
+        };
+        let data = bincode::serialize(&(cmd_to_bytes("share"), data))?;
+        let reply = call(pool, &data)?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    pub fn start_pool(&self) -> Result<()> {
+        info!(
+            "Start pool at {}, node: {}, operator address: {}",
+            &self.pool_address, &self.node, &self.operator_address
+        );
+
+        let listener = TcpListener::bind(&self.pool_address)?;
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let pool = Pool {
+                pool_address: self.pool_address.clone(),
+                node: self.node.clone(),
+                operator_address: self.operator_address.clone(),
+                inner: Arc::clone(&self.inner),
+            };
+            thread::spawn(move || pool.handle_connection(stream));
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        let mut buffer = Vec::new();
+        let count = stream.read_to_end(&mut buffer)?;
+        info!("Accept request: length {}", count);
+
+        match bytes_to_cmd(&buffer)? {
+            PoolMessage::GetWork(data) => {
+                let work = self.handle_get_work(data)?;
+                stream.write_all(&bincode::serialize(&work)?)?;
+            }
+            PoolMessage::Share(data) => {
+                let result = self.handle_share(data)?;
+                stream.write_all(&bincode::serialize(&result)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_get_work(&self, msg: GetWorkmsg) -> Result<Work> {
+        info!("receive get work msg from {}", msg.worker);
+        let template = Server::request_block_template(&self.node)?;
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.tip != template.prev_block_hash {
+            inner.jobs.clear();
+            inner.accepted.clear();
+            inner.tip = template.prev_block_hash.clone();
+        }
+        let job_id = inner.next_job_id;
+        inner.next_job_id += 1;
+
+        // The job id as extra nonce keeps every job's block different.
+        let vout = self.payouts(template.coinbase_value, &inner.shares)?;
+        let cbtx = Transaction::new_coinbase_outputs(template.height, job_id, String::from("pool"), vout)?;
+        let block = Block::from_template(&template, cbtx)?;
+        inner.jobs.insert(job_id, block.clone());
+
+        Ok(Work {
+            job_id,
+            block,
+            share_target: SHARE_TARGET,
+        })
+    }
+
+    fn handle_share(&self, msg: Sharemsg) -> Result<ShareResult> {
+        info!("receive share msg: {:?}", msg);
+        if Address::decode(&msg.worker).is_err() {
+            return Ok(ShareResult::Rejected(String::from("invalid worker address")));
+        }
+
+        let mut block = {
+            let mut inner = self.inner.lock().unwrap();
+            let block = match inner.jobs.get(&msg.job_id) {
+                Some(b) => b.clone(),
+                None => return Ok(ShareResult::Rejected(String::from("unknown or stale job"))),
+            };
+            if !inner.accepted.insert((msg.job_id, msg.nonce)) {
+                return Ok(ShareResult::Rejected(String::from("duplicate share")));
+            }
+            block
+        };
+
+        block.set_nonce(msg.nonce)?;
+        if !block.meets_target(SHARE_TARGET)? {
+            return Ok(ShareResult::Rejected(String::from("share does not meet the target")));
+        }
+        *self.inner.lock().unwrap().shares.entry(msg.worker.clone()).or_insert(0) += 1;
+
+        if !block.is_solved()? {
+            return Ok(ShareResult::Accepted);
+        }
+
+        match Server::submit_block_to(&self.node, &block) {
+            Ok(()) => {
+                info!("block {} found by {}", block.get_hash(), msg.worker);
+                // The block paid the round, start a new one.
+                let mut inner = self.inner.lock().unwrap();
+                inner.shares.clear();
+                inner.jobs.clear();
+                inner.accepted.clear();
+                Ok(ShareResult::BlockFound(block.get_hash()))
+            }
+            Err(e) => {
+                info!("block {} rejected by the node: {}", block.get_hash(), e);
+                Ok(ShareResult::Accepted)
+            }
+        }
+    }
+
+    // Split `value` between workers proportionally to their shares.
+    fn payouts(&self, value: i32, shares: &HashMap<String, u64>) -> Result<Vec<TXOutput>> {
+        let total: u64 = shares.values().sum();
+        if total == 0 {
+            return Ok(vec![TXOutput::new(value, self.operator_address.clone())?]);
+        }
+
+        let mut workers: Vec<(&String, &u64)> = shares.iter().collect();
+        workers.sort();
+
+        let mut vout = Vec::new();
+        let mut paid = 0;
+        for (worker, count) in workers {
+            let amount = (value as u64 * count / total) as i32;
+            if amount > 0 {
+                vout.push(TXOutput::new(amount, worker.clone())?);
+                paid += amount;
+            }
+        }
+        if value > paid {
+            vout.push(TXOutput::new(value - paid, self.operator_address.clone())?);
+        }
+        Ok(vout)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetWorkmsg {
+    worker: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Sharemsg {
+    worker: String,
+    job_id: u64,
+    nonce: i32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum PoolMessage {
+    GetWork(GetWorkmsg),
+    Share(Sharemsg),
+}
+
+fn bytes_to_cmd(bytes: &[u8]) -> Result<PoolMessage> {
+    if bytes.len() < CMD_LEN {
+        anyhow::bail!("Message of {} bytes is too short for a command", bytes.len())
+    }
+    let cmd: Vec<u8> = bytes[..CMD_LEN].iter().filter(|b| **b != 0).cloned().collect();
+    let data = &bytes[CMD_LEN..];
+
+    if cmd == "getwork".as_bytes() {
+        let data: GetWorkmsg = bincode::deserialize(data)?;
+        Ok(PoolMessage::GetWork(data))
+    } else if cmd == "share".as_bytes() {
+        let data: Sharemsg = bincode::deserialize(data)?;
+        Ok(PoolMessage::Share(data))
+    } else {
+        anyhow::bail!("Unknown command in the pool")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use crate::block::{BlockTemplate, TARGET_HEXT};
+    use crate::blockchain::tests::new_chain;
+    use crate::chainparams::TestDataRoot;
+    use crate::server::tests::serve_until;
+    use crate::wallet::Wallet;
+
+    fn rejected(result: ShareResult) -> String {
+        match result {
+            ShareResult::Rejected(reason) => reason,
+            other => panic!("share not rejected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_payouts() {
+        let operator = Wallet::new().get_address();
+        let pool = Pool::new("0", "localhost:0", &operator).unwrap();
+        let (alice, bob) = (Wallet::new().get_address(), Wallet::new().get_address());
+        let paid = |vout: Vec<TXOutput>, address: &str| -> i32 {
+            let hash = Address::decode(address).unwrap().body;
+            vout.iter().filter(|out| out.can_be_unlock_with(&hash)).map(|out| out.value).sum()
+        };
+
+        // No share yet, the operator takes it all.
+        let vout = pool.payouts(100, &HashMap::new()).unwrap();
+        assert_eq!(vout.len(), 1);
+        assert_eq!(paid(vout, &operator), 100);
+
+        let shares = HashMap::from([(alice.clone(), 3), (bob.clone(), 1)]);
+        let vout = pool.payouts(100, &shares).unwrap();
+        assert_eq!(vout.len(), 2);
+        assert_eq!(paid(vout.clone(), &alice), 75);
+        assert_eq!(paid(vout, &bob), 25);
+
+        // 101 * 3 / 4 = 75 and 101 / 4 = 25, the leftover goes to the operator.
+        let vout = pool.payouts(101, &shares).unwrap();
+        assert_eq!(vout.iter().map(|out| out.value).sum::<i32>(), 101);
+        assert_eq!(paid(vout, &operator), 1);
+
+        // A worker whose part rounds down to nothing gets no output.
+        let shares = HashMap::from([(alice.clone(), 1000), (bob.clone(), 1)]);
+        let vout = pool.payouts(100, &shares).unwrap();
+        assert_eq!(paid(vout.clone(), &bob), 0);
+        assert_eq!(paid(vout, &operator), 1);
+    }
+
+    #[test]
+    fn test_handle_share() {
+        let operator = Wallet::new().get_address();
+        let worker = Wallet::new().get_address();
+        let pool = Pool::new("0", "localhost:0", &operator).unwrap();
+        let template = BlockTemplate {
+            prev_block_hash: "00".repeat(32),
+            height: 1,
+            timestamp: 0,
+            target: TARGET_HEXT,
+            transactions: Vec::new(),
+            coinbase_value: 100,
+        };
+        let vout = vec![TXOutput::new(100, operator).unwrap()];
+        let cbtx = Transaction::new_coinbase_outputs(1, 0, String::from("pool"), vout).unwrap();
+        let mut block = Block::from_template(&template, cbtx).unwrap();
+        pool.inner.lock().unwrap().jobs.insert(0, block.clone());
+
+        // A nonce meeting the share target but not the block one, which would go to the node.
+        let mut share = 0;
+        block.set_nonce(share).unwrap();
+        while !block.meets_target(SHARE_TARGET).unwrap() || block.is_solved().unwrap() {
+            share += 1;
+            block.set_nonce(share).unwrap();
+        }
+        let mut low = 0;
+        block.set_nonce(low).unwrap();
+        while block.meets_target(SHARE_TARGET).unwrap() {
+            low += 1;
+            block.set_nonce(low).unwrap();
+        }
+        let msg = |job_id: u64, nonce: i32| Sharemsg { worker: worker.clone(), job_id, nonce };
+
+        assert_eq!(rejected(pool.handle_share(msg(1, share)).unwrap()), "unknown or stale job");
+        assert_eq!(rejected(pool.handle_share(msg(0, low)).unwrap()), "share does not meet the target");
+        assert!(matches!(pool.handle_share(msg(0, share)).unwrap(), ShareResult::Accepted));
+        assert_eq!(rejected(pool.handle_share(msg(0, share)).unwrap()), "duplicate share");
+        let bad_worker = Sharemsg { worker: String::from("nope"), job_id: 0, nonce: share + 1 };
+        assert_eq!(rejected(pool.handle_share(bad_worker).unwrap()), "invalid worker address");
+        assert_eq!(pool.inner.lock().unwrap().shares[&worker], 1);
+    }
+
+    // A job of `pool` and a nonce meeting its share target, which solves the block or not.
+    fn find_share(pool: &str, worker: &str, solved: bool) -> (Work, i32) {
+        let mut work = Pool::request_work(pool, worker).unwrap();
+        let mut nonce = 0;
+        loop {
+            work.block.set_nonce(nonce).unwrap();
+            if work.block.meets_target(work.share_target).unwrap() && work.block.is_solved().unwrap() == solved {
+                return (work, nonce);
+            }
+            nonce += 1;
+        }
+    }
+
+    // A node and a pool on localhost, with two workers: the block found pays them
+    // in proportion to the shares they submitted before its job.
+    #[test]
+    fn test_pool_on_localhost() {
+        let _root = TestDataRoot::new("pool_on_localhost");
+        let listener = TcpListener::bind("localhost:0").unwrap();
+        let node_port = listener.local_addr().unwrap().port().to_string();
+        let node = format!("localhost:{}", node_port);
+        let server = Server::new(&node_port, "", new_chain(&Wallet::new(), 0)).unwrap();
+        let port = TcpListener::bind("localhost:0").unwrap().local_addr().unwrap().port().to_string();
+        let pool = format!("localhost:{}", port);
+        let operator = Wallet::new().get_address();
+        let pool_server = Pool::new(&port, &node, &operator).unwrap();
+        thread::spawn(move || pool_server.start_pool());
+
+        let (alice, bob) = (Wallet::new().get_address(), Wallet::new().get_address());
+        let done = Arc::new(AtomicBool::new(false));
+        let workers = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                // Wait for the pool to listen.
+                while Pool::request_work(&pool, &alice).is_err() {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                let worker = |address: String, shares: usize| {
+                    let pool = pool.clone();
+                    thread::spawn(move || {
+                        for _ in 0..shares {
+                            let (work, nonce) = find_share(&pool, &address, false);
+                            let result = Pool::submit_share(&pool, &address, work.job_id, nonce).unwrap();
+                            assert!(matches!(result, ShareResult::Accepted));
+                        }
+                    })
+                };
+                let (a, b) = (worker(alice.clone(), 3), worker(bob.clone(), 1));
+                a.join().unwrap();
+                b.join().unwrap();
+
+                let (work, nonce) = find_share(&pool, &alice, true);
+                let result = Pool::submit_share(&pool, &alice, work.job_id, nonce).unwrap();
+                done.store(true, Ordering::SeqCst);
+                (work.block, result, alice, bob)
+            })
+        };
+        serve_until(&server, listener, &done);
+        let (block, result, alice, bob) = workers.join().unwrap();
+
+        // Only a block the node connected is reported as found.
+        assert!(matches!(result, ShareResult::BlockFound(hash) if hash == block.get_hash()));
+        let coinbase = &block.get_transactions()[0];
+        let paid = |address: &str| -> i32 {
+            let hash = Address::decode(address).unwrap().body;
+            coinbase.vout.iter().filter(|out| out.can_be_unlock_with(&hash)).map(|out| out.value).sum()
+        };
+        let value: i32 = coinbase.vout.iter().map(|out| out.value).sum();
+        assert_eq!(paid(&alice), value * 3 / 4);
+        assert_eq!(paid(&bob), value / 4);
+        assert_eq!(paid(&operator), value - value * 3 / 4 - value / 4);
+    }
+
+    #[test]
+    fn test_short_message() {
+        assert!(bytes_to_cmd(&[]).is_err());
+        assert!(bytes_to_cmd(b"share").is_err());
+    }
+}
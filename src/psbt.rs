@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use serde::{Serialize, Deserialize};
+
+use crate::errors::Result;
+use crate::multisig::multisig_unlock;
+use crate::blockchain::Blockchain;
+use crate::script::Script;
+use crate::transaction::{
+    Transaction, SIGHASH_ALL, SIGHASH_ANYONECANPAY, SIGHASH_NONE, SIGHASH_SINGLE,
+};
+use crate::tx::TXOutput;
+use crate::util::{decode_hex, encode_hex};
+use crate::wallet::Wallet;
+
+// Partially signed transaction, passed around as a json file.
+// The creator builds the unsigned transaction, which needs the chain, and adds the
+// output each input spends. Signers only need their wallet: the outputs tell which
+// inputs are theirs and give the locking script to sign. Once every input is signed,
+// the unlocking scripts are put together and the transaction can be broadcast.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    pub tx: Transaction,
+    pub inputs: Vec<PsbtInput>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    // The output spent by the input.
+    pub prev_out: TXOutput,
+    // For P2SH outputs, the script whose hash locks them.
+    pub redeem_script: Option<Script>,
+    // Sighash type the signers of the input use.
+    pub sighash_type: u8,
+    // Signatures by hex public key.
+    pub signatures: BTreeMap<String, Vec<u8>>,
+}
+
+impl Psbt {
+    // Wrap the unsigned `tx`, looking up the outputs it spends in the chain.
+    // `redeem_script` goes with the inputs spending its P2SH outputs.
+    pub fn new(tx: Transaction, redeem_script: Option<Script>, sighash_type: u8, bc: &Blockchain) -> Result<Psbt> {
+        let mut inputs = Vec::new();
+        for vin in &tx.vin {
+            let prev_tx = bc.find_transaction(&vin.txid)?;
+            let prev_out = match prev_tx.vout.get(vin.vout as usize) {
+                Some(out) => out.clone(),
+                None => anyhow::bail!("ERROR: Output {}:{} does not exist", vin.txid, vin.vout),
+            };
+            let redeem_script = match (&redeem_script, prev_out.script_pub_key.p2sh_hash()) {
+                (Some(script), Some(hash)) if script.hash() == hash => Some(script.clone()),
+                _ => None,
+            };
+            inputs.push(PsbtInput {
+                prev_out,
+                redeem_script,
+                sighash_type,
+                signatures: BTreeMap::new(),
+            });
+        }
+        Ok(Psbt { tx, inputs })
+    }
+
+    pub fn load(path: &str) -> Result<Psbt> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    // Sign every input `wallet` can sign, returns how many were signed.
+    pub fn sign(&mut self, wallet: &Wallet) -> Result<usize> {
+        let mut pub_key_hash = wallet.public_key.clone();
+        Wallet::hash_pub_key(&mut pub_key_hash);
+
+        let mut signed = 0;
+        for idx in 0..self.inputs.len() {
+            let input = &self.inputs[idx];
+            let script_code = match &input.redeem_script {
+                Some(script) => match script.multisig_keys() {
+                    Some((_, keys)) if keys.contains(&wallet.public_key) => script,
+                    _ => continue,
+                },
+                None => {
+                    let script = &input.prev_out.script_pub_key;
+                    if p2pkh_owner(script).as_deref() != Some(pub_key_hash.as_slice()) {
+                        continue;
+                    }
+                    script
+                }
+            };
+            let signature = self.tx.sign_input_with_type(idx, &wallet.secret_key, script_code, input.sighash_type)?;
+            self.inputs[idx].signatures.insert(encode_hex(&wallet.public_key), signature);
+            signed += 1;
+        }
+        if signed == 0 {
+            anyhow::bail!("ERROR: `{}` can not sign any input of this transaction", wallet.get_address())
+        }
+        Ok(signed)
+    }
+
+    // Merge the signatures of another copy of the same transaction.
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.tx.id != other.tx.id || self.inputs.len() != other.inputs.len() {
+            anyhow::bail!("ERROR: Can not combine signatures of different transactions")
+        }
+        for (mine, theirs) in self.inputs.iter_mut().zip(&other.inputs) {
+            mine.signatures.extend(theirs.signatures.clone());
+        }
+        Ok(())
+    }
+
+    // Build the unlocking scripts: <signature> <pub_key> for P2PKH outputs,
+    // <sig_1> ... <sig_m> <redeem_script> for multisig ones, and check them.
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for (idx, input) in self.inputs.iter().enumerate() {
+            let script_sig = match &input.redeem_script {
+                Some(script) => multisig_unlock(idx, script, &input.signatures)?,
+                None => {
+                    // Other keys may have signed too, take the one the output is locked to.
+                    let locked_to = p2pkh_owner(&input.prev_out.script_pub_key);
+                    let mut script_sig = None;
+                    for (pub_key, signature) in &input.signatures {
+                        let mut pub_key_hash = decode_hex(pub_key)?;
+                        Wallet::hash_pub_key(&mut pub_key_hash);
+                        if locked_to.as_ref() == Some(&pub_key_hash) {
+                            script_sig = Some(Script::new_p2pkh_unlock(signature, &decode_hex(pub_key)?));
+                            break;
+                        }
+                    }
+                    match script_sig {
+                        Some(script_sig) => script_sig,
+                        None => anyhow::bail!("ERROR: Input {} is not signed by the key its output is locked to", idx),
+                    }
+                }
+            };
+            tx.vin[idx].witness = script_sig.to_witness()?;
+        }
+        tx.id = tx.hash()?;
+        tx.verify(&self.prev_txs())?;
+        Ok(tx)
+    }
+
+    // The outputs spent, by txid, as Transaction::verify takes them.
+    fn prev_txs(&self) -> HashMap<String, Transaction> {
+        let mut prev_txs: HashMap<String, Transaction> = HashMap::new();
+        for (vin, input) in self.tx.vin.iter().zip(&self.inputs) {
+            let prev_tx = prev_txs.entry(vin.txid.clone()).or_insert_with(|| Transaction {
+                id: vin.txid.clone(),
+                vin: Vec::new(),
+                vout: Vec::new(),
+                lock_time: 0,
+            });
+            let idx = vin.vout as usize;
+            if prev_tx.vout.len() <= idx {
+                prev_tx.vout.resize(idx + 1, TXOutput { value: 0, script_pub_key: Script::new() });
+            }
+            prev_tx.vout[idx] = input.prev_out.clone();
+        }
+        prev_txs
+    }
+}
+
+// Hash of the key a P2PKH output is locked to, time locked or not.
+fn p2pkh_owner(script: &Script) -> Option<Vec<u8>> {
+    match script.locked_p2pkh() {
+        Some((_, _, hash)) => Some(hash),
+        None => script.p2pkh_hash().map(|hash| hash.to_vec()),
+    }
+}
+
+// Sighash type by name: ALL, NONE or SINGLE, with an optional |ANYONECANPAY.
+pub fn parse_sighash(name: &str) -> Result<u8> {
+    let upper = name.to_uppercase();
+    let (base, anyone_can_pay) = match upper.strip_suffix("|ANYONECANPAY") {
+        Some(base) => (base, SIGHASH_ANYONECANPAY),
+        None => (upper.as_str(), 0),
+    };
+    let base = match base {
+        "ALL" => SIGHASH_ALL,
+        "NONE" => SIGHASH_NONE,
+        "SINGLE" => SIGHASH_SINGLE,
+        _ => anyhow::bail!("ERROR: Unknown sighash type `{}`", name),
+    };
+    Ok(base | anyone_can_pay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tx::TXInput;
+
+    // Two P2PKH coinbase outputs of different owners spent by one transaction.
+    fn setup(owners: &[&Wallet]) -> Psbt {
+        let prevs: Vec<Transaction> = owners
+            .iter()
+            .map(|w| Transaction::new_coinbase(w.get_address(), String::from("test"), 1).unwrap())
+            .collect();
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: prevs.iter().map(|p| TXInput::new(p.id.clone(), 0)).collect(),
+            vout: vec![TXOutput::new(150, Wallet::new().get_address()).unwrap()],
+            lock_time: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        let inputs = prevs
+            .iter()
+            .map(|p| PsbtInput {
+                prev_out: p.vout[0].clone(),
+                redeem_script: None,
+                sighash_type: SIGHASH_ALL,
+                signatures: BTreeMap::new(),
+            })
+            .collect();
+        Psbt { tx, inputs }
+    }
+
+    #[test]
+    fn test_sign_offline_and_combine() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let mut first = setup(&[&alice, &bob]);
+        let mut second = first.clone();
+
+        assert_eq!(first.sign(&alice).unwrap(), 1);
+        assert!(first.finalize().is_err());
+        assert!(first.sign(&Wallet::new()).is_err());
+        assert_eq!(second.sign(&bob).unwrap(), 1);
+
+        first.combine(&second).unwrap();
+        // Signatures of other keys are passed over, whatever their order.
+        let mut extra = first.clone();
+        extra.inputs[0].signatures.insert(String::from("00"), vec![1]);
+        extra.inputs[0].signatures.extend(first.inputs[1].signatures.clone());
+        extra.finalize().unwrap();
+        first.finalize().unwrap();
+
+        // A signature of another transaction is caught.
+        second.inputs[0].signatures = first.inputs[1].signatures.clone();
+        assert!(second.finalize().is_err());
+    }
+
+    #[test]
+    fn test_parse_sighash() {
+        assert_eq!(parse_sighash("all").unwrap(), SIGHASH_ALL);
+        assert_eq!(parse_sighash("SINGLE|ANYONECANPAY").unwrap(), SIGHASH_SINGLE | SIGHASH_ANYONECANPAY);
+        assert!(parse_sighash("ANYONECANPAY").is_err());
+    }
+}
@@ -0,0 +1,925 @@
+use std::fmt;
+use serde::{Serialize, Deserialize};
+use crypto::{
+    digest::Digest,
+    sha2::Sha256
+};
+
+use crate::errors::Result;
+use crate::wallet::Wallet;
+use crate::util::encode_hex;
+
+// Opcodes, same values as Bitcoin script.
+// 0x01..=0x4b push the next n bytes.
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_SIZE: u8 = 0x82;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+
+const MAX_SCRIPT_SIZE: usize = 10_000;
+const MAX_PUSH_SIZE: usize = 520;
+const MAX_STACK_SIZE: usize = 1_000;
+pub const MAX_MULTISIG_KEYS: usize = 16;
+// Largest payload of a data output.
+pub const MAX_DATA_SIZE: usize = 80;
+
+// A locking script (in TXOutput) or an unlocking script (in TXInput), as raw bytes.
+// The unlocking script runs first, the locking script then runs on the stack it left.
+// The output is spent when the locking script finishes with a true value on top of the stack.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Script(pub Vec<u8>);
+
+// Terms of a hash time-locked contract, see Script::new_htlc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Htlc {
+    pub hash: Vec<u8>,
+    pub recipient_hash: Vec<u8>,
+    pub timeout: u32,
+    pub sender_hash: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instruction {
+    Op(u8),
+    Push(Vec<u8>),
+}
+
+// Gives the interpreter access to the spending transaction.
+// `script_code` is the locking script being run, it is part of the signed data.
+pub trait SignatureChecker {
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool;
+    // Whether the transaction lock_time satisfies `lock_time`.
+    fn check_lock_time(&self, lock_time: i64) -> bool;
+    // Whether the input sequence satisfies the relative lock `sequence`.
+    fn check_sequence(&self, sequence: i64) -> bool;
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script(Vec::new())
+    }
+
+    pub fn push_opcode(mut self, op: u8) -> Script {
+        self.0.push(op);
+        self
+    }
+
+    pub fn push_data(mut self, data: &[u8]) -> Script {
+        if data.len() < OP_PUSHDATA1 as usize {
+            self.0.push(data.len() as u8);
+        } else if data.len() <= 0xff {
+            self.0.push(OP_PUSHDATA1);
+            self.0.push(data.len() as u8);
+        } else if data.len() <= 0xffff {
+            self.0.push(OP_PUSHDATA2);
+            self.0.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        } else {
+            self.0.push(OP_PUSHDATA4);
+            self.0.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    // Small numbers use OP_0 and OP_1..OP_16, others a script number push.
+    pub fn push_int(self, n: i64) -> Script {
+        match n {
+            0 => self.push_opcode(OP_0),
+            1..=16 => self.push_opcode(OP_1 + n as u8 - 1),
+            _ => self.push_data(&encode_num(n)),
+        }
+    }
+
+    // Pay to public key hash:
+    // OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(pub_key_hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    // P2PKH spendable once the absolute lock time is reached, e.g. vesting:
+    // <lock_time> OP_CHECKLOCKTIMEVERIFY OP_DROP OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_cltv_p2pkh(lock_time: u32, pub_key_hash: &[u8]) -> Script {
+        let mut script = Script::new()
+            .push_int(lock_time as i64)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP);
+        script.0.extend(Script::new_p2pkh(pub_key_hash).0);
+        script
+    }
+
+    // P2PKH spendable once the output is `sequence` blocks, or time, old:
+    // <sequence> OP_CHECKSEQUENCEVERIFY OP_DROP OP_DUP OP_HASH160 <pub_key_hash> OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_csv_p2pkh(sequence: u32, pub_key_hash: &[u8]) -> Script {
+        let mut script = Script::new()
+            .push_int(sequence as i64)
+            .push_opcode(OP_CHECKSEQUENCEVERIFY)
+            .push_opcode(OP_DROP);
+        script.0.extend(Script::new_p2pkh(pub_key_hash).0);
+        script
+    }
+
+    // Hash time-locked contract: paid to the recipient revealing the preimage of `hash`,
+    // or back to the sender once `timeout` (a height or unix time) is reached.
+    // OP_IF
+    //     OP_SHA256 <hash> OP_EQUALVERIFY OP_DUP OP_HASH160 <recipient_hash>
+    // OP_ELSE
+    //     <timeout> OP_CHECKLOCKTIMEVERIFY OP_DROP OP_DUP OP_HASH160 <sender_hash>
+    // OP_ENDIF
+    // OP_EQUALVERIFY OP_CHECKSIG
+    pub fn new_htlc(hash: &[u8], recipient_hash: &[u8], timeout: u32, sender_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SHA256)
+            .push_data(hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(recipient_hash)
+            .push_opcode(OP_ELSE)
+            .push_int(timeout as i64)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(sender_hash)
+            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    // Redeems an HTLC: <signature> <pub_key> <preimage> OP_1
+    pub fn new_htlc_redeem(signature: &[u8], pub_key: &[u8], preimage: &[u8]) -> Script {
+        Script::new()
+            .push_data(signature)
+            .push_data(pub_key)
+            .push_data(preimage)
+            .push_int(1)
+    }
+
+    // Refunds an HTLC: <signature> <pub_key> OP_0
+    pub fn new_htlc_refund(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script::new().push_data(signature).push_data(pub_key).push_int(0)
+    }
+
+    // Funding output of a unidirectional payment channel: spent by payer and payee together,
+    // or by the payer alone once `timeout` (a height or unix time) is reached.
+    // OP_IF
+    //     <payee_pub_key> OP_CHECKSIGVERIFY
+    // OP_ELSE
+    //     <timeout> OP_CHECKLOCKTIMEVERIFY OP_DROP
+    // OP_ENDIF
+    // <payer_pub_key> OP_CHECKSIG
+    pub fn new_channel(payer_pub_key: &[u8], payee_pub_key: &[u8], timeout: u32) -> Script {
+        Script::new()
+            .push_opcode(OP_IF)
+            .push_data(payee_pub_key)
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_opcode(OP_ELSE)
+            .push_int(timeout as i64)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_opcode(OP_ENDIF)
+            .push_data(payer_pub_key)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    // Closes a channel with the latest payment: <payer_signature> <payee_signature> OP_1
+    pub fn new_channel_close(payer_signature: &[u8], payee_signature: &[u8]) -> Script {
+        Script::new()
+            .push_data(payer_signature)
+            .push_data(payee_signature)
+            .push_int(1)
+    }
+
+    // Takes the channel back after the timeout: <payer_signature> OP_0
+    pub fn new_channel_refund(payer_signature: &[u8]) -> Script {
+        Script::new().push_data(payer_signature).push_int(0)
+    }
+
+    // Provably unspendable output carrying application data: OP_RETURN <data>
+    pub fn new_data(data: &[u8]) -> Result<Script> {
+        if data.len() > MAX_DATA_SIZE {
+            anyhow::bail!("ERROR: Data output carries {} bytes, at most {} allowed", data.len(), MAX_DATA_SIZE)
+        }
+        Ok(Script::new().push_opcode(OP_RETURN).push_data(data))
+    }
+
+    // Scripts starting with OP_RETURN always fail, their outputs can never be spent.
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN)
+    }
+
+    // Payload of a script made by new_data.
+    pub fn data(&self) -> Option<Vec<u8>> {
+        match self.instructions().ok()?.as_slice() {
+            [Instruction::Op(OP_RETURN), Instruction::Push(data)] => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    // Terms of a script made by new_htlc.
+    pub fn htlc(&self) -> Option<Htlc> {
+        use Instruction::{Op, Push};
+        match self.instructions().ok()?.as_slice() {
+            [Op(OP_IF), Op(OP_SHA256), Push(hash), Op(OP_EQUALVERIFY), Op(OP_DUP), Op(OP_HASH160), Push(recipient_hash),
+             Op(OP_ELSE), Push(timeout), Op(OP_CHECKLOCKTIMEVERIFY), Op(OP_DROP), Op(OP_DUP), Op(OP_HASH160), Push(sender_hash),
+             Op(OP_ENDIF), Op(OP_EQUALVERIFY), Op(OP_CHECKSIG)] => {
+                let timeout = u32::try_from(decode_num(timeout, 5).ok()?).ok()?;
+                Some(Htlc {
+                    hash: hash.clone(),
+                    recipient_hash: recipient_hash.clone(),
+                    timeout,
+                    sender_hash: sender_hash.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    // Lock opcode, lock and pub_key_hash of a P2PKH output locked
+    // by new_cltv_p2pkh or new_csv_p2pkh.
+    pub fn locked_p2pkh(&self) -> Option<(u8, u32, Vec<u8>)> {
+        let instructions = self.instructions().ok()?;
+        match instructions.as_slice() {
+            [Instruction::Push(lock), Instruction::Op(op @ (OP_CHECKLOCKTIMEVERIFY | OP_CHECKSEQUENCEVERIFY)), Instruction::Op(OP_DROP), rest @ ..] => {
+                let lock = decode_num(lock, 5).ok()?;
+                let mut p2pkh = Script::new();
+                for instruction in rest {
+                    p2pkh = match instruction {
+                        Instruction::Op(op) => p2pkh.push_opcode(*op),
+                        Instruction::Push(data) => p2pkh.push_data(data),
+                    };
+                }
+                let hash = p2pkh.p2pkh_hash()?.to_vec();
+                Some((*op, u32::try_from(lock).ok()?, hash))
+            }
+            _ => None,
+        }
+    }
+
+    // Unlocks a P2PKH output: <signature> <pub_key>
+    pub fn new_p2pkh_unlock(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script::new().push_data(signature).push_data(pub_key)
+    }
+
+    // M-of-N multisig: OP_m <pub_key_1> ... <pub_key_n> OP_n OP_CHECKMULTISIG
+    // Unlocked by m signatures given in the order of their keys.
+    pub fn new_multisig(m: usize, pub_keys: &[Vec<u8>]) -> Result<Script> {
+        if m == 0 || m > pub_keys.len() || pub_keys.len() > MAX_MULTISIG_KEYS {
+            anyhow::bail!("ERROR: Invalid multisig {} of {}", m, pub_keys.len())
+        }
+        let mut script = Script::new().push_opcode(OP_1 + m as u8 - 1);
+        for pub_key in pub_keys {
+            script = script.push_data(pub_key);
+        }
+        Ok(script
+            .push_opcode(OP_1 + pub_keys.len() as u8 - 1)
+            .push_opcode(OP_CHECKMULTISIG))
+    }
+
+    // Return m and the public keys if this is a multisig script.
+    pub fn multisig_keys(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let instructions = self.instructions().ok()?;
+        if instructions.len() < 4 || instructions.last()? != &Instruction::Op(OP_CHECKMULTISIG) {
+            return None;
+        }
+        let small_int = |i: &Instruction| match i {
+            Instruction::Push(data) if data.len() == 1 && data[0] as usize <= MAX_MULTISIG_KEYS => Some(data[0] as usize),
+            _ => None,
+        };
+        let m = small_int(&instructions[0])?;
+        let n = small_int(&instructions[instructions.len() - 2])?;
+        let mut pub_keys = Vec::new();
+        for i in &instructions[1..instructions.len() - 2] {
+            match i {
+                Instruction::Push(key) => pub_keys.push(key.clone()),
+                Instruction::Op(_) => return None,
+            }
+        }
+        if n != pub_keys.len() || m == 0 || m > n {
+            return None;
+        }
+        Some((m, pub_keys))
+    }
+
+    // Pay to script hash: OP_HASH160 <script_hash> OP_EQUAL
+    // Unlocked by the data the redeem script needs followed by the redeem script itself.
+    pub fn new_p2sh(script_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_HASH160)
+            .push_data(script_hash)
+            .push_opcode(OP_EQUAL)
+    }
+
+    // Return the script hash if this is a P2SH locking script.
+    pub fn p2sh_hash(&self) -> Option<&[u8]> {
+        let b = &self.0;
+        if b.len() == 23 && b[0] == OP_HASH160 && b[1] == 20 && b[22] == OP_EQUAL {
+            Some(&b[2..22])
+        } else {
+            None
+        }
+    }
+
+    // hash160 of the script, what a P2SH output is locked to.
+    pub fn hash(&self) -> Vec<u8> {
+        let mut hash = self.0.clone();
+        Wallet::hash_pub_key(&mut hash);
+        hash
+    }
+
+    // Return the pub_key_hash if this is a P2PKH locking script.
+    pub fn p2pkh_hash(&self) -> Option<&[u8]> {
+        let b = &self.0;
+        if b.len() == 25
+            && b[0] == OP_DUP
+            && b[1] == OP_HASH160
+            && b[2] == 20
+            && b[23] == OP_EQUALVERIFY
+            && b[24] == OP_CHECKSIG
+        {
+            Some(&b[3..23])
+        } else {
+            None
+        }
+    }
+
+    pub fn is_push_only(&self) -> Result<bool> {
+        Ok(self.instructions()?.iter().all(|i| matches!(i, Instruction::Push(_))))
+    }
+
+    // Witness stack items of a push-only unlocking script, see TXInput::witness.
+    pub fn to_witness(&self) -> Result<Vec<Vec<u8>>> {
+        self.instructions()?
+            .into_iter()
+            .map(|i| match i {
+                Instruction::Push(item) => Ok(item),
+                Instruction::Op(op) => anyhow::bail!("script error: witness can only hold pushes, found {:#04x}", op),
+            })
+            .collect()
+    }
+
+    // The unlocking script pushing the items of a witness.
+    pub fn from_witness(items: &[Vec<u8>]) -> Script {
+        items.iter().fold(Script::new(), |script, item| script.push_data(item))
+    }
+
+    pub fn instructions(&self) -> Result<Vec<Instruction>> {
+        let b = &self.0;
+        let mut result = Vec::new();
+        let mut pc = 0;
+        while pc < b.len() {
+            let op = b[pc];
+            pc += 1;
+            let len = match op {
+                OP_0 => {
+                    result.push(Instruction::Push(Vec::new()));
+                    continue;
+                }
+                0x01..=0x4b => op as usize,
+                OP_PUSHDATA1 => {
+                    if pc + 1 > b.len() {
+                        anyhow::bail!("script error: truncated OP_PUSHDATA1")
+                    }
+                    pc += 1;
+                    b[pc - 1] as usize
+                }
+                OP_PUSHDATA2 => {
+                    if pc + 2 > b.len() {
+                        anyhow::bail!("script error: truncated OP_PUSHDATA2")
+                    }
+                    pc += 2;
+                    u16::from_le_bytes([b[pc - 2], b[pc - 1]]) as usize
+                }
+                OP_PUSHDATA4 => {
+                    if pc + 4 > b.len() {
+                        anyhow::bail!("script error: truncated OP_PUSHDATA4")
+                    }
+                    pc += 4;
+                    u32::from_le_bytes([b[pc - 4], b[pc - 3], b[pc - 2], b[pc - 1]]) as usize
+                }
+                OP_1..=OP_16 => {
+                    result.push(Instruction::Push(vec![op - OP_1 + 1]));
+                    continue;
+                }
+                _ => {
+                    result.push(Instruction::Op(op));
+                    continue;
+                }
+            };
+            if len > b.len() - pc {
+                anyhow::bail!("script error: push past the end of the script")
+            }
+            result.push(Instruction::Push(b[pc..pc + len].to_vec()));
+            pc += len;
+        }
+        Ok(result)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let instructions = match self.instructions() {
+            Ok(i) => i,
+            Err(_) => return write!(f, "[invalid script]"),
+        };
+        let words: Vec<String> = instructions
+            .iter()
+            .map(|i| match i {
+                Instruction::Push(data) => encode_hex(data),
+                Instruction::Op(op) => opcode_name(*op),
+            })
+            .collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
+
+fn opcode_name(op: u8) -> String {
+    let name = match op {
+        OP_IF => "OP_IF",
+        OP_NOTIF => "OP_NOTIF",
+        OP_ELSE => "OP_ELSE",
+        OP_ENDIF => "OP_ENDIF",
+        OP_VERIFY => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+        OP_DROP => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        OP_SIZE => "OP_SIZE",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        OP_SHA256 => "OP_SHA256",
+        OP_HASH160 => "OP_HASH160",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        OP_CHECKSIGVERIFY => "OP_CHECKSIGVERIFY",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        OP_CHECKMULTISIGVERIFY => "OP_CHECKMULTISIGVERIFY",
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY",
+        OP_CHECKSEQUENCEVERIFY => "OP_CHECKSEQUENCEVERIFY",
+        _ => return format!("OP_UNKNOWN_{:02x}", op),
+    };
+    name.to_string()
+}
+
+// Run the unlocking script then the locking script.
+// For P2SH, the redeem script on top of the unlocking stack then runs on the rest of it.
+// Return an error telling why when the output can not be spent.
+pub fn verify_script(
+    script_sig: &Script,
+    script_pub_key: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<()> {
+    if !script_sig.is_push_only()? {
+        anyhow::bail!("script error: unlocking script must only push data")
+    }
+    let mut stack = Vec::new();
+    eval(script_sig, &mut stack, checker)?;
+    let mut p2sh_stack = stack.clone();
+
+    eval(script_pub_key, &mut stack, checker)?;
+    check_result(&stack)?;
+
+    if script_pub_key.p2sh_hash().is_some() {
+        // The hash matched, so the unlocking script did push a redeem script.
+        let redeem_script = Script(p2sh_stack.pop().unwrap());
+        eval(&redeem_script, &mut p2sh_stack, checker)?;
+        check_result(&p2sh_stack)?;
+    }
+    Ok(())
+}
+
+fn check_result(stack: &[Vec<u8>]) -> Result<()> {
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => anyhow::bail!("script error: script finished with a false result"),
+    }
+}
+
+fn eval(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<()> {
+    if script.0.len() > MAX_SCRIPT_SIZE {
+        anyhow::bail!("script error: script is too large")
+    }
+    // One entry per enclosing OP_IF, whether its branch currently runs.
+    let mut exec: Vec<bool> = Vec::new();
+
+    for instruction in script.instructions()? {
+        let executing = exec.iter().all(|e| *e);
+        match instruction {
+            Instruction::Push(data) => {
+                if data.len() > MAX_PUSH_SIZE {
+                    anyhow::bail!("script error: push exceeds {} bytes", MAX_PUSH_SIZE)
+                }
+                if executing {
+                    stack.push(data);
+                }
+            }
+            Instruction::Op(op) => match op {
+                OP_IF | OP_NOTIF => {
+                    let mut branch = false;
+                    if executing {
+                        branch = cast_to_bool(&pop(stack, op)?);
+                        if op == OP_NOTIF {
+                            branch = !branch;
+                        }
+                    }
+                    exec.push(branch);
+                }
+                OP_ELSE => match exec.last_mut() {
+                    Some(e) => *e = !*e,
+                    None => anyhow::bail!("script error: OP_ELSE without OP_IF"),
+                },
+                OP_ENDIF => {
+                    if exec.pop().is_none() {
+                        anyhow::bail!("script error: OP_ENDIF without OP_IF")
+                    }
+                }
+                _ if !executing => {}
+                OP_VERIFY => {
+                    if !cast_to_bool(&pop(stack, op)?) {
+                        anyhow::bail!("script error: OP_VERIFY failed")
+                    }
+                }
+                OP_RETURN => anyhow::bail!("script error: OP_RETURN"),
+                OP_DROP => {
+                    pop(stack, op)?;
+                }
+                OP_DUP => {
+                    let top = pop(stack, op)?;
+                    stack.push(top.clone());
+                    stack.push(top);
+                }
+                OP_SIZE => {
+                    let len = match stack.last() {
+                        Some(top) => top.len(),
+                        None => anyhow::bail!("script error: {} on an empty stack", opcode_name(op)),
+                    };
+                    stack.push(encode_num(len as i64));
+                }
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    let a = pop(stack, op)?;
+                    let b = pop(stack, op)?;
+                    if op == OP_EQUALVERIFY {
+                        if a != b {
+                            anyhow::bail!("script error: OP_EQUALVERIFY failed")
+                        }
+                    } else {
+                        stack.push(bool_to_item(a == b));
+                    }
+                }
+                OP_SHA256 => {
+                    let data = pop(stack, op)?;
+                    let mut hasher = Sha256::new();
+                    hasher.input(&data);
+                    let mut hash = vec![0; 32];
+                    hasher.result(&mut hash);
+                    stack.push(hash);
+                }
+                OP_HASH160 => {
+                    let mut data = pop(stack, op)?;
+                    Wallet::hash_pub_key(&mut data);
+                    stack.push(data);
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    let pub_key = pop(stack, op)?;
+                    let signature = pop(stack, op)?;
+                    let ok = checker.check_sig(&signature, &pub_key, script);
+                    if op == OP_CHECKSIGVERIFY {
+                        if !ok {
+                            anyhow::bail!("script error: OP_CHECKSIGVERIFY failed")
+                        }
+                    } else {
+                        stack.push(bool_to_item(ok));
+                    }
+                }
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    let ok = check_multisig(stack, op, script, checker)?;
+                    if op == OP_CHECKMULTISIGVERIFY {
+                        if !ok {
+                            anyhow::bail!("script error: OP_CHECKMULTISIGVERIFY failed")
+                        }
+                    } else {
+                        stack.push(bool_to_item(ok));
+                    }
+                }
+                OP_CHECKLOCKTIMEVERIFY | OP_CHECKSEQUENCEVERIFY => {
+                    // The lock stays on the stack, scripts usually OP_DROP it.
+                    let lock = match stack.last() {
+                        // Lock times are u32, which may need 5 bytes.
+                        Some(top) => decode_num(top, 5)?,
+                        None => anyhow::bail!("script error: {} on an empty stack", opcode_name(op)),
+                    };
+                    if lock < 0 {
+                        anyhow::bail!("script error: negative lock time")
+                    }
+                    if op == OP_CHECKLOCKTIMEVERIFY {
+                        if !checker.check_lock_time(lock) {
+                            anyhow::bail!("script error: OP_CHECKLOCKTIMEVERIFY failed, lock time {} not reached", lock)
+                        }
+                    } else if lock & (1 << 31) == 0 && !checker.check_sequence(lock) {
+                        // With the disable flag set, the opcode does nothing.
+                        anyhow::bail!("script error: OP_CHECKSEQUENCEVERIFY failed, relative lock {} not reached", lock)
+                    }
+                }
+                _ => anyhow::bail!("script error: unknown opcode 0x{:02x}", op),
+            },
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            anyhow::bail!("script error: stack overflow")
+        }
+    }
+
+    if !exec.is_empty() {
+        anyhow::bail!("script error: OP_IF without OP_ENDIF")
+    }
+    Ok(())
+}
+
+// Stack: <sig_1> ... <sig_m> m <pub_key_1> ... <pub_key_n> n
+// Signatures must be in the same order as their keys.
+fn check_multisig(
+    stack: &mut Vec<Vec<u8>>,
+    op: u8,
+    script: &Script,
+    checker: &dyn SignatureChecker,
+) -> Result<bool> {
+    let n = decode_num(&pop(stack, op)?, 4)?;
+    if n < 0 || n as usize > MAX_MULTISIG_KEYS {
+        anyhow::bail!("script error: invalid multisig key count {}", n)
+    }
+    let mut pub_keys = Vec::new();
+    for _ in 0..n {
+        pub_keys.push(pop(stack, op)?);
+    }
+    pub_keys.reverse();
+
+    let m = decode_num(&pop(stack, op)?, 4)?;
+    if m < 0 || m > n {
+        anyhow::bail!("script error: invalid multisig signature count {}", m)
+    }
+    let mut signatures = Vec::new();
+    for _ in 0..m {
+        signatures.push(pop(stack, op)?);
+    }
+    signatures.reverse();
+
+    let mut keys = pub_keys.iter();
+    for signature in &signatures {
+        // Skip keys until one matches, a key is never reused.
+        if !keys.any(|key| checker.check_sig(signature, key, script)) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>, op: u8) -> Result<Vec<u8>> {
+    match stack.pop() {
+        Some(item) => Ok(item),
+        None => anyhow::bail!("script error: {} on an empty stack", opcode_name(op)),
+    }
+}
+
+fn cast_to_bool(item: &[u8]) -> bool {
+    for (i, b) in item.iter().enumerate() {
+        if *b != 0 {
+            // Negative zero is false.
+            return !(i == item.len() - 1 && *b == 0x80);
+        }
+    }
+    false
+}
+
+fn bool_to_item(b: bool) -> Vec<u8> {
+    if b {
+        vec![1]
+    } else {
+        Vec::new()
+    }
+}
+
+// Script numbers are little endian with the sign in the highest bit of the last byte.
+pub fn decode_num(item: &[u8], max_len: usize) -> Result<i64> {
+    if item.len() > max_len {
+        anyhow::bail!("script error: number is too large")
+    }
+    let mut n: i64 = 0;
+    for (i, b) in item.iter().enumerate() {
+        n |= (*b as i64) << (8 * i);
+    }
+    if let Some(last) = item.last() {
+        if last & 0x80 != 0 {
+            n &= !(0x80_i64 << (8 * (item.len() - 1)));
+            n = -n;
+        }
+    }
+    Ok(n)
+}
+
+fn encode_num(n: i64) -> Vec<u8> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    let mut result = Vec::new();
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if result.last().unwrap() & 0x80 != 0 {
+        result.push(if negative { 0x80 } else { 0 });
+    } else if negative {
+        *result.last_mut().unwrap() |= 0x80;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Signatures are valid when they are `b"sig"` followed by the key.
+    struct Checker;
+
+    impl SignatureChecker for Checker {
+        fn check_sig(&self, signature: &[u8], pub_key: &[u8], _script_code: &Script) -> bool {
+            signature.len() == pub_key.len() + 3 && signature[..3] == *b"sig" && signature[3..] == *pub_key
+        }
+
+        fn check_lock_time(&self, _lock_time: i64) -> bool {
+            true
+        }
+
+        fn check_sequence(&self, _sequence: i64) -> bool {
+            true
+        }
+    }
+
+    fn run(script: Script) -> Result<Vec<Vec<u8>>> {
+        let mut stack = Vec::new();
+        eval(&script, &mut stack, &Checker)?;
+        Ok(stack)
+    }
+
+    #[test]
+    fn test_push_decoding() {
+        let data = vec![7; 300];
+        let script = Script::new()
+            .push_opcode(OP_0)
+            .push_data(&[1, 2])
+            .push_data(&data[..100])
+            .push_data(&data)
+            .push_int(16)
+            .push_int(-1);
+        assert_eq!(script.0[4], OP_PUSHDATA1);
+        assert_eq!(
+            script.instructions().unwrap(),
+            vec![
+                Instruction::Push(Vec::new()),
+                Instruction::Push(vec![1, 2]),
+                Instruction::Push(data[..100].to_vec()),
+                Instruction::Push(data.clone()),
+                Instruction::Push(vec![16]),
+                Instruction::Push(vec![0x81]),
+            ]
+        );
+
+        let mut pushdata4 = vec![OP_PUSHDATA4, 3, 0, 0, 0, 1, 2, 3];
+        assert_eq!(Script(pushdata4.clone()).instructions().unwrap(), vec![Instruction::Push(vec![1, 2, 3])]);
+        pushdata4.pop();
+        assert!(Script(pushdata4).instructions().is_err());
+        // Past what a 2 byte length holds.
+        let big = vec![7; 0x10000];
+        let script = Script::new().push_data(&big);
+        assert_eq!(script.0[..5], [OP_PUSHDATA4, 0, 0, 1, 0]);
+        assert_eq!(script.instructions().unwrap(), vec![Instruction::Push(big)]);
+
+        // Truncated lengths and pushes.
+        for bytes in [
+            vec![0x02, 1],
+            vec![OP_PUSHDATA1],
+            vec![OP_PUSHDATA1, 2, 1],
+            vec![OP_PUSHDATA2, 1],
+            vec![OP_PUSHDATA2, 1, 0],
+            vec![OP_PUSHDATA4, 1, 0, 0],
+            vec![OP_PUSHDATA4, 0xff, 0xff, 0xff, 0xff, 1],
+        ] {
+            assert!(Script(bytes.clone()).instructions().is_err(), "{:?}", bytes);
+        }
+        // Too large to run, even when well formed.
+        assert!(run(Script::new().push_data(&[0; MAX_PUSH_SIZE + 1])).is_err());
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let branch = |cond: i64, op: u8| {
+            run(Script::new()
+                .push_int(cond)
+                .push_opcode(op)
+                .push_int(2)
+                .push_opcode(OP_ELSE)
+                .push_int(3)
+                .push_opcode(OP_ENDIF))
+            .unwrap()
+        };
+        assert_eq!(branch(1, OP_IF), vec![vec![2]]);
+        assert_eq!(branch(0, OP_IF), vec![vec![3]]);
+        assert_eq!(branch(0, OP_NOTIF), vec![vec![2]]);
+
+        // The inner condition is not popped when the outer branch is skipped.
+        let nested = |outer: i64| {
+            run(Script::new()
+                .push_int(1)
+                .push_int(outer)
+                .push_opcode(OP_IF)
+                .push_opcode(OP_IF)
+                .push_int(4)
+                .push_opcode(OP_ELSE)
+                .push_int(5)
+                .push_opcode(OP_ENDIF)
+                .push_opcode(OP_ELSE)
+                .push_int(6)
+                .push_opcode(OP_ENDIF))
+            .unwrap()
+        };
+        assert_eq!(nested(1), vec![vec![4]]);
+        assert_eq!(nested(0), vec![vec![1], vec![6]]);
+
+        assert!(run(Script::new().push_int(1).push_opcode(OP_IF)).is_err());
+        assert!(run(Script::new().push_opcode(OP_ELSE)).is_err());
+        assert!(run(Script::new().push_opcode(OP_ENDIF)).is_err());
+        assert!(run(Script::new().push_opcode(OP_IF)).is_err());
+    }
+
+    #[test]
+    fn test_cast_to_bool() {
+        assert!(!cast_to_bool(&[]));
+        assert!(!cast_to_bool(&[0, 0]));
+        assert!(!cast_to_bool(&[0x80]));
+        assert!(!cast_to_bool(&[0, 0, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0]));
+        assert!(cast_to_bool(&[0, 1]));
+        assert!(cast_to_bool(&[0x81]));
+    }
+
+    #[test]
+    fn test_verify() {
+        assert!(run(Script::new().push_int(1).push_opcode(OP_VERIFY)).unwrap().is_empty());
+        let err = run(Script::new().push_data(&[0x80]).push_opcode(OP_VERIFY)).unwrap_err();
+        assert!(err.to_string().contains("OP_VERIFY failed"), "{}", err);
+        assert!(run(Script::new().push_opcode(OP_VERIFY)).is_err());
+        // Runs, but leaves nothing true on the stack.
+        assert!(verify_script(&Script::new(), &Script::new().push_int(1).push_opcode(OP_VERIFY), &Checker).is_err());
+    }
+
+    #[test]
+    fn test_p2pkh() {
+        let pub_key = vec![9; 32];
+        let mut pub_key_hash = pub_key.clone();
+        Wallet::hash_pub_key(&mut pub_key_hash);
+        let lock = Script::new_p2pkh(&pub_key_hash);
+        let signature = [b"sig".to_vec(), pub_key.clone()].concat();
+
+        verify_script(&Script::new_p2pkh_unlock(&signature, &pub_key), &lock, &Checker).unwrap();
+
+        let err = verify_script(&Script::new_p2pkh_unlock(&signature, &[8; 32]), &lock, &Checker).unwrap_err();
+        assert!(err.to_string().contains("OP_EQUALVERIFY"), "{}", err);
+        let err = verify_script(&Script::new_p2pkh_unlock(b"bad", &pub_key), &lock, &Checker).unwrap_err();
+        assert!(err.to_string().contains("false result"), "{}", err);
+        // Hashing items shorter than a key.
+        let short = Script::new().push_data(&[1, 2, 3]);
+        let mut short_hash = short.0[1..].to_vec();
+        Wallet::hash_pub_key(&mut short_hash);
+        let stack = run(short.push_opcode(OP_HASH160)).unwrap();
+        assert_eq!(stack, vec![short_hash.clone()]);
+        assert_eq!(short_hash.len(), 20);
+        // The unlocking script may only push.
+        let unlock = Script::new_p2pkh_unlock(&signature, &pub_key).push_opcode(OP_DROP);
+        assert!(verify_script(&unlock, &lock, &Checker).is_err());
+    }
+}
@@ -1,20 +1,22 @@
 use std::collections::{HashSet, HashMap};
 use std::sync::{Arc, Mutex};
 use std::io::{Read, Write};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use std::thread;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
 
 use log::{info, debug};
 use serde::{Serialize, Deserialize};
 
 use crate::utxoset::UTXOSet;
 use crate::errors::Result;
-use crate::block::Block;
+use crate::chainparams::params;
+use crate::block::{Block, BlockTemplate};
 use crate::transaction::Transaction;
+use crate::history::History;
+use crate::wallet::{close_wallets_db, wallets_path};
 
-const KNOWN_NODE1: &str = "localhost:3000";
-const CMD_LEN: usize = 12;
+pub(crate) const CMD_LEN: usize = 12;
 const VERSION: i32 = 1;
 
 pub struct Server {
@@ -26,13 +28,17 @@ struct ServerInner {
     known_nodes: HashSet<String>,
     utxo: UTXOSet,
     blocks_in_transit: Vec<String>,
+    // By wtxid, which "tx" inv and getdata messages carry.
     mempool: HashMap<String, Transaction>,
+    // Blocks from peers waiting for the block they build on, by previous block hash.
+    // A sync sends the newest blocks first.
+    orphans: HashMap<String, Block>,
 }
 
 impl Server {
     pub fn new(port: &str, miner_address: &str, utxo: UTXOSet) -> Result<Server> {
         let mut node_set = HashSet::new();
-        node_set.insert(String::from(KNOWN_NODE1));
+        node_set.insert(String::from(params().known_node));
         Ok(Server {
             node_address: String::from("localhost:") + port,
             mining_address: miner_address.to_string(),
@@ -41,16 +47,39 @@ impl Server {
                 utxo,
                 blocks_in_transit: Vec::new(),
                 mempool: HashMap::new(),
+                orphans: HashMap::new(),
             })),
         })
     }
 
     pub fn send_transaction(tx: &Transaction, utxoset: UTXOSet) -> Result<()> {
         let server = Server::new("7000", "", utxoset)?;
-        server.send_tx(KNOWN_NODE1, tx)?;
+        server.send_tx(params().known_node, tx)?;
         Ok(())
     }
 
+    // RPC for external miners: ask a node for the template of the next block.
+    pub fn request_block_template(node: &str) -> Result<BlockTemplate> {
+        let data = GetTemplatemsg {
+            addr_from: String::new(),
+        };
+        let data = bincode::serialize(&(cmd_to_bytes("gettemplate"), data))?;
+        let reply = call(node, &data)?;
+        Ok(bincode::deserialize(&reply)?)
+    }
+
+    // RPC for external miners: hand a solved block back to the node.
+    pub fn submit_block_to(node: &str, block: &Block) -> Result<()> {
+        let data = Blockmsg {
+            addr_from: String::new(),
+            block: block.clone(),
+        };
+        let data = bincode::serialize(&(cmd_to_bytes("submitblock"), data))?;
+        let reply = call(node, &data)?;
+        let result: std::result::Result<(), String> = bincode::deserialize(&reply)?;
+        result.map_err(|e| anyhow::anyhow!(e))
+    }
+
     pub fn start_server(&self) -> Result<()> {
         let server1 = Server {
             node_address: self.node_address.clone(),
@@ -70,7 +99,7 @@ impl Server {
                 server1.request_blocks()
             } else {
                 // Sync blocks between nodes - check send_version and handle version.
-                server1.send_version(KNOWN_NODE1)
+                server1.send_version(params().known_node)
             }
         });
 
@@ -112,6 +141,8 @@ impl Server {
             Message::GetData(data) => self.handle_get_data(data)?,
             Message::Tx(data) => self.handle_tx(data)?,
             Message::Version(data) => self.handle_version(data)?,
+            Message::GetTemplate(data) => self.handle_get_template(data, &mut stream)?,
+            Message::SubmitBlock(data) => self.handle_submit_block(data, &mut stream)?,
         }
 
         Ok(())
@@ -222,7 +253,10 @@ impl Server {
             msg.addr_from,
             msg.block.get_hash()
         );
-        self.add_block(msg.block)?;
+        let hash = msg.block.get_hash();
+        if let Err(e) = self.accept_block(msg.block) {
+            info!("reject block {}: {}", hash, e);
+        }
 
         let mut in_transit = self.get_in_transit();
         if in_transit.len() > 0 {
@@ -230,18 +264,27 @@ impl Server {
             self.send_get_data(&msg.addr_from, "block", block_hash)?;
             in_transit.remove(0);
             self.replace_in_transit(in_transit);
-        } else {
-            self.utxo_reindex()?;
         }
         Ok(())
     }
 
-    fn get_in_transit(&self) -> Vec<String> {
-        self.inner.lock().unwrap().blocks_in_transit.clone()
+    // A block from a peer is only stored once validated, when it can be connected.
+    // Until then it waits with the orphans.
+    fn accept_block(&self, block: Block) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.utxo.blockchain.has_block(&block.get_hash())? {
+            return Ok(());
+        }
+        // Cheap to check, keeps junk out of the orphans.
+        if !block.is_solved()? {
+            anyhow::bail!("ERROR: Invalid proof of work")
+        }
+        inner.orphans.insert(block.get_prev_block_hash(), block);
+        inner.connect_orphans()
     }
 
-    fn add_block(&self, block: Block) -> Result<()> {
-        self.inner.lock().unwrap().utxo.blockchain.add_block(block)
+    fn get_in_transit(&self) -> Vec<String> {
+        self.inner.lock().unwrap().blocks_in_transit.clone()
     }
 
     fn get_block(&self, block_hash: &str) -> Result<Block> {
@@ -301,73 +344,82 @@ impl Server {
     //TODO: What does this function suppose to do?
     fn handle_tx(&self, msg: Txmsg) -> Result<()> {
         info!("receive tx msg: {} {}", msg.addr_from, &msg.transaction.id);
-        self.insert_mempool(msg.transaction.clone());
+        if let Err(e) = self.check_mempool_tx(&msg.transaction) {
+            info!("reject tx {}: {}", msg.transaction.id, e);
+            return Ok(());
+        }
+        self.insert_mempool(msg.transaction.clone())?;
 
         let known_nodes = self.get_known_nodes();
 
-        if self.node_address == KNOWN_NODE1 {
+        if self.node_address == params().known_node {
             // Forwarding transaction to other nodes. If current node is not Miner.
             for node in known_nodes {
                 if node != self.node_address && node != msg.addr_from {
-                    self.send_inv(&node, "tx", vec![msg.transaction.id.clone()])?;
+                    self.send_inv(&node, "tx", vec![msg.transaction.wtxid()?])?;
                 }
             }
         } else {
             // Miner Node.
-            let mut mempool = self.get_mempool();
+            let mempool = self.get_mempool();
             debug!("Current mempool: {:#?}", &mempool);
             if mempool.len() >= 1 && !self.mining_address.is_empty() {
-                // Mining.(Why not do in the another thread.)
-                loop {
-                    // 1. Preparing Transactions.
-                    let mut txs = Vec::new();
-
-                    for(_, tx) in &mempool {
-                        if self.verify_tx(tx)? {
-                            txs.push(tx.clone());
-                        }
-                    }
-                    if txs.is_empty() {
-                        return Ok(());
-                    }
-                    let cbtx = Transaction::new_coinbase(self.mining_address.clone(), String::new())?;
-
-                    txs.push(cbtx);
-
-                    for tx in &txs {
-                        mempool.remove(&tx.id);
-                    }
+                // 1. Preparing Transactions.
+                let template = self.get_block_template()?;
+                if template.transactions.is_empty() {
+                    return Ok(());
+                }
+                let cbtx = Transaction::new_coinbase_with_value(
+                    self.mining_address.clone(),
+                    String::new(),
+                    template.coinbase_value,
+                    template.height,
+                )?;
+
+                // 2. Mining(Find Hash meet Bitcoin requirements)
+                let mut new_block = Block::from_template(&template, cbtx)?;
+                new_block.run_proof_if_work()?;
+                self.submit_block(new_block.clone())?;
+
+                // 3. Publishing Mined Block.
+                self.broadcast_block(&new_block)?;
+            }
+        }
+        Ok(())
+    }
 
-                    // 2. Mining(Find Hash meet Bitcoin requirements)
-                    let new_block = self.mine_block(txs)?;
-                    self.utxo_reindex()?;
+    fn handle_get_template(&self, msg: GetTemplatemsg, stream: &mut TcpStream) -> Result<()> {
+        info!("receive get template msg: {:#?}", msg);
+        let template = self.get_block_template()?;
+        stream.write_all(&bincode::serialize(&template)?)?;
+        Ok(())
+    }
 
-                    // 3. Publishing Mined Block.
-                    for node in self.get_known_nodes() {
-                        if node != self.node_address {
-                            self.send_inv(&node, "block", vec![new_block.get_hash()])?;
-                        }
-                    }
+    fn handle_submit_block(&self, msg: Blockmsg, stream: &mut TcpStream) -> Result<()> {
+        info!("receive submit block msg: {}", msg.block.get_hash());
+        let result = self.submit_block(msg.block.clone()).map_err(|e| e.to_string());
+        stream.write_all(&bincode::serialize(&result)?)?;
+        if result.is_ok() {
+            self.broadcast_block(&msg.block)?;
+        }
+        Ok(())
+    }
 
-                    // 4. Exist loop if no transaction available.
-                    if mempool.len() == 0 {
-                        break;
-                    }
-                }
-                // Clear All? Thread Safe?
-                self.clear_mempool();
+    fn broadcast_block(&self, block: &Block) -> Result<()> {
+        for node in self.get_known_nodes() {
+            if node != self.node_address {
+                self.send_inv(&node, "block", vec![block.get_hash()])?;
             }
         }
         Ok(())
     }
 
     // Operations for mempool
-    fn clear_mempool(&self) {
-        self.inner.lock().unwrap().mempool.clear()
-    }
-    
-    fn insert_mempool(&self, tx: Transaction) {
-        self.inner.lock().unwrap().mempool.insert(tx.id.clone(), tx);
+
+    fn insert_mempool(&self, tx: Transaction) -> Result<()> {
+        let wtxid = tx.wtxid()?;
+        self.inner.lock().unwrap().mempool.insert(wtxid, tx);
+        Ok(())
     }
 
     fn get_mempool_tx(&self, addr: &str) -> Option<Transaction> {
@@ -396,14 +448,14 @@ impl Server {
             }
             self.replace_in_transit(new_in_transit);
         } else if msg.kind == "tx" {
-            let txid = &msg.items[0];
-            match self.get_mempool_tx(txid) {
+            let wtxid = &msg.items[0];
+            match self.get_mempool_tx(wtxid) {
                 Some(tx) => {
                     if tx.id.is_empty() {
-                        self.send_get_data(&msg.addr_from, "tx", txid)?
+                        self.send_get_data(&msg.addr_from, "tx", wtxid)?
                     }
                 }
-                None => self.send_get_data(&msg.addr_from, "tx", txid)?,
+                None => self.send_get_data(&msg.addr_from, "tx", wtxid)?,
             }
         }
         Ok(())
@@ -434,20 +486,126 @@ impl Server {
         self.inner.lock().unwrap().known_nodes.remove(addr);
     }
 
-    fn mine_block(&self, txs: Vec<Transaction>) -> Result<Block> {
-        self.inner.lock().unwrap().utxo.blockchain.mine_block(txs)
+    // A transaction enters the mempool when it could go in the next block.
+    fn check_mempool_tx(&self, tx: &Transaction) -> Result<()> {
+        let inner = self.inner.lock().unwrap();
+        let blockchain = &inner.utxo.blockchain;
+        blockchain.verify_transaction(tx)?;
+        let height = blockchain.get_best_height()? + 1;
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        inner.utxo.check_tx_locks(tx, height, time)
     }
 
-    fn utxo_reindex(&self) -> Result<()> {
-        self.inner.lock().unwrap().utxo.reindex()
+    // Transactions which can never be mined on this chain are evicted from the mempool.
+    fn get_block_template(&self) -> Result<BlockTemplate> {
+        let mut inner = self.inner.lock().unwrap();
+        let candidates = inner.mempool.values().cloned().collect();
+        let (template, invalid) = inner.utxo.get_block_template(candidates)?;
+        for tx in invalid {
+            inner.mempool.remove(&tx.wtxid()?);
+        }
+        Ok(template)
     }
 
-    fn verify_tx(&self, tx: &Transaction) -> Result<bool> {
-        self.inner
-            .lock()
-            .unwrap()
-            .utxo
-            .blockchain.verify_transaction(tx)
+    fn submit_block(&self, block: Block) -> Result<()> {
+        self.inner.lock().unwrap().connect(&block)
+    }
+}
+
+impl ServerInner {
+    // Validate a block on top of the tip and connect it, then drop from the mempool
+    // its transactions and the ones spending the same outputs.
+    fn connect(&mut self, block: &Block) -> Result<()> {
+        self.utxo.submit_block(block.clone())?;
+        self.utxo.update(block)?;
+        self.update_history();
+        // By outpoint, a copy of a mined transaction with another witness goes as well.
+        let spent: HashSet<(&String, i32)> = block
+            .get_transactions()
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.vin.iter().map(|vin| (&vin.txid, vin.vout)))
+            .collect();
+        self.mempool.retain(|_, tx| !tx.vin.iter().any(|vin| spent.contains(&(&vin.txid, vin.vout))));
+        Ok(())
+    }
+
+    // Connect the orphans building on the tip. When orphans build a branch from another
+    // stored block, or from the genesis of another chain, which is longer than the chain,
+    // switch to it: the branch is validated from the fork point and the chain is left
+    // as it was if a block is invalid.
+    fn connect_orphans(&mut self) -> Result<()> {
+        self.connect_branch()?;
+
+        let best_height = self.utxo.blockchain.get_best_height()?;
+        let mut fork = None;
+        for prev in self.orphans.keys() {
+            // The genesis block has no previous block.
+            if !prev.is_empty() && !self.utxo.blockchain.has_block(prev)? {
+                continue;
+            }
+            // Follow the branch to its last block.
+            let mut last = &self.orphans[prev];
+            while let Some(next) = self.orphans.get(&last.get_hash()) {
+                last = next;
+            }
+            if last.get_height() > best_height {
+                fork = Some(prev.clone());
+                break;
+            }
+        }
+        let fork = match fork {
+            Some(fork) => fork,
+            None => return Ok(()),
+        };
+
+        info!("switch to a longer branch from {}", fork);
+        let old_tip = self.utxo.blockchain.get_tip();
+        let fork = if fork.is_empty() {
+            let genesis = self.orphans.remove(&fork).unwrap();
+            self.utxo.blockchain.store_genesis(&genesis)?;
+            genesis.get_hash()
+        } else {
+            fork
+        };
+        self.utxo.blockchain.set_tip(&fork)?;
+        self.utxo.reindex()?;
+        if let Err(e) = self.connect_branch() {
+            self.utxo.blockchain.set_tip(&old_tip)?;
+            self.utxo.reindex()?;
+            self.update_history();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // The wallet history follows the blocks connected here and the branch switches.
+    // The wallet file is not kept open, commands run meanwhile need it. When one
+    // has it open, the history catches up at the next block or when it is read.
+    fn update_history(&self) {
+        // No wallet, no history.
+        if std::fs::metadata(wallets_path()).is_err() {
+            return;
+        }
+        if let Err(e) = History::update(&self.utxo.blockchain) {
+            info!("history not updated: {}", e);
+        }
+        close_wallets_db();
+    }
+
+    // Connect orphans one after the other on the tip. An invalid one is dropped,
+    // with the orphans building on it.
+    fn connect_branch(&mut self) -> Result<()> {
+        while let Some(block) = self.orphans.remove(&self.utxo.blockchain.get_tip()) {
+            if let Err(e) = self.connect(&block) {
+                let mut hash = block.get_hash();
+                while let Some(child) = self.orphans.remove(&hash) {
+                    hash = child.get_hash();
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
     }
 }
 
@@ -482,6 +640,11 @@ struct Txmsg {
     transaction: Transaction,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct GetTemplatemsg {
+    addr_from: String,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 struct Versionmsg {
     addr_from: String,
@@ -498,6 +661,8 @@ enum Message {
     GetBlock(GetBlockmsg),
     Inv(Invmsg),
     Block(Blockmsg),
+    GetTemplate(GetTemplatemsg),
+    SubmitBlock(Blockmsg),
 }
 
 
@@ -535,15 +700,245 @@ fn bytes_to_cmd(bytes: &[u8]) -> Result<Message> {
     } else if cmd == "tx".as_bytes() {
         let data: Txmsg = bincode::deserialize(data)?;
         Ok(Message::Tx(data))
+    } else if cmd == "version".as_bytes() {
+        let data: Versionmsg = bincode::deserialize(data)?;
+        Ok(Message::Version(data))
+    } else if cmd == "gettemplate".as_bytes() {
+        let data: GetTemplatemsg = bincode::deserialize(data)?;
+        Ok(Message::GetTemplate(data))
+    } else if cmd == "submitblock".as_bytes() {
+        let data: Blockmsg = bincode::deserialize(data)?;
+        Ok(Message::SubmitBlock(data))
     } else {
         anyhow::bail!("Unknown command in the server")
     }
 }
 
-fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
+// Request/response over a single connection: the caller closes its write side
+// so the node's read_to_end returns, then waits for the reply.
+pub(crate) fn call(addr: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(data)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    Ok(reply)
+}
+
+pub(crate) fn cmd_to_bytes(cmd: &str) -> [u8; CMD_LEN] {
     let mut data = [0; CMD_LEN];
     for (i , d) in cmd.as_bytes().iter().enumerate() {
         data[i] = *d;
     }
     data
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::blockchain::tests::new_chain;
+    use crate::chainparams::TestDataRoot;
+    use crate::block::TARGET_HEXT;
+    use crate::transaction::SUBSIDY;
+    use crate::tx::TXOutput;
+    use crate::utxoset::tests::find_outputs;
+    use crate::wallet::{Wallet, Wallets};
+
+    // A solved block on top of `prev`, its coinbase paying `value`.
+    fn next_block(prev: &Block, value: i32) -> Block {
+        block_paying(prev, value, Wallet::new().get_address())
+    }
+
+    fn block_paying(prev: &Block, value: i32, to: String) -> Block {
+        let template = BlockTemplate {
+            prev_block_hash: prev.get_hash(),
+            height: prev.get_height() + 1,
+            timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis(),
+            target: TARGET_HEXT,
+            transactions: Vec::new(),
+            coinbase_value: value,
+        };
+        let cbtx = Transaction::new_coinbase_with_value(to, String::new(), value, template.height).unwrap();
+        let mut block = Block::from_template(&template, cbtx).unwrap();
+        block.run_proof_if_work().unwrap();
+        block
+    }
+
+    fn coinbase_id(block: &Block) -> String {
+        block.get_transactions()[0].id.clone()
+    }
+
+    // Serve a node on `listener` from the current thread until `done` is set. The threads
+    // start_server spawns for connections would not see the data root of the test.
+    pub fn serve_until(server: &Server, listener: TcpListener, done: &AtomicBool) {
+        listener.set_nonblocking(true).unwrap();
+        while !done.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false).unwrap();
+                    let _ = server.handle_connection(stream);
+                }
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mempool_eviction() {
+        let _root = TestDataRoot::new("mempool_eviction");
+        let wallet = Wallet::new();
+        let utxo_set = new_chain(&wallet, params().coinbase_maturity - 1);
+        let pay = |amount: i32| {
+            let to = TXOutput::new(amount, Wallet::new().get_address()).unwrap();
+            Transaction::new_utxo_with_outputs(&wallet, vec![to], 0, &utxo_set).unwrap()
+        };
+        let spend = pay(3);
+        let double_spend = pay(4);
+        let mut tampered = pay(5);
+        tampered.vout[0].value = 6;
+
+        let server = Server::new("0", "", utxo_set).unwrap();
+        for tx in [spend, double_spend, tampered] {
+            server.insert_mempool(tx).unwrap();
+        }
+        // The invalid transaction goes, the conflicting ones wait for a block.
+        let template = server.get_block_template().unwrap();
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(server.get_mempool().len(), 2);
+
+        let cbtx = Transaction::new_coinbase_with_value(
+            wallet.get_address(),
+            String::new(),
+            template.coinbase_value,
+            template.height,
+        ).unwrap();
+        let mut block = Block::from_template(&template, cbtx).unwrap();
+        block.run_proof_if_work().unwrap();
+        server.submit_block(block).unwrap();
+        assert!(server.get_mempool().is_empty());
+    }
+
+    #[test]
+    fn test_version_message() {
+        let msg = Versionmsg {
+            addr_from: String::from("localhost:3001"),
+            version: VERSION,
+            best_height: 4,
+        };
+        let data = bincode::serialize(&(cmd_to_bytes("version"), msg)).unwrap();
+        match bytes_to_cmd(&data).unwrap() {
+            Message::Version(v) => {
+                assert_eq!(v.addr_from, "localhost:3001");
+                assert_eq!(v.best_height, 4);
+            }
+            _ => panic!("not a version message"),
+        }
+    }
+
+    #[test]
+    fn test_peer_blocks_are_validated() {
+        let _root = TestDataRoot::new("peer_blocks");
+        let utxo_set = new_chain(&Wallet::new(), 0);
+        let genesis = utxo_set.blockchain.get_block(&utxo_set.blockchain.get_tip()).unwrap();
+        let server = Server::new("0", "", utxo_set).unwrap();
+        let receive = |block: &Block| {
+            server.handle_block(Blockmsg { addr_from: String::from("localhost:0"), block: block.clone() }).unwrap()
+        };
+        let tip = || server.inner.lock().unwrap().utxo.blockchain.get_tip();
+        let stored = |block: &Block| server.inner.lock().unwrap().utxo.blockchain.has_block(&block.get_hash()).unwrap();
+
+        // A sync sends the newest blocks first, the invalid one is never stored.
+        let b1 = next_block(&genesis, SUBSIDY);
+        let b2 = next_block(&b1, SUBSIDY);
+        let bad_reward = next_block(&b2, SUBSIDY + 1);
+        receive(&bad_reward);
+        receive(&b2);
+        assert_eq!(tip(), genesis.get_hash());
+        receive(&b1);
+        assert_eq!(tip(), b2.get_hash());
+        assert!(!stored(&bad_reward));
+        assert!(find_outputs(&coinbase_id(&b2)).unwrap().is_some());
+
+        let mut bad_pow = next_block(&b2, SUBSIDY);
+        let mut nonce = 0;
+        while bad_pow.is_solved().unwrap() {
+            nonce += 1;
+            bad_pow.set_nonce(nonce).unwrap();
+        }
+        receive(&bad_pow);
+        assert!(server.inner.lock().unwrap().orphans.is_empty());
+
+        // A longer branch from b1 replaces b2.
+        let c2 = next_block(&b1, SUBSIDY);
+        let c3 = next_block(&c2, SUBSIDY);
+        receive(&c3);
+        receive(&c2);
+        assert_eq!(tip(), c3.get_hash());
+        assert!(find_outputs(&coinbase_id(&b2)).unwrap().is_none());
+        assert!(find_outputs(&coinbase_id(&c2)).unwrap().is_some());
+
+        // A longer branch ending with an invalid block does not.
+        let d2 = next_block(&b1, SUBSIDY);
+        let d3 = next_block(&d2, SUBSIDY);
+        let d4 = next_block(&d3, SUBSIDY + 1);
+        receive(&d4);
+        receive(&d3);
+        receive(&d2);
+        assert_eq!(tip(), c3.get_hash());
+        assert!(!stored(&d4));
+        assert!(find_outputs(&coinbase_id(&c3)).unwrap().is_some());
+        assert!(find_outputs(&coinbase_id(&d3)).unwrap().is_none());
+
+        // A fresh node syncs the chain of its peer, which has another genesis block.
+        let data = String::from(params().genesis_coinbase_data);
+        let cbtx = Transaction::new_coinbase(Wallet::new().get_address(), data, 0).unwrap();
+        let other_genesis = Block::new_genesis_block(cbtx);
+        let mut branch = vec![other_genesis];
+        for _ in 0..4 {
+            branch.push(next_block(branch.last().unwrap(), SUBSIDY));
+        }
+        for block in branch.iter().rev() {
+            receive(block);
+        }
+        assert_eq!(tip(), branch[4].get_hash());
+        assert!(find_outputs(&coinbase_id(&branch[0])).unwrap().is_some());
+        assert!(find_outputs(&coinbase_id(&genesis)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_history_follows_peer_blocks() {
+        let _root = TestDataRoot::new("peer_history");
+        let mut wallets = Wallets::new().unwrap();
+        let address = wallets.create_wallet(0).unwrap();
+        wallets.save_all().unwrap();
+        drop(wallets);
+        close_wallets_db();
+
+        let utxo_set = new_chain(&Wallet::new(), 0);
+        let genesis = utxo_set.blockchain.get_block(&utxo_set.blockchain.get_tip()).unwrap();
+        let server = Server::new("0", "", utxo_set).unwrap();
+        let receive = |block: &Block| {
+            server.handle_block(Blockmsg { addr_from: String::from("localhost:0"), block: block.clone() }).unwrap()
+        };
+        // Read as a command would, while the node does not have the wallet file open.
+        let in_history = |block: &Block| {
+            let found = History::open().unwrap().get_transaction(&coinbase_id(block)).unwrap().is_some();
+            close_wallets_db();
+            found
+        };
+
+        let b1 = block_paying(&genesis, SUBSIDY, address.clone());
+        receive(&b1);
+        assert!(in_history(&b1));
+
+        // A longer branch from the genesis block takes b1 out.
+        let c1 = next_block(&genesis, SUBSIDY);
+        let c2 = block_paying(&c1, SUBSIDY, address);
+        receive(&c2);
+        receive(&c1);
+        assert!(!in_history(&b1));
+        assert!(in_history(&c2));
+    }
+}
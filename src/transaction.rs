@@ -1,100 +1,225 @@
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
-use log::error;
 use std::collections::HashMap;
 use crypto::ed25519;
+use bitcoincash_addr::Address;
 
 use crate::errors::Result;
 use crate::tx::{TXInput, TXOutput};
 use crate::wallet::{Wallet, Wallets};
 use crate::utxoset::UTXOSet;
+use crate::coinselect::CoinSelection;
+use crate::blockchain::Blockchain;
+use crate::script::{
+    Script, SignatureChecker, verify_script, decode_num, Instruction, MAX_DATA_SIZE, OP_CHECKLOCKTIMEVERIFY,
+};
+use crate::util::encode_hex;
+use crate::encoding::{Reader, Writer, TX_VERSION, WITNESS_FLAG, WITNESS_MARKER};
+
+// Block reward paid by the coinbase transaction, fees come on top of it.
+pub const SUBSIDY: i32 = 100;
+
+// Below the threshold lock_time is a block height, otherwise a unix timestamp in seconds.
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+// Input sequence disabling its relative lock time. When every input has it, lock_time is ignored too.
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+// Relative lock time in the input sequence (BIP68 style):
+// the output spent must be at least that many blocks, or 512 seconds units, old.
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+// Sighash type, the last byte of a signature, telling which parts of the transaction it covers.
+// ALL signs every output, NONE none of them, SINGLE only the one at the index of the input.
+pub const SIGHASH_ALL: u8 = 0x01;
+pub const SIGHASH_NONE: u8 = 0x02;
+pub const SIGHASH_SINGLE: u8 = 0x03;
+// Combined with the above: only the input itself is signed, anyone can add more inputs.
+pub const SIGHASH_ANYONECANPAY: u8 = 0x80;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     pub id: String,
     pub vin: Vec<TXInput>,
     pub vout: Vec<TXOutput>,
+    // The transaction can not be mined before this height or time.
+    pub lock_time: u32,
 }
 
 impl Transaction {
-    // Mining
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
+    // Mining, `height` is the height of the block the coinbase goes in.
+    pub fn new_coinbase(to: String, data: String, height: i32) -> Result<Transaction> {
+        Transaction::new_coinbase_with_value(to, data, SUBSIDY, height)
+    }
+
+    // Coinbase paying `value`, which is the subsidy plus the fees of the block.
+    pub fn new_coinbase_with_value(to: String, mut data: String, value: i32, height: i32) -> Result<Transaction> {
         if data == "" {
             data += &format!("Reward to `{}`", to);
         }
+        Transaction::new_coinbase_outputs(height, 0, data, vec![TXOutput::new(value, to)?])
+    }
 
+    // Coinbase splitting the reward between several outputs, e.g. a pool payout.
+    // The script_sig starts with the block height, so that two coinbases never share
+    // a txid, then the extra nonce, which keeps apart blocks built at the same height.
+    pub fn new_coinbase_outputs(height: i32, extra_nonce: u64, data: String, vout: Vec<TXOutput>) -> Result<Transaction> {
         let mut tx = Transaction {
             id: String::new(),
             vin: vec![TXInput {
                 txid: String::new(),
                 vout: -1,
-                signature: Vec::new(),
-                pub_key: Vec::from(data.as_bytes()),
+                script_sig: Script::new()
+                    .push_int(height as i64)
+                    .push_int(extra_nonce as i64)
+                    .push_data(data.as_bytes()),
+                witness: Vec::new(),
+                sequence: SEQUENCE_FINAL,
             }],
-            vout: vec![TXOutput::new(100, to)?],
+            vout,
+            lock_time: 0,
         };
         tx.id = tx.hash()?;
         Ok(tx)
     }
 
-    // from and to are address encoded by bitcoincash_addr::Address
-    // 1. find Wallet using from address
-    // 2. check if to address is valid(Need?)
-    // 3. Using pub_key_hash to find unspend coin through the block chain.
-    // 4. if balance is enough, create a transaction.
-    // 5. create TXInput to unlock coin from previous TXOutput
-    // 6. create TXOutput to forward coin to `to` address
-    // 7. return the remaining balance to 'from' address.
-    pub fn new_UTXO(wallet: &Wallet, to: &str, amount: i32, txSet: &UTXOSet) -> Result<Transaction> {
-        let mut vin = Vec::new();
+    // Pay `outputs`, e.g. to addresses, other locking scripts or data, from the coins of `wallet`.
+    // 1. Using pub_key_hash to find unspend coin through the block chain.
+    // 2. if balance is enough, create a transaction.
+    // 3. create TXInput to unlock coin from previous TXOutput
+    // 4. return the remaining balance to the wallet address.
+    // A non zero `lock_time` keeps the transaction out of blocks until then.
+    pub fn new_utxo_with_outputs(
+        wallet: &Wallet,
+        outputs: Vec<TXOutput>,
+        lock_time: u32,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        let address = wallet.get_address();
+        Transaction::new_utxo_with_selection(wallet, outputs, lock_time, &CoinSelection::default(), &address, utxo_set)
+    }
 
-        let mut pub_key_hash = wallet.public_key.clone();
-        Wallet::hash_pub_key(&mut pub_key_hash);
+    // Same as new_utxo_with_outputs, the coins spent are picked by `selection`
+    // and the change goes to `change_to`, e.g. a change address of the wallet.
+    pub fn new_utxo_with_selection(
+        wallet: &Wallet,
+        outputs: Vec<TXOutput>,
+        lock_time: u32,
+        selection: &CoinSelection,
+        change_to: &str,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        let from = wallet.get_address();
+        let mut tx = Transaction::new_unsigned_with_selection(&from, outputs, lock_time, selection, change_to, utxo_set)?;
+        utxo_set.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
+        Ok(tx)
+    }
 
-        let acc_v = txSet.find_spendable_outputs(&pub_key_hash, amount)?;
+    // The unsigned transaction paying `outputs` from the coins of the `from` address,
+    // which needs no key, e.g. for a Psbt signed elsewhere. The change goes back to `from`.
+    pub fn new_unsigned(
+        from: &str,
+        outputs: Vec<TXOutput>,
+        lock_time: u32,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        Transaction::new_unsigned_with_selection(from, outputs, lock_time, &CoinSelection::default(), from, utxo_set)
+    }
 
-        if acc_v.0 < amount {
-            error!("Not enough balance");
-            anyhow::bail!("Not enough blance: current balance {}", acc_v.0)
-        }
+    // Same as new_unsigned, the coins spent are picked by `selection` and the change goes to `change_to`.
+    pub fn new_unsigned_with_selection(
+        from: &str,
+        outputs: Vec<TXOutput>,
+        lock_time: u32,
+        selection: &CoinSelection,
+        change_to: &str,
+        utxo_set: &UTXOSet,
+    ) -> Result<Transaction> {
+        let pub_key_hash = match Address::decode(from) {
+            Ok(address) => address.body,
+            Err(_) => anyhow::bail!("ERROR: Invalid address `{}`", from),
+        };
 
-        for tx in acc_v.1 {
-            for out in tx.1 {
-                let input = TXInput {
-                    txid: tx.0.clone(),
-                    vout: out,
-                    signature: Vec::new(),
-                    pub_key: wallet.public_key.clone(),
-                };
-                vin.push(input);
+        let selected = selection.select(utxo_set.find_coins(&pub_key_hash)?, &outputs)?;
+
+        let mut vin = Vec::new();
+        for coin in selected.coins {
+            let mut input = TXInput::new(coin.txid, coin.vout);
+            if lock_time != 0 {
+                // Final inputs would disable lock_time.
+                input.sequence = SEQUENCE_FINAL - 1;
             }
+            vin.push(input);
         }
 
-        let mut vout = vec![TXOutput::new(amount, String::from(to))?];
+        let mut vout = outputs;
 
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(acc_v.0 - amount, wallet.get_address())?)
+        if selected.change > 0 {
+            vout.push(TXOutput::new(selected.change, change_to.to_string())?)
         }
 
         let mut tx = Transaction {
             id: String::new(),
             vin,
             vout,
+            lock_time,
+        };
+        tx.id = tx.hash()?;
+        Ok(tx)
+    }
+
+    // Spend a vesting output (Script::new_cltv_p2pkh or new_csv_p2pkh) of `wallet`
+    // back to its address. The transaction carries the lock the script checks.
+    pub fn new_vesting_claim(wallet: &Wallet, txid: &str, vout: i32, bc: &Blockchain) -> Result<Transaction> {
+        let prev_tx = bc.find_transaction(txid)?;
+        let prev_out = match prev_tx.vout.get(vout as usize) {
+            Some(out) => out,
+            None => anyhow::bail!("ERROR: Output {}:{} does not exist", txid, vout),
+        };
+        let mut input = TXInput::new(txid.to_string(), vout);
+        let mut lock_time = 0;
+        match prev_out.script_pub_key.locked_p2pkh() {
+            Some((OP_CHECKLOCKTIMEVERIFY, lock, _)) => {
+                lock_time = lock;
+                input.sequence = SEQUENCE_FINAL - 1;
+            }
+            Some((_, lock, _)) => input.sequence = lock,
+            None => anyhow::bail!("ERROR: Output {}:{} is not a vesting output", txid, vout),
+        }
+
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![input],
+            vout: vec![TXOutput::new(prev_out.value, wallet.get_address())?],
+            lock_time,
         };
         tx.id = tx.hash()?;
-        txSet.blockchain.sign_transaction(&mut tx, &wallet.secret_key)?;
+        bc.sign_transaction(&mut tx, &wallet.secret_key)?;
         Ok(tx)
     }
 
     // Create/Copy the transaction with signature set.
     // You need to understand what need to include in the signature.
-    // sign a transaction need pub_key_hash from previous Transaction.
+    // sign a transaction need the locking script from previous Transaction.
     // And, only Blockchain struct has method to access whole chain.
     // So, instead of calling this method directly, calling bc.sign_transaction.
+    // Only P2PKH outputs, possibly time locked, can be signed this way,
+    // the unlocking script is <signature> <pub_key>.
     pub fn sign(
         &mut self,
         private_key: &[u8],
         prev_TXs: HashMap<String, Transaction>,
+    ) -> Result<()> {
+        self.sign_with_type(private_key, prev_TXs, SIGHASH_ALL)
+    }
+
+    // Same as sign, with the sighash type `hash_type` for every input.
+    pub fn sign_with_type(
+        &mut self,
+        private_key: &[u8],
+        prev_TXs: HashMap<String, Transaction>,
+        hash_type: u8,
     ) -> Result<()> {
         if self.is_coinbase() {
             return Ok(());
@@ -107,28 +232,94 @@ impl Transaction {
             }
         }
 
-        // Make a copy of current transaction.
-        // Use only for hash calculation.
-        let mut tx_copy = self.trim_copy();
+        check_secret_key(private_key)?;
+        // ed25519 secret key is seed || public key.
+        let pub_key = &private_key[32..64];
+
+        for idx in 0..self.vin.len() {
+            let prev_tx = prev_TXs.get(&self.vin[idx].txid).unwrap();
+            let script_pub_key = &prev_tx.vout[self.vin[idx].vout as usize].script_pub_key;
+            if script_pub_key.p2pkh_hash().is_none() && script_pub_key.locked_p2pkh().is_none() {
+                anyhow::bail!("ERROR: Input {} does not spend a P2PKH output", idx)
+            }
 
-        for idx in 0..tx_copy.vin.len() {
-            let pre_Tx = prev_TXs.get(&tx_copy.vin[idx].txid).unwrap();
-            tx_copy.vin[idx].signature.clear();
-            //Copy corresponding output.
-            tx_copy.vin[idx].pub_key = pre_Tx.vout[tx_copy.vin[idx].vout as usize]
-                .pub_key_hash
-                .clone();
-            
-            tx_copy.id = tx_copy.hash()?;
-            //Why reset?
-            tx_copy.vin[idx].pub_key = Vec::new();
-            let signature = ed25519::signature(tx_copy.id.as_bytes(), private_key);
-            self.vin[idx].signature = signature.to_vec()
+            let signature = self.sign_input_with_type(idx, private_key, script_pub_key, hash_type)?;
+            self.vin[idx].witness = vec![signature, pub_key.to_vec()];
         }
+        self.id = self.hash()?;
 
         Ok(())
     }
 
+    // Signature of input `idx` covering the whole transaction, `script_code` is the
+    // locking script it spends (the redeem script for P2SH).
+    pub fn sign_input(&self, idx: usize, private_key: &[u8], script_code: &Script) -> Result<Vec<u8>> {
+        self.sign_input_with_type(idx, private_key, script_code, SIGHASH_ALL)
+    }
+
+    // The ed25519 signature followed by the sighash type.
+    pub fn sign_input_with_type(
+        &self,
+        idx: usize,
+        private_key: &[u8],
+        script_code: &Script,
+        hash_type: u8,
+    ) -> Result<Vec<u8>> {
+        check_secret_key(private_key)?;
+        let sighash = self.signature_hash(idx, script_code, hash_type)?;
+        let mut signature = ed25519::signature(sighash.as_bytes(), private_key).to_vec();
+        signature.push(hash_type);
+        Ok(signature)
+    }
+
+    // Whether `signature`, with its sighash type, signs input `idx` for `pub_key`.
+    pub fn check_signature(&self, idx: usize, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool {
+        if signature.len() != 65 || pub_key.len() != 32 {
+            return false;
+        }
+        match self.signature_hash(idx, script_code, signature[64]) {
+            Ok(sighash) => ed25519::verify(sighash.as_bytes(), pub_key, &signature[..64]),
+            Err(_) => false,
+        }
+    }
+
+    // The data signed for input `idx`: the transaction with every unlocking script
+    // removed, and the locking script being spent in place of the one of input `idx`.
+    // `hash_type` leaves out what others may still change, and is signed as well.
+    pub fn signature_hash(&self, idx: usize, script_code: &Script, hash_type: u8) -> Result<String> {
+        if idx >= self.vin.len() {
+            anyhow::bail!("ERROR: Input {} does not exist", idx)
+        }
+        let mut tx_copy = self.trim_copy();
+        tx_copy.vin[idx].script_sig = script_code.clone();
+
+        match hash_type & !SIGHASH_ANYONECANPAY {
+            SIGHASH_ALL => {}
+            SIGHASH_NONE => {
+                tx_copy.vout.clear();
+                set_other_sequences(&mut tx_copy.vin, idx);
+            }
+            SIGHASH_SINGLE => {
+                if idx >= tx_copy.vout.len() {
+                    anyhow::bail!("ERROR: SIGHASH_SINGLE input {} has no matching output", idx)
+                }
+                tx_copy.vout.truncate(idx + 1);
+                for out in &mut tx_copy.vout[..idx] {
+                    *out = TXOutput { value: -1, script_pub_key: Script::new() };
+                }
+                set_other_sequences(&mut tx_copy.vin, idx);
+            }
+            _ => anyhow::bail!("ERROR: Unknown sighash type {:#04x}", hash_type),
+        }
+        if hash_type & SIGHASH_ANYONECANPAY != 0 {
+            tx_copy.vin = vec![tx_copy.vin[idx].clone()];
+        }
+
+        let mut data = tx_copy.serialize()?;
+        data.push(hash_type);
+        Ok(sha256_hex(&data))
+    }
+
     fn trim_copy(&self) -> Transaction {
         let mut vin = Vec::new();
         let mut vout = Vec::new();
@@ -137,15 +328,16 @@ impl Transaction {
             vin.push(TXInput {
                 txid: i.txid.clone(),
                 vout: i.vout,
-                signature: Vec::new(),
-                pub_key: Vec::new(),
+                script_sig: Script::new(),
+                witness: Vec::new(),
+                sequence: i.sequence,
             })
         }
 
         for v in &self.vout {
             vout.push(TXOutput {
                 value: v.value,
-                pub_key_hash: v.pub_key_hash.clone(),
+                script_pub_key: v.script_pub_key.clone(),
             })
         }
 
@@ -153,51 +345,190 @@ impl Transaction {
             id: self.id.clone(),
             vin,
             vout,
+            lock_time: self.lock_time,
         }
     }
 
-    pub fn verify(
-        &self, 
-        prev_TXs: HashMap<String, Transaction>
-    ) -> Result<bool> {
+    // Run the unlocking script of every input against the locking script it spends.
+    // The error tells why the transaction is invalid.
+    pub fn verify(&self, prev_txs: &HashMap<String, Transaction>) -> Result<()> {
+        for (idx, out) in self.vout.iter().enumerate() {
+            if !out.script_pub_key.is_unspendable() {
+                continue;
+            }
+            match out.script_pub_key.data() {
+                Some(data) if data.len() <= MAX_DATA_SIZE => {}
+                _ => anyhow::bail!(
+                    "ERROR: Output {} of transaction {}: data output must carry at most {} bytes",
+                    idx, self.id, MAX_DATA_SIZE
+                ),
+            }
+        }
         if self.is_coinbase() {
-            return Ok(true);
+            return Ok(());
+        }
+
+        for (idx, vin) in self.vin.iter().enumerate() {
+            let prev_tx = match prev_txs.get(&vin.txid) {
+                Some(tx) if !tx.id.is_empty() => tx,
+                _ => anyhow::bail!(
+                    "ERROR: Input {} of transaction {}: previous transaction {} is not found",
+                    idx, self.id, vin.txid
+                ),
+            };
+            let prev_out = match prev_tx.vout.get(vin.vout as usize) {
+                Some(out) => out,
+                None => anyhow::bail!(
+                    "ERROR: Input {} of transaction {}: output {}:{} does not exist",
+                    idx, self.id, vin.txid, vin.vout
+                ),
+            };
+
+            // The script would fail anyway, but tell clearly that someone else's coin is spent.
+            if let Some(locked_to) = prev_out.script_pub_key.p2pkh_hash() {
+                let signed_by = vin.pub_key_hash().unwrap_or_default();
+                if signed_by != locked_to {
+                    anyhow::bail!(
+                        "ERROR: Input {} of transaction {}: output {}:{} is locked to pub_key_hash {} but the spending key hashes to {}",
+                        idx, self.id, vin.txid, vin.vout, encode_hex(locked_to), encode_hex(&signed_by)
+                    )
+                }
+            }
+
+            // Unlocking data goes in one place only, or the txid would still commit to part of it.
+            if !vin.witness.is_empty() && !vin.script_sig.0.is_empty() {
+                anyhow::bail!("ERROR: Input {} of transaction {}: has both a witness and a script_sig", idx, self.id)
+            }
+            let checker = TransactionSignatureChecker { tx: self, input: idx };
+            if let Err(e) = verify_script(&vin.unlocking_script(), &prev_out.script_pub_key, &checker) {
+                anyhow::bail!("ERROR: Input {} of transaction {}: {}", idx, self.id, e)
+            }
         }
+        Ok(())
+    }
 
+    // The txid: SHA-256 of the canonical encoding without the witnesses, see encoding.rs.
+    pub fn hash(&self) -> Result<String> {
+        let mut writer = Writer::new();
+        self.write_with(&mut writer, false)?;
+        Ok(sha256_hex(&writer.into_bytes()))
+    }
+
+    // Same as the txid, with the witnesses. Transactions are relayed by it, since
+    // two copies with different witnesses share their txid.
+    pub fn wtxid(&self) -> Result<String> {
+        Ok(sha256_hex(&self.serialize()?))
+    }
+
+    pub fn has_witness(&self) -> bool {
+        self.vin.iter().any(|vin| !vin.witness.is_empty())
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut writer = Writer::new();
+        self.write(&mut writer)?;
+        Ok(writer.into_bytes())
+    }
+
+    pub fn write(&self, writer: &mut Writer) -> Result<()> {
+        self.write_with(writer, true)
+    }
+
+    fn write_with(&self, writer: &mut Writer, with_witness: bool) -> Result<()> {
+        let with_witness = with_witness && self.has_witness();
+        writer.u32(TX_VERSION);
+        if with_witness {
+            writer.bytes(&[WITNESS_MARKER, WITNESS_FLAG]);
+        }
+        writer.var_int(self.vin.len() as u64);
         for vin in &self.vin {
-            if prev_TXs.get(&vin.txid).unwrap().id.is_empty() {
-                anyhow::bail!("ERROR: Previous transaction is not correct")
+            writer.hash(&vin.txid)?;
+            writer.u32(vin.vout as u32);
+            writer.var_bytes(&vin.script_sig.0);
+            writer.u32(vin.sequence);
+        }
+        writer.var_int(self.vout.len() as u64);
+        for out in &self.vout {
+            writer.i64(out.value as i64);
+            writer.var_bytes(&out.script_pub_key.0);
+        }
+        if with_witness {
+            for vin in &self.vin {
+                writer.var_int(vin.witness.len() as u64);
+                for item in &vin.witness {
+                    writer.var_bytes(item);
+                }
             }
         }
-        
-        let mut tx_copy = self.trim_copy();
+        writer.u32(self.lock_time);
+        Ok(())
+    }
 
-        for idx in 0..self.vin.len() {
-            let prev_Tx = prev_TXs.get(&self.vin[idx].txid).unwrap();
-            tx_copy.vin[idx].signature.clear();
-            tx_copy.vin[idx].pub_key = prev_Tx.vout[self.vin[idx].vout as usize]
-                .pub_key_hash
-                .clone();
-            tx_copy.id = tx_copy.hash()?;
-            tx_copy.vin[idx].pub_key = Vec::new();
-
-            if !ed25519::verify(
-                &tx_copy.id.as_bytes(),
-                &self.vin[idx].pub_key,
-                &self.vin[idx].signature
-            ) {
-                return Ok(false);
+    pub fn read(reader: &mut Reader) -> Result<Transaction> {
+        let version = reader.u32()?;
+        if version != TX_VERSION {
+            anyhow::bail!("ERROR: Unknown transaction version {}", version)
+        }
+        let with_witness = reader.peek() == Some(WITNESS_MARKER);
+        if with_witness && reader.take(2)? != [WITNESS_MARKER, WITNESS_FLAG] {
+            anyhow::bail!("ERROR: Unknown transaction flag")
+        }
+        let mut vin = Vec::new();
+        for _ in 0..reader.var_int()? {
+            vin.push(TXInput {
+                txid: reader.hash()?,
+                vout: reader.u32()? as i32,
+                script_sig: Script(reader.var_bytes()?),
+                witness: Vec::new(),
+                sequence: reader.u32()?,
+            });
+        }
+        let mut vout = Vec::new();
+        for _ in 0..reader.var_int()? {
+            let value = reader.i64()?;
+            if value < 0 || value > i32::MAX as i64 {
+                anyhow::bail!("ERROR: Invalid output value {}", value)
+            }
+            vout.push(TXOutput {
+                value: value as i32,
+                script_pub_key: Script(reader.var_bytes()?),
+            });
+        }
+        if with_witness {
+            for input in &mut vin {
+                for _ in 0..reader.var_int()? {
+                    input.witness.push(reader.var_bytes()?);
+                }
             }
         }
-        Ok(true)
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout,
+            lock_time: reader.u32()?,
+        };
+        // Without any witness the flag must be left out, there is one encoding only.
+        if with_witness && !tx.has_witness() {
+            anyhow::bail!("ERROR: Transaction flagged with witnesses has none")
+        }
+        tx.id = tx.hash()?;
+        Ok(tx)
     }
 
-    // hash entire transaction?
-    pub fn hash(&self) -> Result<String> {
-        let data = bincode::serialize(self)?;
-        let mut hasher = Sha256::new();
-        hasher.update(&data[..]);
-        Ok(format!("{:X}", hasher.finalize()))
+    // Whether lock_time allows the transaction in a block at `height` and `time` (seconds).
+    pub fn is_final(&self, height: i32, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let now = if self.lock_time < LOCKTIME_THRESHOLD {
+            height as u64
+        } else {
+            time
+        };
+        if (self.lock_time as u64) < now {
+            return true;
+        }
+        self.vin.iter().all(|vin| vin.sequence == SEQUENCE_FINAL)
     }
 
     // If a transaction has only one vin, and its txid is empty and vout == -1,
@@ -205,4 +536,427 @@ impl Transaction {
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
-}
\ No newline at end of file
+
+    // Block height the coinbase commits to, the first push of its script_sig.
+    pub fn coinbase_height(&self) -> Option<i32> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        match self.vin[0].script_sig.instructions().ok()?.first() {
+            Some(Instruction::Push(item)) => match decode_num(item, 4) {
+                Ok(height) if height >= 0 => Some(height as i32),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+// The secret key of a locked wallet is not loaded.
+fn check_secret_key(private_key: &[u8]) -> Result<()> {
+    if private_key.len() != 64 {
+        anyhow::bail!("ERROR: Wallet is locked, run unlock first")
+    }
+    Ok(())
+}
+
+// Upper case hex, the format of txids.
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:X}", hasher.finalize())
+}
+
+// Without SIGHASH_ALL the other inputs may be replaced, so their sequence is not signed.
+fn set_other_sequences(vin: &mut [TXInput], idx: usize) {
+    for (i, input) in vin.iter_mut().enumerate() {
+        if i != idx {
+            input.sequence = 0;
+        }
+    }
+}
+
+// Checks signatures against the signature hash of one input of a transaction.
+struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    input: usize,
+}
+
+impl SignatureChecker for TransactionSignatureChecker<'_> {
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8], script_code: &Script) -> bool {
+        self.tx.check_signature(self.input, signature, pub_key, script_code)
+    }
+
+    // OP_CHECKLOCKTIMEVERIFY: the transaction lock_time must be at least `lock_time`,
+    // both heights or both timestamps. Block validation then makes sure it has passed.
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let tx_lock_time = self.tx.lock_time as i64;
+        let threshold = LOCKTIME_THRESHOLD as i64;
+        if (lock_time < threshold) != (tx_lock_time < threshold) {
+            return false;
+        }
+        if lock_time > tx_lock_time {
+            return false;
+        }
+        // A final input would disable lock_time.
+        self.tx.vin[self.input].sequence != SEQUENCE_FINAL
+    }
+
+    // OP_CHECKSEQUENCEVERIFY: the input relative lock must be at least `sequence`,
+    // both in blocks or both in time.
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.vin[self.input].sequence as i64;
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG as i64 != 0 {
+            return false;
+        }
+        let mask = (SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK) as i64;
+        let (sequence, tx_sequence) = (sequence & mask, tx_sequence & mask);
+        let type_flag = SEQUENCE_LOCKTIME_TYPE_FLAG as i64;
+        if (sequence & type_flag) != (tx_sequence & type_flag) {
+            return false;
+        }
+        sequence <= tx_sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincash_addr::Address;
+
+    fn coinbase_to(wallet: &Wallet) -> Transaction {
+        Transaction::new_coinbase(wallet.get_address(), String::from("test"), 1).unwrap()
+    }
+
+    // Unsigned transaction moving the first output of `prev` to `to`.
+    fn spend(prev: &Transaction, to: &Wallet) -> Transaction {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin: vec![TXInput::new(prev.id.clone(), 0)],
+            vout: vec![TXOutput::new(prev.vout[0].value, to.get_address()).unwrap()],
+            lock_time: 0,
+        };
+        tx.id = tx.hash().unwrap();
+        tx
+    }
+
+    fn prev_txs(prev: &Transaction) -> HashMap<String, Transaction> {
+        HashMap::from([(prev.id.clone(), prev.clone())])
+    }
+
+    #[test]
+    fn test_owner_can_spend() {
+        let owner = Wallet::new();
+        let prev = coinbase_to(&owner);
+        let mut tx = spend(&prev, &Wallet::new());
+
+        tx.sign(&owner.secret_key, prev_txs(&prev)).unwrap();
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+    }
+
+    #[test]
+    fn test_spend_others_coin_with_own_key() {
+        let victim = Wallet::new();
+        let attacker = Wallet::new();
+        let prev = coinbase_to(&victim);
+        let mut tx = spend(&prev, &attacker);
+
+        // The signature itself is valid for the attacker's key.
+        tx.sign(&attacker.secret_key, prev_txs(&prev)).unwrap();
+        let err = tx.verify(&prev_txs(&prev)).unwrap_err().to_string();
+        assert!(err.contains("is locked to pub_key_hash"), "{}", err);
+    }
+
+    #[test]
+    fn test_spend_others_coin_with_their_pub_key() {
+        let victim = Wallet::new();
+        let attacker = Wallet::new();
+        let prev = coinbase_to(&victim);
+        let mut tx = spend(&prev, &attacker);
+
+        // Key hash matches, but the signature is made by the attacker.
+        tx.sign(&attacker.secret_key, prev_txs(&prev)).unwrap();
+        let signature = tx.sign_input(0, &attacker.secret_key, &prev.vout[0].script_pub_key).unwrap();
+        tx.vin[0].witness = vec![signature, victim.public_key.clone()];
+
+        let err = tx.verify(&prev_txs(&prev)).unwrap_err().to_string();
+        assert!(err.contains("false result"), "{}", err);
+    }
+
+    #[test]
+    fn test_short_key_is_rejected() {
+        let owner = Wallet::new();
+        let prev = coinbase_to(&owner);
+        let mut tx = spend(&prev, &Wallet::new());
+        assert!(tx.sign(&owner.secret_key[..32], prev_txs(&prev)).is_err());
+    }
+
+    #[test]
+    fn test_tampered_output_is_rejected() {
+        let owner = Wallet::new();
+        let attacker = Wallet::new();
+        let prev = coinbase_to(&owner);
+        let mut tx = spend(&prev, &Wallet::new());
+        tx.sign(&owner.secret_key, prev_txs(&prev)).unwrap();
+
+        // Redirect the signed payment to the attacker.
+        tx.vout[0] = TXOutput::new(tx.vout[0].value, attacker.get_address()).unwrap();
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+    }
+
+    #[test]
+    fn test_is_final() {
+        let mut tx = spend(&coinbase_to(&Wallet::new()), &Wallet::new());
+        assert!(tx.is_final(0, 0));
+
+        tx.lock_time = 10;
+        tx.vin[0].sequence = SEQUENCE_FINAL - 1;
+        assert!(!tx.is_final(10, 0));
+        assert!(tx.is_final(11, 0));
+
+        tx.lock_time = 1_700_000_000;
+        assert!(!tx.is_final(1_000_000, 1_600_000_000));
+        assert!(tx.is_final(0, 1_700_000_001));
+
+        // Final inputs disable the lock.
+        tx.vin[0].sequence = SEQUENCE_FINAL;
+        assert!(tx.is_final(0, 0));
+    }
+
+    // Coinbase whose output is locked by `script`.
+    fn vesting_coinbase(script: Script) -> Transaction {
+        let mut prev = coinbase_to(&Wallet::new());
+        prev.vout[0].script_pub_key = script;
+        prev.id = prev.hash().unwrap();
+        prev
+    }
+
+    fn sign_vesting(tx: &mut Transaction, owner: &Wallet, prev: &Transaction) {
+        tx.id = tx.hash().unwrap();
+        tx.sign(&owner.secret_key, prev_txs(prev)).unwrap();
+    }
+
+    #[test]
+    fn test_check_lock_time_verify() {
+        let owner = Wallet::new();
+        let pub_key_hash = Address::decode(&owner.get_address()).unwrap().body;
+        let prev = vesting_coinbase(Script::new_cltv_p2pkh(100, &pub_key_hash));
+
+        let mut tx = spend(&prev, &owner);
+        tx.lock_time = 100;
+        tx.vin[0].sequence = SEQUENCE_FINAL - 1;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+
+        // The spending transaction must be locked at least as long.
+        tx.lock_time = 99;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+
+        // A final input would let it be mined before the lock.
+        tx.lock_time = 100;
+        tx.vin[0].sequence = SEQUENCE_FINAL;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+
+        // A timestamp does not satisfy a height.
+        tx.lock_time = LOCKTIME_THRESHOLD + 100;
+        tx.vin[0].sequence = SEQUENCE_FINAL - 1;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+    }
+
+    #[test]
+    fn test_check_sequence_verify() {
+        let owner = Wallet::new();
+        let pub_key_hash = Address::decode(&owner.get_address()).unwrap().body;
+        let prev = vesting_coinbase(Script::new_csv_p2pkh(5, &pub_key_hash));
+
+        let mut tx = spend(&prev, &owner);
+        tx.vin[0].sequence = 5;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+
+        tx.vin[0].sequence = 4;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+
+        // Relative lock in time units instead of blocks.
+        tx.vin[0].sequence = 5 | SEQUENCE_LOCKTIME_TYPE_FLAG;
+        sign_vesting(&mut tx, &owner, &prev);
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+    }
+
+    #[test]
+    fn test_data_output_size_limit() {
+        let owner = Wallet::new();
+        let prev = coinbase_to(&owner);
+        let mut tx = spend(&prev, &Wallet::new());
+        tx.vout.push(TXOutput::new_data(&[7; MAX_DATA_SIZE]).unwrap());
+        tx.sign(&owner.secret_key, prev_txs(&prev)).unwrap();
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+
+        tx.vout[1].script_pub_key = Script::new().push_opcode(crate::script::OP_RETURN).push_data(&[7; MAX_DATA_SIZE + 1]);
+        tx.sign(&owner.secret_key, prev_txs(&prev)).unwrap();
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+    }
+
+    #[test]
+    fn test_coinbase_commits_to_height() {
+        let address = Wallet::new().get_address();
+        let first = Transaction::new_coinbase(address.clone(), String::from("reward!"), 7).unwrap();
+        let second = Transaction::new_coinbase(address.clone(), String::from("reward!"), 8).unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.coinbase_height(), Some(7));
+
+        for height in [0, 16, 17, 1000, 70000] {
+            let tx = Transaction::new_coinbase(address.clone(), String::new(), height).unwrap();
+            assert_eq!(tx.coinbase_height(), Some(height));
+        }
+        assert_eq!(spend(&first, &Wallet::new()).coinbase_height(), None);
+    }
+
+    // Sign input `idx`, spending the first output of `prev`, with `hash_type`.
+    fn sign_with(tx: &mut Transaction, idx: usize, owner: &Wallet, prev: &Transaction, hash_type: u8) {
+        let script_code = &prev.vout[0].script_pub_key;
+        let signature = tx.sign_input_with_type(idx, &owner.secret_key, script_code, hash_type).unwrap();
+        tx.vin[idx].witness = vec![signature, owner.public_key.clone()];
+    }
+
+    #[test]
+    fn test_anyonecanpay_crowdfunding() {
+        let (alice, bob) = (Wallet::new(), Wallet::new());
+        let (prev_a, prev_b) = (coinbase_to(&alice), coinbase_to(&bob));
+        let prev_txs = HashMap::from([(prev_a.id.clone(), prev_a.clone()), (prev_b.id.clone(), prev_b.clone())]);
+
+        // Alice pledges to a fixed output, then Bob adds his own input to it.
+        let mut tx = spend(&prev_a, &Wallet::new());
+        tx.vout[0].value = prev_a.vout[0].value + prev_b.vout[0].value;
+        sign_with(&mut tx, 0, &alice, &prev_a, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        tx.vin.push(TXInput::new(prev_b.id.clone(), 0));
+        tx.id = tx.hash().unwrap();
+        sign_with(&mut tx, 1, &bob, &prev_b, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        assert!(tx.verify(&prev_txs).is_ok());
+
+        // The output is still covered.
+        tx.vout[0].value -= 1;
+        assert!(tx.verify(&prev_txs).is_err());
+        tx.vout[0].value += 1;
+
+        // A plain SIGHASH_ALL signature breaks once an input is added.
+        sign_with(&mut tx, 0, &alice, &prev_a, SIGHASH_ALL);
+        tx.vin.push(TXInput::new(prev_b.id.clone(), 1));
+        assert!(tx.verify(&prev_txs).is_err());
+    }
+
+    #[test]
+    fn test_sighash_single_and_none() {
+        let owner = Wallet::new();
+        let prev = coinbase_to(&owner);
+        let mut tx = spend(&prev, &Wallet::new());
+        sign_with(&mut tx, 0, &owner, &prev, SIGHASH_SINGLE);
+
+        // The counterparty may add a change output, not change the signed one.
+        tx.vout.push(TXOutput::new(5, Wallet::new().get_address()).unwrap());
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+        tx.vout[0].value -= 1;
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+
+        // NONE lets every output change.
+        sign_with(&mut tx, 0, &owner, &prev, SIGHASH_NONE);
+        tx.vout.pop();
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+
+        // Unknown types and SINGLE without a matching output are refused.
+        assert!(tx.sign_input_with_type(0, &owner.secret_key, &prev.vout[0].script_pub_key, 0x04).is_err());
+        tx.vout.clear();
+        assert!(tx.sign_input_with_type(0, &owner.secret_key, &prev.vout[0].script_pub_key, SIGHASH_SINGLE).is_err());
+    }
+
+    // Fixed transactions with their canonical encoding and txid, which must never change.
+    fn golden_txs() -> Vec<Transaction> {
+        let mut spend = Transaction {
+            id: String::new(),
+            vin: vec![TXInput {
+                txid: String::from("00112233445566778899AABBCCDDEEFF00112233445566778899AABBCCDDEEFF"),
+                vout: 1,
+                script_sig: Script(vec![0x01, 0x02]),
+                witness: Vec::new(),
+                sequence: SEQUENCE_FINAL - 1,
+            }],
+            vout: vec![
+                TXOutput { value: 50, script_pub_key: Script::new_p2pkh(&[0x11; 20]) },
+                TXOutput::new_data(b"hello").unwrap(),
+            ],
+            lock_time: 100,
+        };
+        spend.id = spend.hash().unwrap();
+        let coinbase = Transaction::new_coinbase_outputs(
+            300,
+            7,
+            String::from("golden"),
+            vec![TXOutput { value: SUBSIDY, script_pub_key: Script::new_p2pkh(&[0x22; 20]) }],
+        )
+        .unwrap();
+        let mut witness_spend = spend.clone();
+        witness_spend.vin[0].script_sig = Script::new();
+        witness_spend.vin[0].witness = vec![vec![0x01, 0x02], Vec::new()];
+        witness_spend.id = witness_spend.hash().unwrap();
+        vec![spend, coinbase, witness_spend]
+    }
+
+    #[test]
+    fn test_golden_vectors() {
+        let expected = [
+            (
+                "010000000100112233445566778899aabbccddeeff00112233445566778899aabbccddeeff01000000020102feffffff02\
+                 32000000000000001976a914111111111111111111111111111111111111111188ac0000000000000000076a0568656c6c6f\
+                 64000000",
+                "AA6962EBCD27F6E444D1948B7B830F55B1273A503C81F43A364CBA8956C27FCC",
+                "AA6962EBCD27F6E444D1948B7B830F55B1273A503C81F43A364CBA8956C27FCC",
+            ),
+            (
+                "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0b022c015706676f6c\
+                 64656effffffff0164000000000000001976a914222222222222222222222222222222222222222288ac00000000",
+                "CD25EC6846C65A1BDC29B5C2D1090F66D58C43F8DA333C2228DA589E6B0F7D62",
+                "CD25EC6846C65A1BDC29B5C2D1090F66D58C43F8DA333C2228DA589E6B0F7D62",
+            ),
+            (
+                "0100000000010100112233445566778899aabbccddeeff00112233445566778899aabbccddeeff0100000000feffffff0232\
+                 000000000000001976a914111111111111111111111111111111111111111188ac0000000000000000076a0568656c6c6f02\
+                 0201020064000000",
+                "E1FD99E79FE8E6B74E60E883F24C5EB342AF695ACF0B4BDEB25E60F073F82367",
+                "34926196105DDCF437EA5F4A3BBE69B9A68F18E2C887E0A4B3DFDC4759F1E3B4",
+            ),
+        ];
+        for (tx, (encoding, txid, wtxid)) in golden_txs().iter().zip(expected) {
+            let bytes = tx.serialize().unwrap();
+            assert_eq!(encode_hex(&bytes), encoding);
+            assert_eq!(tx.id, txid);
+            assert_eq!(tx.wtxid().unwrap(), wtxid);
+
+            let decoded = Transaction::read(&mut Reader::new(&bytes)).unwrap();
+            assert_eq!(decoded.id, txid);
+            assert_eq!(decoded.serialize().unwrap(), bytes);
+        }
+    }
+
+    #[test]
+    fn test_witness_is_not_in_txid() {
+        let owner = Wallet::new();
+        let prev = coinbase_to(&owner);
+        let mut tx = spend(&prev, &Wallet::new());
+        tx.sign(&owner.secret_key, prev_txs(&prev)).unwrap();
+        let (txid, wtxid) = (tx.id.clone(), tx.wtxid().unwrap());
+
+        // Another valid signature changes the wtxid only.
+        sign_with(&mut tx, 0, &owner, &prev, SIGHASH_ALL | SIGHASH_ANYONECANPAY);
+        assert!(tx.verify(&prev_txs(&prev)).is_ok());
+        assert_eq!(tx.hash().unwrap(), txid);
+        assert_ne!(tx.wtxid().unwrap(), wtxid);
+
+        // Unlocking data in both places is refused.
+        tx.vin[0].script_sig = Script::new().push_data(b"x");
+        assert!(tx.verify(&prev_txs(&prev)).is_err());
+    }
+}
@@ -1,9 +1,13 @@
+use std::collections::BTreeMap;
 use serde::{Serialize, Deserialize};
 use log::debug;
-use bitcoincash_addr::{Address};
+use bitcoincash_addr::{Address, HashType};
 
 use crate::errors::Result;
 use crate::wallet::Wallet;
+use crate::script::{Instruction, Script};
+use crate::transaction::SEQUENCE_FINAL;
+use crate::chainparams::params;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
@@ -13,59 +17,121 @@ pub struct TXInput {
     // Why not using usize?
     pub vout: i32,
 
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>
+    // Unlocking script, e.g. <signature> <pub_key> for P2PKH.
+    // For coinbase, it only pushes arbitrary data.
+    // Left empty by inputs with a witness.
+    pub script_sig: Script,
+    // Items the unlocking script would push, kept out of the txid so that
+    // re-encoding a signature can not change it. See encoding.rs.
+    pub witness: Vec<Vec<u8>>,
+    // SEQUENCE_FINAL unless the input carries a relative lock time.
+    pub sequence: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
     pub value: i32,
-    pub pub_key_hash: Vec<u8>
+    // Locking script, the conditions to spend this output.
+    pub script_pub_key: Script,
 }
 
 impl TXInput {
+    // Unsigned input spending output `vout` of transaction `txid`.
+    pub fn new(txid: String, vout: i32) -> TXInput {
+        TXInput {
+            txid,
+            vout,
+            script_sig: Script::new(),
+            witness: Vec::new(),
+            sequence: SEQUENCE_FINAL,
+        }
+    }
+
+    // What unlocks the output spent: the script_sig, or the pushes of the witness.
+    pub fn unlocking_script(&self) -> Script {
+        if self.witness.is_empty() {
+            self.script_sig.clone()
+        } else {
+            Script::from_witness(&self.witness)
+        }
+    }
+
     // checks whether the address initiated the transaction.
     pub fn can_unlock_output_with(&self, unlocking_data: &[u8]) -> bool {
-        let mut pub_key_hash = self.pub_key.clone();
+        self.pub_key_hash().as_deref() == Some(unlocking_data)
+    }
+
+    // Hash of the key spending a P2PKH output, which is the last push of its unlocking script.
+    pub fn pub_key_hash(&self) -> Option<Vec<u8>> {
+        let mut pub_key_hash = match self.unlocking_script().instructions().ok()?.pop()? {
+            Instruction::Push(pub_key) => pub_key,
+            Instruction::Op(_) => return None,
+        };
         Wallet::hash_pub_key(&mut pub_key_hash);
-        pub_key_hash == unlocking_data
+        Some(pub_key_hash)
     }
-    
+
 }
 
 impl TXOutput {
     // checks if the output can be unlocked with the provided data.
+    // The data is the hash carried by the address: a pub_key_hash or a script hash.
     pub fn can_be_unlock_with(&self, unlocking_data: &[u8]) -> bool {
-        self.pub_key_hash == unlocking_data
+        self.script_pub_key.p2pkh_hash() == Some(unlocking_data)
+            || self.script_pub_key.p2sh_hash() == Some(unlocking_data)
     }
 
-    // Extract pub_key_hash from address and do assignment.
+    // Extract the hash from address and do assignment.
+    // Key address locks to P2PKH, script address locks to P2SH.
     fn lock(&mut self, address: &str) -> Result<()> {
-        let pub_key_hash = Address::decode(address).unwrap().body;
-        debug!("lock: {}", address);
-        self.pub_key_hash = pub_key_hash;
+        let address = Address::decode(address).unwrap();
+        debug!("lock: {:?}", address);
+        self.script_pub_key = match address.hash_type {
+            HashType::Key => Script::new_p2pkh(&address.body),
+            HashType::Script => Script::new_p2sh(&address.body),
+        };
         Ok(())
     }
 
     pub fn new(value: i32, address: String) -> Result<Self> {
         let mut txo = TXOutput {
             value,
-            pub_key_hash: Vec::new(),
+            script_pub_key: Script::new(),
         };
         txo.lock(&address)?;
         Ok(txo)
     }
+
+    // Zero value output carrying `data`, it never enters the UTXO set.
+    pub fn new_data(data: &[u8]) -> Result<Self> {
+        Ok(TXOutput {
+            value: 0,
+            script_pub_key: Script::new_data(data)?,
+        })
+    }
 }
 
+// Unspent outputs of a transaction, by index in its vout.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutputs {
-    pub outputs: Vec<TXOutput>,
+    pub outputs: BTreeMap<i32, TXOutput>,
+    // Coinbase outputs must mature before they are spent.
+    pub coinbase: bool,
+    // Height of the block containing the transaction.
+    pub height: i32,
 }
 
 impl TXOutputs {
-    pub fn new() -> TXOutputs {
+    pub fn new(coinbase: bool, height: i32) -> TXOutputs {
         TXOutputs {
-            outputs: Vec::new(),
+            outputs: BTreeMap::new(),
+            coinbase,
+            height,
         }
     }
+
+    // Whether the outputs can be spent in a block at `height`.
+    pub fn is_mature(&self, height: i32) -> bool {
+        !self.coinbase || height - self.height >= params().coinbase_maturity
+    }
 }
\ No newline at end of file
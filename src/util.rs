@@ -0,0 +1,81 @@
+use sha2::{Sha256, Digest};
+
+use crate::errors::Result;
+
+// Lower case hex, used to show hashes, keys and scripts.
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        anyhow::bail!("ERROR: Invalid hex string `{}`", hex)
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| match hex.get(i..i + 2).map(|b| u8::from_str_radix(b, 16)) {
+            Some(Ok(b)) => Ok(b),
+            _ => anyhow::bail!("ERROR: Invalid hex string `{}`", hex),
+        })
+        .collect()
+}
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Base58 with a 4 byte double SHA-256 checksum, as addresses are written.
+pub fn encode_base58check(data: &[u8]) -> String {
+    let mut bytes = data.to_vec();
+    bytes.extend_from_slice(&Sha256::digest(Sha256::digest(data))[..4]);
+
+    // Base 256 to base 58, most significant digit last.
+    let mut digits: Vec<u8> = Vec::new();
+    for byte in &bytes {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // Leading zero bytes are kept as leading 1s.
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|d| BASE58_ALPHABET[*d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+pub fn decode_base58check(text: &str) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in text.bytes() {
+        let mut carry = match BASE58_ALPHABET.iter().position(|a| *a == c) {
+            Some(value) => value as u32,
+            None => anyhow::bail!("ERROR: Invalid base58 character `{}`", c as char),
+        };
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = text.bytes().take_while(|c| *c == b'1').count();
+    bytes.extend(std::iter::repeat_n(0, zeros));
+    bytes.reverse();
+
+    if bytes.len() < 4 {
+        anyhow::bail!("ERROR: Base58 string `{}` is too short", text)
+    }
+    let (data, checksum) = bytes.split_at(bytes.len() - 4);
+    if Sha256::digest(Sha256::digest(data))[..4] != *checksum {
+        anyhow::bail!("ERROR: Wrong checksum in `{}`", text)
+    }
+    Ok(data.to_vec())
+}
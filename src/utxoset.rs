@@ -1,17 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 use log::info;
+use serde::{Serialize, Deserialize};
 
-use crate::blockchain::Blockchain;
+use crate::blockchain::{check_coinbase_height, Blockchain};
 use crate::errors::Result;
-use crate::block::Block;
-use crate::tx::{TXOutputs};
+use crate::chainparams::{data_dir, params};
+use crate::block::{Block, BlockTemplate, TARGET_HEXT};
+use crate::coinselect::Coin;
+use crate::transaction::{
+    Transaction, SUBSIDY, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG,
+};
+use crate::tx::{TXOutput, TXOutputs};
 
-const UTXOS_PATH: & str = "data/utxos";
+// Tree of the UTXO database indexing data outputs, keys start with the data.
+const DATA_TREE: &str = "data";
+
+// How far in the future a block timestamp may be, in milliseconds.
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
 
 pub struct UTXOSet {
     pub blockchain: Blockchain,
 }
 
+// A data output (Script::new_data) found in the chain.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DataOutput {
+    pub txid: String,
+    pub vout: i32,
+    pub height: i32,
+    pub data: Vec<u8>,
+}
+
 // Cache/Index for the block chain.
 // Key: transactionId, value: unspend TXOutput.
 // UTXOSet only maintain unspend transactions.
@@ -27,10 +48,10 @@ impl UTXOSet {
     // Rebuilds the UTXO set
     pub fn reindex(&self) -> Result<()> {
         // Recreate new DB.
-        if let Err(_) = std::fs::remove_dir_all(UTXOS_PATH) {
+        if std::fs::remove_dir_all(utxos_path()).is_err() {
             info!("UTXOSet does not exists.");
         }
-        let db = sled::open(UTXOS_PATH)?;
+        let db = sled::open(utxos_path())?;
 
         let utxos = self.blockchain.find_UTXO();
 
@@ -38,46 +59,51 @@ impl UTXOSet {
             db.insert(txid.as_bytes(), bincode::serialize(&outs)?)?;
         }
 
+        let data_tree = db.open_tree(DATA_TREE)?;
+        for block in self.blockchain.iter() {
+            index_data(&data_tree, &block)?;
+        }
+
         Ok(())
     }
 
     // Update UTXO set with transactions from the Block
     // The method only make sense when the block is the last block.
     pub fn update(&self, block: & Block) -> Result<()> {
-        let db = sled::open(UTXOS_PATH)?;
+        let db = sled::open(utxos_path())?;
 
         for tx in block.get_transactions() {
             if !tx.is_coinbase() {
                 for vin in & tx.vin {
-                    let mut update_unspend_outputs = TXOutputs::new();
                     // Get unspend output set that persistent in the db.
-                    let outs = bincode::deserialize::<TXOutputs>(& db.get(& vin.txid)?.unwrap())?;
-                    for out_idx in 0..outs.outputs.len() {
-                        if out_idx != vin.vout as usize {
-                            update_unspend_outputs.outputs.push(outs.outputs[out_idx].clone());
-                        }
-                    }
-                    if update_unspend_outputs.outputs.is_empty() {
+                    let mut outs = bincode::deserialize::<TXOutputs>(& db.get(& vin.txid)?.unwrap())?;
+                    outs.outputs.remove(&vin.vout);
+                    if outs.outputs.is_empty() {
                         db.remove(&vin.txid)?;
                     } else {
-                        db.insert(vin.txid.as_bytes(), bincode::serialize(&update_unspend_outputs)?)?;
+                        db.insert(vin.txid.as_bytes(), bincode::serialize(&outs)?)?;
                     }
                 }
             }
 
-            let mut update_unspend_outputs = TXOutputs::new();
-            for out in & tx.vout {
-                update_unspend_outputs.outputs.push(out.clone());
+            let mut update_unspend_outputs = TXOutputs::new(tx.is_coinbase(), block.get_height());
+            for (idx, out) in tx.vout.iter().enumerate() {
+                if !out.script_pub_key.is_unspendable() {
+                    update_unspend_outputs.outputs.insert(idx as i32, out.clone());
+                }
+            }
+            if !update_unspend_outputs.outputs.is_empty() {
+                db.insert(tx.id.as_bytes(), bincode::serialize(&update_unspend_outputs)?)?;
             }
-            db.insert(tx.id.as_bytes(), bincode::serialize(&update_unspend_outputs)?)?;
         }
+        index_data(&db.open_tree(DATA_TREE)?, block)?;
         Ok(())
     }
 
     // return the number of transactions in the UXTO set.
     pub fn count_transactions(& self) -> Result<i32> {
         let mut counter = 0 as i32;
-        let db = sled::open(UTXOS_PATH)?;
+        let db = sled::open(utxos_path())?;
         for kv in db.iter() {
             kv?;
             counter += 1;
@@ -94,20 +120,25 @@ impl UTXOSet {
     ) -> Result<(i32, HashMap<String, Vec<i32>>)> {
         let mut unspend_outputs: HashMap<String, Vec<i32>> = HashMap::new();
         let mut accumulated = 0;
+        let height = self.blockchain.get_best_height()? + 1;
 
-        let db = sled::open(UTXOS_PATH)?;
+        let db = sled::open(utxos_path())?;
         for kv in db.iter() {
             let (k, v) = kv?;
             let txid = String::from_utf8(k.to_vec())?;
             let outs = bincode::deserialize::<TXOutputs>(&v.to_vec())?;
+            // Immature coinbase outputs would make the transaction invalid.
+            if !outs.is_mature(height) {
+                continue;
+            }
 
-            for out_idx in 0..outs.outputs.len() {
-                if outs.outputs[out_idx].can_be_unlock_with(pub_key_hash) && accumulated < amount {
-                    accumulated += outs.outputs[out_idx].value;
+            for (out_idx, out) in &outs.outputs {
+                if out.can_be_unlock_with(pub_key_hash) && accumulated < amount {
+                    accumulated += out.value;
                     match unspend_outputs.get_mut(&txid) {
-                        Some(v) => v.push(out_idx as i32),
+                        Some(v) => v.push(*out_idx),
                         None => {
-                            unspend_outputs.insert(txid.clone(), vec![out_idx as i32]);
+                            unspend_outputs.insert(txid.clone(), vec![*out_idx]);
                         }
                     }
                 }
@@ -116,21 +147,404 @@ impl UTXOSet {
         Ok((accumulated, unspend_outputs))
     }
 
+    // The outputs of `pub_key_hash` a transaction in the next block can spend.
+    pub fn find_coins(&self, pub_key_hash: &[u8]) -> Result<Vec<Coin>> {
+        let mut coins = Vec::new();
+        let height = self.blockchain.get_best_height()? + 1;
+
+        let db = sled::open(utxos_path())?;
+        for kv in db.iter() {
+            let (k, v) = kv?;
+            let txid = String::from_utf8(k.to_vec())?;
+            let outs = bincode::deserialize::<TXOutputs>(&v)?;
+            if !outs.is_mature(height) {
+                continue;
+            }
+            for (out_idx, out) in &outs.outputs {
+                if out.can_be_unlock_with(pub_key_hash) {
+                    coins.push(Coin { txid: txid.clone(), vout: *out_idx, value: out.value, height: outs.height });
+                }
+            }
+        }
+        Ok(coins)
+    }
+
     // find UTXO for a public key hash.
-    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<TXOutputs> {
-        let mut utxos = TXOutputs::new();
-        let db = sled::open(UTXOS_PATH)?;
+    pub fn find_UTXO(&self, pub_key_hash: &[u8]) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
+        let db = sled::open(utxos_path())?;
 
         for kv in db.iter() {
             let (_, v) = kv?;
             let outs = bincode::deserialize::<TXOutputs>(&v.to_vec())?;
 
-            for out in outs.outputs {
+            for out in outs.outputs.into_values() {
                 if out.can_be_unlock_with(pub_key_hash) {
-                    utxos.outputs.push(out.clone());
+                    utxos.push(out);
                 }
             }
         }
         Ok(utxos)
     }
-}
\ No newline at end of file
+
+    // Mine a block of `transactions` on the tip, once their inputs are unspent and unlocked.
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        let height = self.blockchain.get_best_height()? + 1;
+        let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        for tx in &transactions {
+            self.check_tx_locks(tx, height, time)?;
+        }
+        self.blockchain.mine_block(transactions)
+    }
+
+    // Prepare the next block for an external miner.
+    // Candidates usually come from the mempool. Locked ones and the ones spending an
+    // output already spent by a chosen transaction are left out. Those which can never
+    // go in a block on this chain, invalid or spending a spent output, are returned
+    // as well so that the caller can evict them.
+    pub fn get_block_template(&self, candidates: Vec<Transaction>) -> Result<(BlockTemplate, Vec<Transaction>)> {
+        let height = self.blockchain.get_best_height()? + 1;
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_millis();
+        let mut transactions = Vec::new();
+        let mut invalid = Vec::new();
+        let mut spent = HashSet::new();
+        let mut fees = 0;
+
+        for tx in candidates {
+            if tx.is_coinbase() {
+                invalid.push(tx);
+                continue;
+            }
+            if let Err(e) = self.blockchain.verify_transaction(&tx).and_then(|_| self.check_unspent(&tx)) {
+                info!("evict invalid transaction {}: {}", tx.id, e);
+                invalid.push(tx);
+                continue;
+            }
+            let fee = self.blockchain.get_transaction_fee(&tx)?;
+            if fee < 0 {
+                info!("evict transaction {}: outputs exceed inputs", tx.id);
+                invalid.push(tx);
+                continue;
+            }
+            if let Err(e) = self.check_tx_locks(&tx, height, (timestamp / 1000) as u64) {
+                info!("skip locked transaction {}: {}", tx.id, e);
+                continue;
+            }
+            let outpoints: Vec<(String, i32)> = tx.vin.iter().map(|i| (i.txid.clone(), i.vout)).collect();
+            if outpoints.iter().any(|o| spent.contains(o)) {
+                info!("skip conflicting transaction {}", tx.id);
+                continue;
+            }
+            spent.extend(outpoints);
+            fees += fee;
+            transactions.push(tx);
+        }
+
+        Ok((BlockTemplate {
+            prev_block_hash: self.blockchain.get_tip(),
+            height,
+            timestamp,
+            target: TARGET_HEXT,
+            transactions,
+            coinbase_value: SUBSIDY + fees,
+        }, invalid))
+    }
+
+    // Validate a block solved by an external miner and connect it as the new tip.
+    pub fn submit_block(&mut self, block: Block) -> Result<()> {
+        info!("submit block {}", block.get_hash());
+        self.validate_block(&block)?;
+        self.blockchain.connect_block(&block)
+    }
+
+    // Check a block can be connected on top of the current tip.
+    pub fn validate_block(&self, block: &Block) -> Result<()> {
+        let tip = self.blockchain.get_tip();
+        if block.get_prev_block_hash() != tip {
+            anyhow::bail!("ERROR: Block does not extend the current tip")
+        }
+        if block.get_height() != self.blockchain.get_best_height()? + 1 {
+            anyhow::bail!("ERROR: Invalid block height {}", block.get_height())
+        }
+        if !block.is_solved()? {
+            anyhow::bail!("ERROR: Invalid proof of work")
+        }
+        // Lock times by timestamp rely on block timestamps being sane.
+        let prev_block = self.blockchain.get_block(&tip)?;
+        if block.get_timestamp() < prev_block.get_timestamp() {
+            anyhow::bail!("ERROR: Block timestamp is older than the previous block")
+        }
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_millis();
+        if block.get_timestamp() > now + MAX_FUTURE_BLOCK_TIME {
+            anyhow::bail!("ERROR: Block timestamp is too far in the future")
+        }
+
+        let transactions = block.get_transactions();
+        if transactions.is_empty() || !transactions[0].is_coinbase() {
+            anyhow::bail!("ERROR: First transaction of the block must be the coinbase")
+        }
+        check_coinbase_height(&transactions[0], block.get_height())?;
+        self.blockchain.verify_transaction(&transactions[0])?;
+        block.check_witness_commitment()?;
+
+        let mut spent = HashSet::new();
+        let mut fees = 0;
+        for tx in &transactions[1..] {
+            if tx.is_coinbase() {
+                anyhow::bail!("ERROR: Block contains more than one coinbase")
+            }
+            self.blockchain.verify_transaction(tx)?;
+            self.check_tx_locks(tx, block.get_height(), (block.get_timestamp() / 1000) as u64)?;
+            for vin in &tx.vin {
+                if !spent.insert((vin.txid.clone(), vin.vout)) {
+                    anyhow::bail!("ERROR: Output {}:{} spent twice in the block", vin.txid, vin.vout)
+                }
+            }
+            let fee = self.blockchain.get_transaction_fee(tx)?;
+            if fee < 0 {
+                anyhow::bail!("ERROR: Outputs of transaction {} exceed its inputs", tx.id)
+            }
+            fees += fee;
+        }
+
+        let reward: i32 = transactions[0].vout.iter().map(|out| out.value).sum();
+        if reward > SUBSIDY + fees {
+            anyhow::bail!("ERROR: Coinbase pays {} but only {} is allowed", reward, SUBSIDY + fees)
+        }
+        Ok(())
+    }
+
+    // Check the inputs of a transaction spend unspent outputs, their absolute and
+    // relative lock times, and the maturity of the coinbase outputs they spend, allow
+    // it in a block at `height` with timestamp `time` (seconds).
+    pub fn check_tx_locks(&self, tx: &Transaction, height: i32, time: u64) -> Result<()> {
+        if tx.is_coinbase() {
+            return Ok(());
+        }
+        if !tx.is_final(height, time) {
+            anyhow::bail!("ERROR: Transaction {} is locked until {}", tx.id, tx.lock_time)
+        }
+
+        // The UTXO set knows the height of the block which confirmed each spent output.
+        for (idx, (vin, outs)) in tx.vin.iter().zip(self.find_inputs(tx)?).enumerate() {
+            if !outs.is_mature(height) {
+                anyhow::bail!(
+                    "ERROR: Input {} of transaction {} spends an immature coinbase, spendable from height {}",
+                    idx, tx.id, outs.height + params().coinbase_maturity
+                )
+            }
+
+            // Relative lock time.
+            if vin.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+                continue;
+            }
+            let lock = vin.sequence & SEQUENCE_LOCKTIME_MASK;
+            if vin.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+                let prev_time = (self.blockchain.get_block_at_height(outs.height)?.get_timestamp() / 1000) as u64;
+                let unlock_time = prev_time + ((lock as u64) << SEQUENCE_LOCKTIME_GRANULARITY);
+                if time < unlock_time {
+                    anyhow::bail!(
+                        "ERROR: Input {} of transaction {} is locked until time {}",
+                        idx, tx.id, unlock_time
+                    )
+                }
+            } else {
+                let unlock_height = outs.height + lock as i32;
+                if height < unlock_height {
+                    anyhow::bail!(
+                        "ERROR: Input {} of transaction {} is locked until height {}",
+                        idx, tx.id, unlock_height
+                    )
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Every input must spend an output of the UTXO set, which is up to date with the tip.
+    pub fn check_unspent(&self, tx: &Transaction) -> Result<()> {
+        self.find_inputs(tx).map(|_| ())
+    }
+
+    // Unspent outputs of the transactions the inputs of `tx` spend, one per input.
+    fn find_inputs(&self, tx: &Transaction) -> Result<Vec<TXOutputs>> {
+        let db = sled::open(utxos_path())?;
+        let mut inputs = Vec::new();
+        for (idx, vin) in tx.vin.iter().enumerate() {
+            let outs = match db.get(&vin.txid)? {
+                Some(v) => bincode::deserialize::<TXOutputs>(&v)?,
+                None => TXOutputs::new(false, 0),
+            };
+            if !outs.outputs.contains_key(&vin.vout) {
+                anyhow::bail!(
+                    "ERROR: Input {} of transaction {} spends {}:{}, which is not unspent",
+                    idx, tx.id, vin.txid, vin.vout
+                )
+            }
+            inputs.push(outs);
+        }
+        Ok(inputs)
+    }
+
+    // Data outputs whose data starts with `prefix`, e.g. a document hash.
+    pub fn find_data(&self, prefix: &[u8]) -> Result<Vec<DataOutput>> {
+        let db = sled::open(utxos_path())?;
+        let mut found = Vec::new();
+        for kv in db.open_tree(DATA_TREE)?.scan_prefix(prefix) {
+            let (_, v) = kv?;
+            found.push(bincode::deserialize(&v)?);
+        }
+        Ok(found)
+    }
+}
+
+fn utxos_path() -> String {
+    format!("{}/utxos", data_dir())
+}
+
+// Key: data || txid || vout, so that a prefix scan finds the data.
+fn index_data(tree: &sled::Tree, block: &Block) -> Result<()> {
+    for tx in block.get_transactions() {
+        for (idx, out) in tx.vout.iter().enumerate() {
+            if let Some(data) = out.script_pub_key.data() {
+                let mut key = data.clone();
+                key.extend_from_slice(tx.id.as_bytes());
+                key.extend_from_slice(&(idx as i32).to_be_bytes());
+                let entry = DataOutput {
+                    txid: tx.id.clone(),
+                    vout: idx as i32,
+                    height: block.get_height(),
+                    data,
+                };
+                tree.insert(key, bincode::serialize(&entry)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::blockchain::tests::new_chain;
+    use crate::chainparams::TestDataRoot;
+    use crate::script::{Script, MAX_DATA_SIZE, OP_RETURN};
+    use crate::wallet::Wallet;
+
+    // Unspent outputs of a transaction, None when all of them are spent.
+    pub fn find_outputs(txid: &str) -> Result<Option<TXOutputs>> {
+        let db = sled::open(utxos_path())?;
+        match db.get(txid)? {
+            Some(v) => Ok(Some(bincode::deserialize(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn solve(template: &BlockTemplate, coinbase_value: i32) -> Block {
+        let cbtx = Transaction::new_coinbase_with_value(
+            Wallet::new().get_address(),
+            String::new(),
+            coinbase_value,
+            template.height,
+        ).unwrap();
+        let mut block = Block::from_template(template, cbtx).unwrap();
+        block.run_proof_if_work().unwrap();
+        block
+    }
+
+    #[test]
+    fn test_submit_block() {
+        let _root = TestDataRoot::new("submit_block");
+        let wallet = Wallet::new();
+        // Only the genesis coinbase is mature in the next block.
+        let mut utxo_set = new_chain(&wallet, params().coinbase_maturity - 1);
+        let pay = |amount: i32| {
+            let to = TXOutput::new(amount, Wallet::new().get_address()).unwrap();
+            Transaction::new_utxo_with_outputs(&wallet, vec![to], 0, &utxo_set).unwrap()
+        };
+        let spend = pay(3);
+        let double_spend = pay(4);
+        let mut tampered = pay(5);
+        tampered.vout[0].value = 6;
+
+        let (template, invalid) = utxo_set
+            .get_block_template(vec![spend.clone(), double_spend.clone(), tampered.clone()])
+            .unwrap();
+        assert_eq!(template.transactions.len(), 1);
+        assert_eq!(template.coinbase_value, SUBSIDY);
+        // The conflicting transaction may be mined instead of the chosen one, it is not evicted.
+        assert_eq!(invalid.iter().map(|tx| &tx.id).collect::<Vec<_>>(), vec![&tampered.id]);
+        let chosen = template.transactions[0].clone();
+        let other = if chosen.id == spend.id { double_spend } else { spend };
+
+        let bad_reward = solve(&template, template.coinbase_value + 1);
+        assert!(utxo_set.submit_block(bad_reward).is_err());
+
+        // Data outputs of the coinbase are held to the same limit as any other.
+        let mut cbtx = Transaction::new_coinbase(Wallet::new().get_address(), String::new(), template.height).unwrap();
+        cbtx.vout.push(TXOutput {
+            value: 0,
+            script_pub_key: Script::new().push_opcode(OP_RETURN).push_data(&[7; MAX_DATA_SIZE + 1]),
+        });
+        cbtx.id = cbtx.hash().unwrap();
+        let mut big_data = Block::from_template(&template, cbtx).unwrap();
+        big_data.run_proof_if_work().unwrap();
+        assert!(utxo_set.submit_block(big_data).is_err());
+
+        let mut bad_pow = solve(&template, template.coinbase_value);
+        let mut nonce = 0;
+        while bad_pow.is_solved().unwrap() {
+            nonce += 1;
+            bad_pow.set_nonce(nonce).unwrap();
+        }
+        assert!(utxo_set.submit_block(bad_pow).is_err());
+
+        let mut wrong_prev = template.clone();
+        wrong_prev.prev_block_hash = utxo_set.blockchain.iter().nth(1).unwrap().get_hash();
+        assert!(utxo_set.submit_block(solve(&wrong_prev, SUBSIDY)).is_err());
+
+        let mut spent_twice = template.clone();
+        spent_twice.transactions.push(other.clone());
+        assert!(utxo_set.submit_block(solve(&spent_twice, SUBSIDY)).is_err());
+
+        let block = solve(&template, template.coinbase_value);
+        utxo_set.submit_block(block.clone()).unwrap();
+        utxo_set.update(&block).unwrap();
+        assert_eq!(utxo_set.blockchain.get_best_height().unwrap(), template.height);
+
+        // Now the other spend conflicts with the chain.
+        let (next, invalid) = utxo_set.get_block_template(vec![other.clone()]).unwrap();
+        assert!(next.transactions.is_empty());
+        assert_eq!(invalid.len(), 1);
+        let mut spent = next.clone();
+        spent.transactions.push(other);
+        assert!(utxo_set.submit_block(solve(&spent, SUBSIDY)).is_err());
+    }
+
+    #[test]
+    fn test_coinbase_maturity() {
+        let _root = TestDataRoot::new("coinbase_maturity");
+        let wallet = Wallet::new();
+        let maturity = params().coinbase_maturity;
+        let mut pub_key_hash = wallet.public_key.clone();
+        Wallet::hash_pub_key(&mut pub_key_hash);
+        // The next block is at maturity - 1, the genesis coinbase can not be selected yet.
+        let mut utxo_set = new_chain(&wallet, maturity - 2);
+        assert_eq!(utxo_set.find_spendable_outputs(&pub_key_hash, 1).unwrap().0, 0);
+
+        let cbtx = Transaction::new_coinbase(wallet.get_address(), String::new(), maturity - 1).unwrap();
+        let block = utxo_set.blockchain.mine_block(vec![cbtx]).unwrap();
+        utxo_set.update(&block).unwrap();
+        let (found, outputs) = utxo_set.find_spendable_outputs(&pub_key_hash, 1).unwrap();
+        assert_eq!(found, SUBSIDY);
+        let genesis = utxo_set.blockchain.get_block_at_height(0).unwrap();
+        assert_eq!(outputs.keys().collect::<Vec<_>>(), vec![&genesis.get_transactions()[0].id]);
+
+        let to = TXOutput::new(3, Wallet::new().get_address()).unwrap();
+        let tx = Transaction::new_utxo_with_outputs(&wallet, vec![to], 0, &utxo_set).unwrap();
+        assert!(utxo_set.check_tx_locks(&tx, maturity - 1, 0).is_err());
+        assert!(utxo_set.check_tx_locks(&tx, maturity, 0).is_ok());
+    }
+}
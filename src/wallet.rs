@@ -1,17 +1,31 @@
 use crypto::{
-    ed25519,
+    aead::{AeadDecryptor, AeadEncryptor},
+    chacha20poly1305::ChaCha20Poly1305,
     digest::Digest,
     ripemd160::Ripemd160,
-    sha2::Sha256
+    scrypt::{scrypt, ScryptParams},
+    sha2::Sha256,
 };
 use bitcoincash_addr::{Address, HashType, Scheme};
 use rand::RngCore;
 use rand::rngs::OsRng;
 use serde::{Serialize, Deserialize};
 use log::info;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use fs2::FileExt;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
 
+use crate::chainparams::{data_root, params};
 use crate::errors::Result;
+use crate::hd::{KeyPath, GAP_LIMIT};
+use crate::script::Script;
+use crate::tx::TXOutput;
+use crate::util::{decode_base58check, encode_base58check};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Wallet {
@@ -20,10 +34,12 @@ pub struct Wallet {
 }
 
 impl Wallet {
+    // A random key, the keys of the wallet file are derived from its seed (Wallets::create_wallet).
+    #[cfg(test)]
     pub fn new() -> Self {
         let mut key: [u8; 32] = [0; 32];
         OsRng.fill_bytes(&mut key);
-        let (secret_key, public_key) = ed25519::keypair(&key);
+        let (secret_key, public_key) = crypto::ed25519::keypair(&key);
         Wallet {
             secret_key: secret_key.to_vec(),
             public_key: public_key.to_vec(),
@@ -32,12 +48,29 @@ impl Wallet {
 
     // Example of generate address from Wallet
     pub fn get_address(&self) -> String {
-        let mut pub_hash = self.public_key.clone();
+        Self::get_pub_key_address(&self.public_key)
+    }
+
+    // Address of the P2PKH outputs of `pub_key`, which may be another party's key.
+    pub fn get_pub_key_address(pub_key: &[u8]) -> String {
+        let mut pub_hash = pub_key.to_vec();
         Self::hash_pub_key(&mut pub_hash);
 
         let address = Address {
             body: pub_hash,
             scheme: Scheme::Base58,
+            hash_type: HashType::Key,
+            ..Default::default()
+        };
+
+        address.encode().unwrap()
+    }
+
+    // Address of a P2SH output locked to the hash of `redeem_script`.
+    pub fn get_script_address(redeem_script: &Script) -> String {
+        let address = Address {
+            body: redeem_script.hash(),
+            scheme: Scheme::Base58,
             hash_type: HashType::Script,
             ..Default::default()
         };
@@ -45,45 +78,680 @@ impl Wallet {
         address.encode().unwrap()
     }
 
+    // Address an output pays to: P2PKH, possibly time locked, or P2SH.
+    pub fn get_output_address(script_pub_key: &Script) -> Option<String> {
+        let (body, hash_type) = if let Some(hash) = script_pub_key.p2pkh_hash() {
+            (hash.to_vec(), HashType::Key)
+        } else if let Some((_, _, hash)) = script_pub_key.locked_p2pkh() {
+            (hash, HashType::Key)
+        } else if let Some(hash) = script_pub_key.p2sh_hash() {
+            (hash.to_vec(), HashType::Script)
+        } else {
+            return None;
+        };
+        let address = Address {
+            body,
+            scheme: Scheme::Base58,
+            hash_type,
+            ..Default::default()
+        };
+        address.encode().ok()
+    }
+
+    // Base58check of the chain prefix, the 32 byte ed25519 seed and ED25519_KEY_SUFFIX.
+    pub fn export_secret_key(&self) -> Result<String> {
+        if self.is_locked() {
+            anyhow::bail!("ERROR: Wallet is locked, run unlock first")
+        }
+        let mut data = vec![params().secret_key_prefix];
+        data.extend_from_slice(&self.secret_key[..32]);
+        data.push(ED25519_KEY_SUFFIX);
+        Ok(encode_base58check(&data))
+    }
+
+    pub fn import_secret_key(text: &str) -> Result<Wallet> {
+        let data = decode_base58check(text.trim())?;
+        if data.len() != 34 || data[33] != ED25519_KEY_SUFFIX {
+            anyhow::bail!("ERROR: Not an exported secret key")
+        }
+        if data[0] != params().secret_key_prefix {
+            anyhow::bail!("ERROR: Secret key of another chain than {}", params().name)
+        }
+        let (secret_key, public_key) = crypto::ed25519::keypair(&data[1..33]);
+        Ok(Wallet {
+            secret_key: secret_key.to_vec(),
+            public_key: public_key.to_vec(),
+        })
+    }
+
+    // While the wallets are locked, only the public key is loaded.
+    pub fn is_locked(&self) -> bool {
+        self.secret_key.is_empty()
+    }
+
+    // In place, RIPEMD160(SHA256(data)). OP_HASH160 hashes stack items of any size.
     pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
         let mut hasher1 = Sha256::new();
         hasher1.input(pub_key);
-        hasher1.result(pub_key);
+        let mut sha = [0; 32];
+        hasher1.result(&mut sha);
         let mut hasher2 = Ripemd160::new();
-        hasher2.input(pub_key);
+        hasher2.input(&sha);
         pub_key.resize(20, 0);
         hasher2.result(pub_key);
     }
 }
 
+// Ends exported secret keys, like the compressed flag of Bitcoin's WIF.
+const ED25519_KEY_SUFFIX: u8 = 0xed;
+
+pub const DEFAULT_WALLET: &str = "default";
+
+// Database of the default wallet file.
+fn default_wallet_path() -> String {
+    format!("{}/wallets", data_root())
+}
+
+// Named wallet files, each its own database: keys of two wallet files never mix.
+fn wallet_dir() -> String {
+    format!("{}/walletdir", data_root())
+}
+
+// Names of the loaded wallet files, one per line. The default one is always loaded.
+fn loaded_path() -> String {
+    format!("{}/loaded", wallet_dir())
+}
+
+static SELECTED: OnceLock<String> = OnceLock::new();
+
+// Pick the wallet file this process works on, before anything opens it.
+pub fn select(name: &str) -> Result<()> {
+    if !loaded_wallets()?.iter().any(|loaded| loaded == name) {
+        anyhow::bail!("ERROR: Wallet `{}` is not loaded, run load-wallet {} first", name, name)
+    }
+    if SELECTED.set(name.to_string()).is_err() {
+        anyhow::bail!("ERROR: Wallet is already selected")
+    }
+    Ok(())
+}
+
+// Database of the selected wallet file, the default one unless another one was selected.
+pub fn wallets_path() -> String {
+    wallet_path(SELECTED.get_or_init(|| DEFAULT_WALLET.to_string()))
+}
+
+fn wallet_path(name: &str) -> String {
+    if name == DEFAULT_WALLET {
+        default_wallet_path()
+    } else {
+        format!("{}/{}", wallet_dir(), name)
+    }
+}
+
+// The wallet files this process has open. sled locks a database against every other
+// open, from this process too, and lets go of the lock only a moment after the last
+// handle is dropped. So a command opens a wallet file once and shares the handle.
+static OPEN_FILES: Mutex<BTreeMap<String, sled::Db>> = Mutex::new(BTreeMap::new());
+
+fn open_wallet_file(path: &str) -> Result<sled::Db> {
+    let mut open = OPEN_FILES.lock().unwrap();
+    if let Some(db) = open.get(path) {
+        return Ok(db.clone());
+    }
+    let db = sled::open(path)?;
+    open.insert(path.to_string(), db.clone());
+    Ok(db)
+}
+
+// The selected wallet file.
+pub fn wallets_db() -> Result<sled::Db> {
+    open_wallet_file(&wallets_path())
+}
+
+// Lets other processes open the selected wallet file once the handles given out are
+// dropped. For the node, which writes to it now and then but runs for long.
+pub fn close_wallets_db() {
+    OPEN_FILES.lock().unwrap().remove(&wallets_path());
+}
+
+// The key of an unlocked wallet file, until it expires. Each command is its own process,
+// so it has to outlive the `unlock` command: whoever reads the file meanwhile can decrypt
+// the keys. `lock` removes it, expired ones are removed by sweep_sessions.
+fn session_path() -> String {
+    format!("{}.session", wallets_path())
+}
+
+// The session in `path` unless it expired, expired ones are removed.
+fn read_session(path: &str) -> Result<Option<Session>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+    let session: Session = bincode::deserialize(&data)?;
+    if session.expires <= now()? {
+        fs::remove_file(path)?;
+        return Ok(None);
+    }
+    Ok(Some(session))
+}
+
+// Only readable by the owner, a session is as good as the passphrase until it expires.
+#[cfg(unix)]
+fn create_session_file(path: &str) -> Result<fs::File> {
+    Ok(fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?)
+}
+
+// Elsewhere the file would get the default permissions, readable by other users.
+#[cfg(not(unix))]
+fn create_session_file(_path: &str) -> Result<fs::File> {
+    anyhow::bail!("ERROR: unlock keeps the key in a file only its owner can read, which needs unix permissions")
+}
+
+// Remove the expired sessions of every wallet file, run by every command so that
+// a key does not stay on disk until its wallet file is used again.
+pub fn sweep_sessions() -> Result<()> {
+    for (name, _) in list_wallet_files()? {
+        read_session(&format!("{}.session", wallet_path(&name)))?;
+    }
+    Ok(())
+}
+
+// sled keeps removed values in its log files until it reclaims them, so removing a
+// plaintext key does not erase it. Copy what is live into a fresh database instead and
+// delete the old one. Until the last step, one of the two copies is complete on disk.
+fn rewrite_wallet_file(path: &str) -> Result<()> {
+    let fresh = format!("{}.rewrite", path);
+    let old = format!("{}.old", path);
+    let _ = fs::remove_dir_all(&fresh);
+    {
+        let db = open_wallet_file(path)?;
+        let copy = sled::open(&fresh)?;
+        copy.import(db.export());
+        copy.flush()?;
+    }
+    // Handles given out before keep the old copy, until they are dropped.
+    OPEN_FILES.lock().unwrap().remove(path);
+    fs::rename(path, &old)?;
+    fs::rename(&fresh, path)?;
+    fs::remove_dir_all(&old)?;
+    // The fresh copy was only opened here, wait for its handle to let go of the lock.
+    fs::File::open(format!("{}/db", path))?.lock_exclusive()?;
+    Ok(())
+}
+
+// Names stay inside wallet_dir(), where `loaded` is taken by loaded_path().
+fn is_wallet_name(name: &str) -> bool {
+    !name.is_empty() && name != "loaded" && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// A new empty wallet file, loaded.
+pub fn create_wallet_file(name: &str) -> Result<()> {
+    if !is_wallet_name(name) {
+        anyhow::bail!("ERROR: Wallet names are letters, digits, - and _, other than `loaded`, not `{}`", name)
+    }
+    if name == DEFAULT_WALLET || fs::metadata(wallet_path(name)).is_ok() {
+        anyhow::bail!("ERROR: Wallet `{}` already exists", name)
+    }
+    open_wallet_file(&wallet_path(name))?.flush()?;
+    load_wallet_file(name)
+}
+
+pub fn load_wallet_file(name: &str) -> Result<()> {
+    if name == DEFAULT_WALLET || !is_wallet_name(name) || !fs::metadata(wallet_path(name)).is_ok_and(|m| m.is_dir()) {
+        anyhow::bail!("ERROR: No wallet file `{}`", name)
+    }
+    let mut loaded = loaded_wallets()?;
+    if loaded.iter().any(|loaded| loaded == name) {
+        anyhow::bail!("ERROR: Wallet `{}` is already loaded", name)
+    }
+    loaded.push(name.to_string());
+    save_loaded_wallets(&loaded)
+}
+
+// The wallet file can no longer be used, until it is loaded again. It is locked too.
+pub fn unload_wallet_file(name: &str) -> Result<()> {
+    if name == DEFAULT_WALLET {
+        anyhow::bail!("ERROR: The default wallet can not be unloaded")
+    }
+    let mut loaded = loaded_wallets()?;
+    if !loaded.iter().any(|loaded| loaded == name) {
+        anyhow::bail!("ERROR: Wallet `{}` is not loaded", name)
+    }
+    loaded.retain(|loaded| loaded != name);
+    save_loaded_wallets(&loaded)?;
+    let _ = fs::remove_file(format!("{}.session", wallet_path(name)));
+    Ok(())
+}
+
+// Every wallet file, and whether it is loaded.
+pub fn list_wallet_files() -> Result<Vec<(String, bool)>> {
+    let loaded = loaded_wallets()?;
+    let mut files = vec![(DEFAULT_WALLET.to_string(), true)];
+    if let Ok(entries) = fs::read_dir(wallet_dir()) {
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            // Not the copies left by an interrupted rewrite_wallet_file.
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() && is_wallet_name(&name) {
+                names.push(name);
+            }
+        }
+        names.sort();
+        files.extend(names.into_iter().map(|name| {
+            let is_loaded = loaded.contains(&name);
+            (name, is_loaded)
+        }));
+    }
+    Ok(files)
+}
+
+fn loaded_wallets() -> Result<Vec<String>> {
+    let mut loaded = vec![DEFAULT_WALLET.to_string()];
+    if let Ok(text) = fs::read_to_string(loaded_path()) {
+        loaded.extend(text.lines().filter(|line| !line.is_empty()).map(String::from));
+    }
+    Ok(loaded)
+}
+
+fn save_loaded_wallets(loaded: &[String]) -> Result<()> {
+    let names: Vec<&str> = loaded.iter().map(|name| name.as_str()).filter(|name| *name != DEFAULT_WALLET).collect();
+    fs::create_dir_all(wallet_dir())?;
+    fs::write(loaded_path(), names.join("\n"))?;
+    Ok(())
+}
+
+// scrypt cost of new passphrases: N = 2^14, r = 8, p = 1.
+const KDF_LOG_N: u8 = 14;
+const KDF_R: u32 = 8;
+const KDF_P: u32 = 1;
+
+// How the key of an encrypted wallet file is derived from its passphrase.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KdfParams {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    // The empty message sealed under the key, tells a wrong passphrase apart.
+    check: Sealed,
+}
+
+impl KdfParams {
+    fn new(passphrase: &str, log_n: u8, r: u32, p: u32) -> (KdfParams, Vec<u8>) {
+        let mut salt = vec![0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut params = KdfParams { salt, log_n, r, p, check: Sealed::default() };
+        let key = params.derive(passphrase);
+        params.check = seal(&key, b"check", &[]);
+        (params, key)
+    }
+
+    fn derive(&self, passphrase: &str) -> Vec<u8> {
+        let mut key = vec![0; 32];
+        scrypt(passphrase.as_bytes(), &self.salt, &ScryptParams::new(self.log_n, self.r, self.p), &mut key);
+        key
+    }
+
+    // The key if `passphrase` is the right one.
+    fn unlock(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let key = self.derive(passphrase);
+        if open(&key, b"check", &self.check).is_err() {
+            anyhow::bail!("ERROR: Wrong passphrase")
+        }
+        Ok(key)
+    }
+}
+
+// ChaCha20-Poly1305 ciphertext, with the address as associated data
+// so that an entry can not be moved to another address.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Sealed {
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+// Secret key of an encrypted wallet, the public key stays readable.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EncryptedWallet {
+    public_key: Vec<u8>,
+    secret_key: Sealed,
+}
+
+fn seal(key: &[u8], aad: &[u8], plaintext: &[u8]) -> Sealed {
+    let mut nonce = vec![0; 8];
+    OsRng.fill_bytes(&mut nonce);
+    let mut ciphertext = vec![0; plaintext.len()];
+    let mut tag = vec![0; 16];
+    ChaCha20Poly1305::new(key, &nonce, aad).encrypt(plaintext, &mut ciphertext, &mut tag);
+    Sealed { nonce, ciphertext, tag }
+}
+
+fn open(key: &[u8], aad: &[u8], sealed: &Sealed) -> Result<Vec<u8>> {
+    if sealed.nonce.len() != 8 || sealed.tag.len() != 16 {
+        anyhow::bail!("ERROR: Corrupted encrypted wallet")
+    }
+    let mut plaintext = vec![0; sealed.ciphertext.len()];
+    if !ChaCha20Poly1305::new(key, &sealed.nonce, aad).decrypt(&sealed.ciphertext, &mut plaintext, &sealed.tag) {
+        anyhow::bail!("ERROR: Wallet key does not decrypt")
+    }
+    Ok(plaintext)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Session {
+    key: Vec<u8>,
+    // Unix time in seconds.
+    expires: u64,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs())
+}
+
 pub struct Wallets {
     wallets: HashMap<String, Wallet>,
+    // Redeem scripts of the P2SH addresses we take part in, by address.
+    scripts: HashMap<String, Script>,
+    // Set once the wallet file is encrypted.
+    kdf: Option<KdfParams>,
+    // Encryption key while unlocked.
+    key: Option<Vec<u8>>,
+    // Seed of the derived keys, not loaded while locked.
+    seed: Option<Vec<u8>>,
+    // Derivation path of the derived keys, by address.
+    paths: HashMap<String, KeyPath>,
+    // Addresses tracked without their secret key, with the public key when known.
+    watch_only: HashMap<String, Option<Vec<u8>>>,
 }
 
 impl Wallets {
     pub fn new() -> Result<Wallets> {
         let mut wlt = Wallets {
             wallets: HashMap::<String, Wallet>::new(),
+            scripts: HashMap::<String, Script>::new(),
+            kdf: None,
+            key: None,
+            seed: None,
+            paths: HashMap::new(),
+            watch_only: HashMap::new(),
         };
 
-        let db = sled::open("data/wallets")?;
-        for item in db.into_iter() {
+        let db = wallets_db()?;
+        let meta = db.open_tree("meta")?;
+        if let Some(kdf) = meta.get("kdf")? {
+            wlt.kdf = Some(bincode::deserialize(&kdf)?);
+            wlt.key = Self::load_session()?;
+        }
+        if let Some(seed) = meta.get("seed")? {
+            wlt.seed = match (&wlt.kdf, &wlt.key) {
+                (None, _) => Some(bincode::deserialize(&seed)?),
+                (Some(_), Some(key)) => Some(open(key, b"seed", &bincode::deserialize(&seed)?)?),
+                (Some(_), None) => None,
+            };
+        }
+        for item in db.open_tree("paths")?.into_iter() {
             let i = item?;
             let address = String::from_utf8(i.0.to_vec())?;
-            let wallet = bincode::deserialize(&i.1.to_vec())?;
+            wlt.paths.insert(address, KeyPath::from_record(&i.1)?);
+        }
+        for item in db.open_tree("encrypted")?.into_iter() {
+            let i = item?;
+            let address = String::from_utf8(i.0.to_vec())?;
+            let encrypted: EncryptedWallet = bincode::deserialize(&i.1)?;
+            let secret_key = match &wlt.key {
+                Some(key) => open(key, address.as_bytes(), &encrypted.secret_key)?,
+                None => Vec::new(),
+            };
+            wlt.wallets.insert(address, Wallet { secret_key, public_key: encrypted.public_key });
+        }
+        let plain: Vec<(sled::IVec, sled::IVec)> = db.iter().collect::<std::result::Result<_, _>>()?;
+        for (stored, data) in plain {
+            let wallet: Wallet = bincode::deserialize(&data)?;
+            // Older wallets were stored under a script typed address, move them to their key address.
+            let address = wallet.get_address();
+            if stored != address.as_bytes() {
+                db.insert(&address, data)?;
+                db.remove(stored)?;
+                db.flush()?;
+            }
             wlt.wallets.insert(address, wallet);
         }
+        for item in db.open_tree("scripts")?.into_iter() {
+            let i = item?;
+            let address = String::from_utf8(i.0.to_vec())?;
+            let script = bincode::deserialize(&i.1)?;
+            wlt.scripts.insert(address, script);
+        }
+        for item in db.open_tree("watch_only")?.into_iter() {
+            let i = item?;
+            let address = String::from_utf8(i.0.to_vec())?;
+            wlt.watch_only.insert(address, bincode::deserialize(&i.1)?);
+        }
         //manual drop the db struct.
         drop(db);
         Ok(wlt)
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
+    pub fn add_script(&mut self, redeem_script: Script) -> String {
+        let address = Wallet::get_script_address(&redeem_script);
+        self.scripts.insert(address.clone(), redeem_script);
+        info!("Add script: {}", address);
+        address
+    }
+
+    pub fn get_script(&self, address: &str) -> Option<&Script> {
+        self.scripts.get(address)
+    }
+
+    // The redeem script to put in a PSBT spending from `address`. Without it nobody
+    // could sign the inputs of a P2SH address, so that is an error.
+    pub fn spending_script(&self, address: &str) -> Result<Option<Script>> {
+        let decoded = match Address::decode(address) {
+            Ok(decoded) => decoded,
+            Err(_) => anyhow::bail!("ERROR: Invalid address `{}`", address),
+        };
+        match self.scripts.get(address) {
+            Some(script) => Ok(Some(script.clone())),
+            None if decoded.hash_type == HashType::Script => anyhow::bail!(
+                "ERROR: `{}` is a P2SH address and its redeem script is not in the wallet, run create-multisig first",
+                address
+            ),
+            None => Ok(None),
+        }
+    }
+
+    // Addresses made before P2SH addresses existed were script typed, with the pub_key_hash
+    // as body. Paying one of them now would lock the coins to P2SH with no redeem script,
+    // so the key address is returned instead when the key is in the wallet.
+    pub fn resolve_address(&self, address: &str) -> String {
+        let body = match Address::decode(address) {
+            Ok(decoded) if decoded.hash_type == HashType::Script => decoded.body,
+            _ => return address.to_string(),
+        };
+        if self.scripts.contains_key(address) {
+            return address.to_string();
+        }
+        let key_address = Address {
+            body,
+            scheme: Scheme::Base58,
+            hash_type: HashType::Key,
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+        if self.wallets.contains_key(&key_address) || self.watch_only.contains_key(&key_address) {
+            info!("{} is in the old address format, using {}", address, key_address);
+            key_address
+        } else {
+            address.to_string()
+        }
+    }
+
+    // Same as resolve_address, for an output locked to the address.
+    pub fn resolve_output(&self, out: TXOutput) -> Result<TXOutput> {
+        if out.script_pub_key.p2sh_hash().is_none() {
+            return Ok(out);
+        }
+        let address = Wallet::get_output_address(&out.script_pub_key).unwrap();
+        let resolved = self.resolve_address(&address);
+        if resolved == address {
+            Ok(out)
+        } else {
+            TXOutput::new(out.value, resolved)
+        }
+    }
+
+    // Derive the next address of `account`, the seed is made on first use.
+    pub fn create_wallet(&mut self, account: u32) -> Result<String> {
+        self.create_address(account, false)
+    }
+
+    // A new change address of `account`, for the change of a transaction.
+    pub fn create_change_address(&mut self, account: u32) -> Result<String> {
+        self.create_address(account, true)
+    }
+
+    // The address create_change_address would make, None when there is no seed yet.
+    pub fn next_change_address(&self, account: u32) -> Result<Option<String>> {
+        self.check_unlocked()?;
+        match &self.seed {
+            Some(seed) => Ok(Some(self.next_path(account, true).derive(seed)?.get_address())),
+            None => Ok(None),
+        }
+    }
+
+    fn create_address(&mut self, account: u32, change: bool) -> Result<String> {
+        self.check_unlocked()?;
+        let path = self.next_path(account, change);
+        let seed = self.seed.get_or_insert_with(|| {
+            let mut seed = vec![0; 32];
+            OsRng.fill_bytes(&mut seed);
+            seed
+        });
+        let wallet = path.derive(seed)?;
         let address = wallet.get_address();
         self.wallets.insert(address.clone(), wallet);
-        info!("Create wallet: {}", address);
-        address
+        self.paths.insert(address.clone(), path);
+        info!("Create wallet: {} at {}", address, path);
+        Ok(address)
+    }
+
+    // The first path after those of the receiving or change chain of `account`.
+    fn next_path(&self, account: u32, change: bool) -> KeyPath {
+        let index = self
+            .paths
+            .values()
+            .filter(|path| path.account == account && path.change == change)
+            .map(|path| path.index + 1)
+            .max()
+            .unwrap_or(0);
+        KeyPath { account, change, index }
+    }
+
+    // The seed, to back up a wallet made without a mnemonic. None before the first address.
+    pub fn get_seed(&self) -> Result<Option<&Vec<u8>>> {
+        self.check_unlocked()?;
+        Ok(self.seed.as_ref())
+    }
+
+    // Derive the addresses from `seed`, e.g. of a mnemonic, instead of a random one.
+    pub fn set_seed(&mut self, seed: Vec<u8>) -> Result<()> {
+        self.check_unlocked()?;
+        match &self.seed {
+            Some(current) if *current != seed => anyhow::bail!("ERROR: Wallet already has another seed"),
+            _ => self.seed = Some(seed),
+        }
+        Ok(())
+    }
+
+    // Derive the addresses of `seed` again, those `is_used` tells were used in the chain.
+    // The receiving and change chains of an account end after GAP_LIMIT unused addresses
+    // in a row, the first unused account ends the restore. Returns the addresses found.
+    pub fn restore(&mut self, seed: Vec<u8>, is_used: impl Fn(&str) -> bool) -> Result<Vec<String>> {
+        self.set_seed(seed.clone())?;
+        let mut found = Vec::new();
+        for account in 0.. {
+            let found_before = found.len();
+            for change in [false, true] {
+                let (mut index, mut unused) = (0, 0);
+                while unused < GAP_LIMIT {
+                    let path = KeyPath { account, change, index };
+                    let wallet = path.derive(&seed)?;
+                    let address = wallet.get_address();
+                    if is_used(&address) {
+                        unused = 0;
+                        self.wallets.insert(address.clone(), wallet);
+                        self.paths.insert(address.clone(), path);
+                        found.push(address);
+                    } else {
+                        unused += 1;
+                    }
+                    index += 1;
+                }
+            }
+            if found.len() == found_before {
+                break;
+            }
+        }
+        info!("Restore wallet: {} addresses", found.len());
+        Ok(found)
+    }
+
+    // Add a key of another wallet, it is not derived from the seed.
+    pub fn import_wallet(&mut self, wallet: Wallet) -> Result<String> {
+        self.check_unlocked()?;
+        let address = wallet.get_address();
+        self.wallets.insert(address.clone(), wallet);
+        self.watch_only.remove(&address);
+        info!("Import wallet: {}", address);
+        Ok(address)
+    }
+
+    // Track `address` without its secret key, `public_key` is the key it pays to if known.
+    pub fn add_watch_only(&mut self, address: &str, public_key: Option<Vec<u8>>) -> Result<()> {
+        let hash = match Address::decode(address) {
+            Ok(decoded) => decoded.body,
+            Err(_) => anyhow::bail!("ERROR: Invalid address `{}`", address),
+        };
+        if let Some(public_key) = &public_key {
+            let mut pub_key_hash = public_key.clone();
+            Wallet::hash_pub_key(&mut pub_key_hash);
+            if pub_key_hash != hash {
+                anyhow::bail!("ERROR: The public key is not the one of `{}`", address)
+            }
+        }
+        if self.wallets.contains_key(address) {
+            anyhow::bail!("ERROR: `{}` is already in the wallet with its secret key", address)
+        }
+        self.watch_only.insert(address.to_string(), public_key);
+        info!("Watch address: {}", address);
+        Ok(())
+    }
+
+    pub fn is_watch_only(&self, address: &str) -> bool {
+        self.watch_only.contains_key(address)
+    }
+
+    pub fn get_watch_only_addresses(&self) -> Vec<String> {
+        self.watch_only.keys().cloned().collect()
+    }
+
+    // Every address whose transactions the wallet follows: its keys, the P2SH
+    // addresses it takes part in and the watch-only ones.
+    pub fn get_tracked_addresses(&self) -> HashSet<String> {
+        let mut addresses: HashSet<String> = self.wallets.keys().cloned().collect();
+        addresses.extend(self.scripts.keys().cloned());
+        addresses.extend(self.watch_only.keys().cloned());
+        addresses
+    }
+
+    fn check_unlocked(&self) -> Result<()> {
+        if self.kdf.is_some() && self.key.is_none() {
+            anyhow::bail!("ERROR: Wallet is locked, run unlock first")
+        }
+        Ok(())
     }
 
     pub fn get_all_address(&self) -> Vec<String> {
@@ -94,19 +762,419 @@ impl Wallets {
         addresses
     }
 
+    // Whether `address` was derived for change, not handed out to receive.
+    pub fn is_change(&self, address: &str) -> bool {
+        self.paths.get(address).is_some_and(|path| path.change)
+    }
+
+    // Account of a derived address.
+    pub fn get_account(&self, address: &str) -> Option<u32> {
+        self.paths.get(address).map(|path| path.account)
+    }
+
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
         self.wallets.get(address)
     }
 
+    pub fn is_encrypted(&self) -> bool {
+        self.kdf.is_some()
+    }
+
+    // Encrypt every key with `passphrase`, the wallets are locked afterwards.
+    pub fn encrypt(&mut self, passphrase: &str) -> Result<()> {
+        if self.is_encrypted() {
+            anyhow::bail!("ERROR: Wallet is already encrypted, use change-passphrase")
+        }
+        let (kdf, key) = KdfParams::new(passphrase, KDF_LOG_N, KDF_R, KDF_P);
+        self.kdf = Some(kdf);
+        self.key = Some(key);
+        self.save_all()?;
+        self.lock()
+    }
+
+    // Keep the keys usable by the following commands for `timeout` seconds.
+    pub fn unlock(&mut self, passphrase: &str, timeout: u64) -> Result<()> {
+        let key = match &self.kdf {
+            Some(kdf) => kdf.unlock(passphrase)?,
+            None => anyhow::bail!("ERROR: Wallet is not encrypted"),
+        };
+        let session = Session { key: key.clone(), expires: now()? + timeout };
+        let _ = fs::remove_file(session_path());
+        create_session_file(&session_path())?.write_all(&bincode::serialize(&session)?)?;
+        self.key = Some(key);
+        Ok(())
+    }
+
+    pub fn lock(&mut self) -> Result<()> {
+        if !self.is_encrypted() {
+            anyhow::bail!("ERROR: Wallet is not encrypted")
+        }
+        if fs::metadata(session_path()).is_ok() {
+            fs::remove_file(session_path())?;
+        }
+        self.key = None;
+        self.seed = None;
+        for wallet in self.wallets.values_mut() {
+            wallet.secret_key.clear();
+        }
+        Ok(())
+    }
+
+    // Re-encrypt every key under `new`, the wallets are locked afterwards.
+    pub fn change_passphrase(&mut self, old: &str, new: &str) -> Result<()> {
+        let key = match &self.kdf {
+            Some(kdf) => kdf.unlock(old)?,
+            None => anyhow::bail!("ERROR: Wallet is not encrypted"),
+        };
+        // Reload with the key, the keys may not be decrypted yet.
+        self.key = Some(key);
+        self.reload_secret_keys()?;
+        let kdf = self.kdf.clone().unwrap();
+        let (kdf, key) = KdfParams::new(new, kdf.log_n, kdf.r, kdf.p);
+        self.kdf = Some(kdf);
+        self.key = Some(key);
+        self.save_all()?;
+        // Nor keys sealed under the old passphrase.
+        rewrite_wallet_file(&wallets_path())?;
+        self.lock()
+    }
+
+    fn reload_secret_keys(&mut self) -> Result<()> {
+        let key = self.key.clone().unwrap();
+        let db = wallets_db()?;
+        if let Some(seed) = db.open_tree("meta")?.get("seed")? {
+            self.seed = Some(open(&key, b"seed", &bincode::deserialize(&seed)?)?);
+        }
+        let encrypted = db.open_tree("encrypted")?;
+        for (address, wallet) in self.wallets.iter_mut() {
+            if let Some(data) = encrypted.get(address)? {
+                let entry: EncryptedWallet = bincode::deserialize(&data)?;
+                wallet.secret_key = open(&key, address.as_bytes(), &entry.secret_key)?;
+            }
+        }
+        Ok(())
+    }
+
+    // The key of the current unlock, if it has not expired.
+    fn load_session() -> Result<Option<Vec<u8>>> {
+        Ok(read_session(&session_path())?.map(|session| session.key))
+    }
+
     pub fn save_all(&self) -> Result<()> {
-        let db = sled::open("data/wallets")?;
+        let db = wallets_db()?;
+        let meta = db.open_tree("meta")?;
+        // Plaintext keys left from before the encryption.
+        let mut rewrite = false;
 
-        for (address, wallet) in &self.wallets {
-            let data = bincode::serialize(wallet)?;
-            db.insert(address, data)?;
+        if let Some(kdf) = &self.kdf {
+            let encrypted = db.open_tree("encrypted")?;
+            for (address, wallet) in &self.wallets {
+                // Locked keys are already stored.
+                if wallet.is_locked() {
+                    continue;
+                }
+                let key = match &self.key {
+                    Some(key) => key,
+                    None => anyhow::bail!("ERROR: Wallet is locked, run unlock first"),
+                };
+                let entry = EncryptedWallet {
+                    public_key: wallet.public_key.clone(),
+                    secret_key: seal(key, address.as_bytes(), &wallet.secret_key),
+                };
+                encrypted.insert(address, bincode::serialize(&entry)?)?;
+            }
+            // Locked, the seed is already stored.
+            if let (Some(seed), Some(key)) = (&self.seed, &self.key) {
+                meta.insert("seed", bincode::serialize(&seal(key, b"seed", seed))?)?;
+            }
+            // No plaintext key is left, including those under an older address format.
+            rewrite = !db.is_empty();
+            db.clear()?;
+            meta.insert("kdf", bincode::serialize(kdf)?)?;
+        } else {
+            for (address, wallet) in &self.wallets {
+                let data = bincode::serialize(wallet)?;
+                db.insert(address, data)?;
+            }
+            if let Some(seed) = &self.seed {
+                meta.insert("seed", bincode::serialize(seed)?)?;
+            }
+        }
+        let paths = db.open_tree("paths")?;
+        for (address, path) in &self.paths {
+            paths.insert(address, bincode::serialize(path)?)?;
+        }
+        let scripts = db.open_tree("scripts")?;
+        for (address, script) in &self.scripts {
+            scripts.insert(address, bincode::serialize(script)?)?;
+        }
+        let watch_only = db.open_tree("watch_only")?;
+        // Entries whose secret key was imported since are dropped.
+        watch_only.clear()?;
+        for (address, public_key) in &self.watch_only {
+            watch_only.insert(address, bincode::serialize(public_key)?)?;
         }
         db.flush()?;
-        drop(db);
+        drop((db, meta, paths, scripts, watch_only));
+        if rewrite {
+            rewrite_wallet_file(&wallets_path())?;
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::new_chain;
+    use crate::chainparams::TestDataRoot;
+    use crate::transaction::Transaction;
+    use crate::util::encode_hex;
+
+    #[test]
+    fn test_hash_pub_key() {
+        for (data, hash) in [
+            (Vec::new(), "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb"),
+            ((0..33).collect(), "c31b1d87d352c7f17bc1e24942b05bdd4c3387ea"),
+            // As long as a 1-of-1 multisig redeem script.
+            ((0..71).collect(), "9ba49b4c0a74746a54263a743ecb8c87c40368d3"),
+        ] {
+            let mut data: Vec<u8> = data;
+            Wallet::hash_pub_key(&mut data);
+            assert_eq!(encode_hex(&data), hash);
+        }
+    }
+
+    #[test]
+    fn test_passphrase_and_sealed_keys() {
+        // Cheap parameters, the default ones take a while in debug builds.
+        let (kdf, key) = KdfParams::new("correct horse", 4, 8, 1);
+        assert_eq!(kdf.unlock("correct horse").unwrap(), key);
+        assert!(kdf.unlock("wrong horse").is_err());
+
+        let wallet = Wallet::new();
+        let address = wallet.get_address();
+        let sealed = seal(&key, address.as_bytes(), &wallet.secret_key);
+        assert_ne!(sealed.ciphertext, wallet.secret_key);
+        assert_eq!(open(&key, address.as_bytes(), &sealed).unwrap(), wallet.secret_key);
+        // Bound to the address it was stored under.
+        assert!(open(&key, Wallet::new().get_address().as_bytes(), &sealed).is_err());
+    }
+
+    #[test]
+    fn test_export_secret_key() {
+        let wallet = Wallet::new();
+        let exported = wallet.export_secret_key().unwrap();
+        assert_eq!(Wallet::import_secret_key(&exported).unwrap(), wallet);
+
+        // A typo is caught by the checksum.
+        let mut typo = exported.into_bytes();
+        typo[10] = if typo[10] == b'2' { b'3' } else { b'2' };
+        assert!(Wallet::import_secret_key(&String::from_utf8(typo).unwrap()).is_err());
+        // Addresses use the same encoding.
+        let mut address = vec![0];
+        address.extend(Address::decode(&wallet.get_address()).unwrap().body);
+        assert_eq!(encode_base58check(&address), wallet.get_address());
+    }
+
+    #[test]
+    fn test_pay_to_old_address_format() {
+        let _root = TestDataRoot::new("old_address_format");
+        let payer = Wallet::new();
+        let owner = Wallet::new();
+        let old_address = Address {
+            body: Address::decode(&owner.get_address()).unwrap().body,
+            scheme: Scheme::Base58,
+            hash_type: HashType::Script,
+            ..Default::default()
+        }
+        .encode()
+        .unwrap();
+
+        let mut utxo_set = new_chain(&payer, params().coinbase_maturity - 1);
+        // A wallet file written before P2SH, the key stored under its script typed address.
+        let db = wallets_db().unwrap();
+        db.insert(&old_address, bincode::serialize(&owner).unwrap()).unwrap();
+        drop(db);
+
+        let wallets = Wallets::new().unwrap();
+        assert!(wallets.get_wallet(&owner.get_address()).is_some());
+        let db = wallets_db().unwrap();
+        assert!(db.get(&old_address).unwrap().is_none());
+        assert!(db.get(owner.get_address()).unwrap().is_some());
+        drop(db);
+
+        // As is, the payment would be locked to P2SH with no redeem script.
+        let out = TXOutput::new(30, old_address.clone()).unwrap();
+        assert!(out.script_pub_key.p2sh_hash().is_some());
+        assert_eq!(wallets.resolve_address(&old_address), owner.get_address());
+        let out = wallets.resolve_output(out).unwrap();
+        let tx = Transaction::new_utxo_with_outputs(&payer, vec![out], 0, &utxo_set).unwrap();
+        let height = utxo_set.blockchain.get_best_height().unwrap() + 1;
+        let cbtx = Transaction::new_coinbase(payer.get_address(), String::new(), height).unwrap();
+        let block = utxo_set.mine_block(vec![cbtx, tx]).unwrap();
+        utxo_set.update(&block).unwrap();
+
+        let back = TXOutput::new(30, payer.get_address()).unwrap();
+        let spend = Transaction::new_utxo_with_outputs(&owner, vec![back], 0, &utxo_set).unwrap();
+        utxo_set.blockchain.verify_transaction(&spend).unwrap();
+
+        // Other script addresses are left alone.
+        let stranger = Wallet::get_script_address(&Script::new_p2pkh(&[1; 20]));
+        assert_eq!(wallets.resolve_address(&stranger), stranger);
+    }
+
+    // Whether a file under `dir` holds `needle`.
+    fn files_contain(dir: &str, needle: &[u8]) -> bool {
+        fs::read_dir(dir).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files_contain(path.to_str().unwrap(), needle)
+            } else {
+                fs::read(&path).unwrap().windows(needle.len()).any(|w| w == needle)
+            }
+        })
+    }
+
+    #[test]
+    fn test_encrypt_erases_plaintext() {
+        let _root = TestDataRoot::new("encrypt_erases_plaintext");
+        let wallet = Wallet::new();
+        let mut ws = Wallets::new().unwrap();
+        ws.wallets.insert(wallet.get_address(), wallet.clone());
+        ws.save_all().unwrap();
+        assert!(files_contain(&wallets_path(), &wallet.secret_key));
+
+        ws.encrypt("correct horse").unwrap();
+        assert!(!files_contain(&wallets_path(), &wallet.secret_key));
+        assert!(fs::metadata(format!("{}.old", wallets_path())).is_err());
+        assert!(fs::metadata(format!("{}.rewrite", wallets_path())).is_err());
+
+        let mut ws = Wallets::new().unwrap();
+        assert!(ws.get_wallet(&wallet.get_address()).unwrap().is_locked());
+        ws.change_passphrase("correct horse", "battery staple").unwrap();
+        let mut ws = Wallets::new().unwrap();
+        assert!(ws.unlock("correct horse", 60).is_err());
+        ws.unlock("battery staple", 60).unwrap();
+        let ws = Wallets::new().unwrap();
+        assert_eq!(ws.get_wallet(&wallet.get_address()).unwrap(), &wallet);
+    }
+
+    #[test]
+    fn test_sweep_sessions() {
+        let _root = TestDataRoot::new("sweep_sessions");
+        let mut ws = Wallets::new().unwrap();
+        ws.encrypt("correct horse").unwrap();
+        ws.unlock("correct horse", 60).unwrap();
+        create_wallet_file("other").unwrap();
+        let expired = format!("{}.session", wallet_path("other"));
+        let session = Session { key: vec![1; 32], expires: now().unwrap() - 1 };
+        fs::write(&expired, bincode::serialize(&session).unwrap()).unwrap();
+
+        sweep_sessions().unwrap();
+        assert!(fs::metadata(&expired).is_err());
+        assert!(fs::metadata(session_path()).is_ok());
+    }
+
+    #[test]
+    fn test_export_seed() {
+        let _root = TestDataRoot::new("export_seed");
+        let mut ws = Wallets::new().unwrap();
+        assert!(ws.get_seed().unwrap().is_none());
+        let addresses = vec![ws.create_wallet(0).unwrap(), ws.create_wallet(0).unwrap()];
+        let seed = ws.get_seed().unwrap().unwrap().clone();
+
+        // Nothing was saved, this is a new wallet.
+        let mut restored = Wallets::new().unwrap();
+        let found = restored.restore(seed, |address| addresses.iter().any(|a| a == address)).unwrap();
+        assert_eq!(found, addresses);
+    }
+
+    #[test]
+    fn test_watch_only() {
+        let _root = TestDataRoot::new("watch_only");
+        let mut ws = Wallets::new().unwrap();
+        let held = ws.create_wallet(0).unwrap();
+        let watched = Wallet::new();
+        let script_address = Wallet::get_script_address(&Script::new_multisig(1, std::slice::from_ref(&watched.public_key)).unwrap());
+
+        assert!(ws.add_watch_only("not an address", None).is_err());
+        assert!(ws.add_watch_only(&held, None).is_err());
+        assert!(ws.add_watch_only(&watched.get_address(), Some(Wallet::new().public_key)).is_err());
+        assert!(ws.add_watch_only(&script_address, Some(watched.public_key.clone())).is_err());
+        ws.add_watch_only(&watched.get_address(), Some(watched.public_key.clone())).unwrap();
+        ws.add_watch_only(&script_address, None).unwrap();
+        ws.save_all().unwrap();
+
+        let mut ws = Wallets::new().unwrap();
+        assert!(ws.is_watch_only(&watched.get_address()));
+        assert!(!ws.is_watch_only(&held));
+        assert!(ws.get_wallet(&watched.get_address()).is_none());
+        assert!(ws.get_tracked_addresses().contains(&script_address));
+
+        // A PSBT of the script address could never be signed.
+        assert!(ws.spending_script(&watched.get_address()).unwrap().is_none());
+        assert!(ws.spending_script(&script_address).is_err());
+
+        // Importing the secret key ends the watching.
+        ws.import_wallet(watched.clone()).unwrap();
+        assert!(!ws.is_watch_only(&watched.get_address()));
+        ws.save_all().unwrap();
+        assert!(!Wallets::new().unwrap().is_watch_only(&watched.get_address()));
+    }
+
+    #[test]
+    fn test_change_addresses() {
+        let _root = TestDataRoot::new("change_addresses");
+        let mut ws = Wallets::new().unwrap();
+        assert!(ws.next_change_address(0).unwrap().is_none());
+        let receiving = ws.create_wallet(0).unwrap();
+
+        // Only a peek, until the change address is made.
+        let next = ws.next_change_address(0).unwrap().unwrap();
+        assert_eq!(ws.next_change_address(0).unwrap().unwrap(), next);
+        assert!(ws.get_wallet(&next).is_none());
+        assert_eq!(ws.create_change_address(0).unwrap(), next);
+        assert!(ws.is_change(&next) && !ws.is_change(&receiving));
+        assert_ne!(ws.next_change_address(0).unwrap().unwrap(), next);
+        // Each account has its own change chain.
+        assert_ne!(ws.next_change_address(1).unwrap().unwrap(), next);
+        ws.save_all().unwrap();
+
+        // Paths stored before change addresses existed, {account, index}.
+        let db = wallets_db().unwrap();
+        db.open_tree("paths").unwrap().insert(&receiving, bincode::serialize(&(0u32, 0u32)).unwrap()).unwrap();
+        drop(db);
+        let ws = Wallets::new().unwrap();
+        assert!(!ws.is_change(&receiving) && ws.is_change(&next));
+        assert_eq!(ws.get_account(&receiving), Some(0));
+    }
+
+    #[test]
+    fn test_wallet_files() {
+        let _root = TestDataRoot::new("wallet_files");
+        for name in ["", "../cold", "a/b", "a.b", "loaded", DEFAULT_WALLET] {
+            assert!(create_wallet_file(name).is_err(), "{}", name);
+        }
+        create_wallet_file("cold").unwrap();
+        assert!(create_wallet_file("cold").is_err());
+        assert!(load_wallet_file("cold").is_err());
+        // Another directory next to the wallet files is not one.
+        fs::create_dir_all(format!("{}/../outside", wallet_dir())).unwrap();
+        assert!(load_wallet_file("../outside").is_err());
+        fs::create_dir_all(format!("{}/cold.old", wallet_dir())).unwrap();
+        let files = list_wallet_files().unwrap();
+        assert_eq!(files, vec![(DEFAULT_WALLET.to_string(), true), (String::from("cold"), true)]);
+
+        // Unloading locks the wallet file and it can not be picked anymore.
+        let session = format!("{}.session", wallet_path("cold"));
+        fs::write(&session, b"key").unwrap();
+        unload_wallet_file("cold").unwrap();
+        assert!(fs::metadata(&session).is_err());
+        assert!(select("cold").is_err());
+        assert!(select("missing").is_err());
+        assert!(unload_wallet_file("cold").is_err());
+        assert!(unload_wallet_file(DEFAULT_WALLET).is_err());
+        load_wallet_file("cold").unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Runs the binary in `dir`, on `chain` when given, and returns its output.
+fn run(dir: &PathBuf, chain: Option<&str>, args: &[&str]) -> Result<String, String> {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_blockchain_rust"));
+    cmd.current_dir(dir).args(args);
+    if let Some(chain) = chain {
+        cmd.args(["--chain", chain]);
+    }
+    let output = cmd.output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(stdout + &String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+fn field<'a>(output: &'a str, name: &str) -> &'a str {
+    let prefix = format!("{}: ", name);
+    output.lines().find_map(|l| l.strip_prefix(&prefix)).unwrap()
+}
+
+fn create_wallet(dir: &PathBuf) -> String {
+    let output = run(dir, None, &["create-wallet"]).unwrap();
+    output.split('`').nth(1).unwrap().to_string()
+}
+
+fn balance(dir: &PathBuf, chain: &str, address: &str) -> i32 {
+    let output = run(dir, Some(chain), &["get-balance", address]).unwrap();
+    output.trim().rsplit(' ').next().unwrap().parse().unwrap()
+}
+
+// Alice trades 30 coins of the main chain for 20 coins of the test chain with Bob.
+// Both chains live in one data root and every step mines its own block with `-m`,
+// instead of going through a `start-node` per chain: a running node holds the
+// lock on its chain database, so the CLI could not read the HTLCs while it runs.
+#[test]
+fn test_atomic_swap() {
+    let dir = std::env::temp_dir().join(format!("atomic_swap_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let alice = create_wallet(&dir);
+    let bob = create_wallet(&dir);
+    let miner = create_wallet(&dir);
+    run(&dir, Some("main"), &["create", &alice]).unwrap();
+    run(&dir, Some("test"), &["create", &bob]).unwrap();
+    // Let the genesis coins mature.
+    run(&dir, Some("main"), &["generate", "10", &miner]).unwrap();
+    run(&dir, Some("test"), &["generate", "5", &miner]).unwrap();
+
+    // Alice locks her coins first, with the later timeout.
+    let output = run(&dir, Some("main"), &["htlc-create", &alice, &bob, "30", "30", "-m"]).unwrap();
+    let secret = field(&output, "secret").to_string();
+    let hash = field(&output, "hash").to_string();
+    let alice_htlc = field(&output, "htlc").split(' ').next().unwrap().to_string();
+
+    // Bob locks his coins under the same hash.
+    let output = run(&dir, Some("test"), &["htlc-create", &bob, &alice, "20", "20", "--hash", &hash, "-m"]).unwrap();
+    let bob_htlc = field(&output, "htlc").split(' ').next().unwrap().to_string();
+
+    // Nobody can take the coins back before the timeout, nor redeem without the secret.
+    assert!(run(&dir, Some("test"), &["htlc-refund", &bob_htlc, "0", &bob, "-m"]).is_err());
+    assert!(run(&dir, Some("main"), &["htlc-preimage", &alice_htlc, "0"]).is_err());
+    // An HTLC of one chain does not exist on the other.
+    assert!(run(&dir, Some("main"), &["htlc-redeem", &bob_htlc, "0", &alice, &secret]).is_err());
+
+    // Alice redeems on the test chain, revealing the secret to Bob.
+    run(&dir, Some("test"), &["htlc-redeem", &bob_htlc, "0", &alice, &secret, "-m"]).unwrap();
+    let output = run(&dir, Some("test"), &["htlc-preimage", &bob_htlc, "0"]).unwrap();
+    let revealed = field(&output, "preimage").to_string();
+    assert_eq!(revealed, secret);
+    run(&dir, Some("main"), &["htlc-redeem", &alice_htlc, "0", &bob, &revealed, "-m"]).unwrap();
+
+    // Both got the coins of the other chain, each mined one block on each chain.
+    assert_eq!(balance(&dir, "main", &alice), 100 - 30 + 100);
+    assert_eq!(balance(&dir, "main", &bob), 30 + 100);
+    assert_eq!(balance(&dir, "test", &bob), 100 - 20 + 100);
+    assert_eq!(balance(&dir, "test", &alice), 20 + 100);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
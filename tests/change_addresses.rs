@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Runs the binary in `dir` and returns its output.
+fn run(dir: &PathBuf, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_blockchain_rust")).current_dir(dir).args(args).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(stdout + &String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+fn created_address(output: &str) -> String {
+    output.split('`').nth(1).unwrap().to_string()
+}
+
+fn change_addresses(dir: &PathBuf) -> Vec<String> {
+    let output = run(dir, &["list-addresses"]).unwrap();
+    output.lines().filter_map(|l| l.strip_suffix(" (change)")).map(String::from).collect()
+}
+
+// A change address is only taken when the transaction has change.
+#[test]
+fn test_change_addresses() {
+    let dir = std::env::temp_dir().join(format!("change_addresses_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let alice = created_address(&run(&dir, &["create-wallet"]).unwrap());
+    let bob = created_address(&run(&dir, &["create-wallet"]).unwrap());
+    run(&dir, &["create", &alice]).unwrap();
+    run(&dir, &["generate", "9", &bob]).unwrap();
+
+    // The genesis coin is spent whole.
+    run(&dir, &["send", &alice, &bob, "100", "-m"]).unwrap();
+    assert!(change_addresses(&dir).is_empty());
+
+    run(&dir, &["send", &bob, &alice, "30", "-m"]).unwrap();
+    let change = change_addresses(&dir);
+    assert_eq!(change.len(), 1);
+    let balance = run(&dir, &["get-balance", &change[0]]).unwrap();
+    assert!(balance.trim().ends_with(" 70"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
@@ -0,0 +1,69 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Runs the binary in `dir` with `input` on stdin and returns its output.
+fn run_with_input(dir: &PathBuf, args: &[&str], input: &str) -> Result<String, String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_blockchain_rust"))
+        .current_dir(dir)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(stdout + &String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+fn run(dir: &PathBuf, args: &[&str]) -> Result<String, String> {
+    run_with_input(dir, args, "")
+}
+
+fn created_address(output: &str) -> String {
+    output.split('`').nth(1).unwrap().to_string()
+}
+
+// Keys, unlock sessions and histories of two wallet files never mix.
+#[test]
+fn test_wallet_files_are_apart() {
+    let dir = std::env::temp_dir().join(format!("wallet_files_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    run(&dir, &["create-wallet-file", "cold"]).unwrap();
+    let cold = created_address(&run(&dir, &["create-wallet", "--wallet", "cold"]).unwrap());
+    let hot = created_address(&run(&dir, &["create-wallet"]).unwrap());
+    assert!(!run(&dir, &["list-addresses"]).unwrap().contains(&cold));
+    assert!(!run(&dir, &["list-addresses", "--wallet", "cold"]).unwrap().contains(&hot));
+    assert!(run(&dir, &["dump-privkey", &cold]).is_err());
+
+    run(&dir, &["create", &cold]).unwrap();
+    let history = run(&dir, &["list-transactions", "--wallet", "cold"]).unwrap();
+    assert_eq!(history.lines().count(), 1);
+    assert!(history.contains("amount +100"));
+    assert!(run(&dir, &["list-transactions"]).unwrap().trim().is_empty());
+
+    // Unlocking one wallet file leaves the other locked.
+    run_with_input(&dir, &["encrypt-wallet"], "hot pass\n").unwrap();
+    run_with_input(&dir, &["encrypt-wallet", "--wallet", "cold"], "cold pass\n").unwrap();
+    assert!(run_with_input(&dir, &["unlock", "--wallet", "cold"], "hot pass\n").is_err());
+    run_with_input(&dir, &["unlock", "--wallet", "cold"], "cold pass\n").unwrap();
+    run(&dir, &["dump-privkey", &cold, "--wallet", "cold"]).unwrap();
+    assert!(run(&dir, &["dump-privkey", &hot]).is_err());
+
+    // Unloading ends the session.
+    run(&dir, &["unload-wallet", "cold"]).unwrap();
+    assert!(run(&dir, &["list-addresses", "--wallet", "cold"]).is_err());
+    run(&dir, &["load-wallet", "cold"]).unwrap();
+    assert!(run(&dir, &["dump-privkey", &cold, "--wallet", "cold"]).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// Runs the binary in `dir` and returns its output.
+fn run(dir: &PathBuf, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_blockchain_rust")).current_dir(dir).args(args).output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        Err(stdout + &String::from_utf8(output.stderr).unwrap())
+    }
+}
+
+fn created_address(output: &str) -> String {
+    output.split('`').nth(1).unwrap().to_string()
+}
+
+// The cold wallet file holds the key, the default one only watches its address.
+#[test]
+fn test_watch_only() {
+    let dir = std::env::temp_dir().join(format!("watch_only_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    run(&dir, &["create-wallet-file", "cold"]).unwrap();
+    let cold = created_address(&run(&dir, &["create-wallet", "--wallet", "cold"]).unwrap());
+    let miner = created_address(&run(&dir, &["create-wallet"]).unwrap());
+    let payee = created_address(&run(&dir, &["create-wallet"]).unwrap());
+    run(&dir, &["create", &cold]).unwrap();
+    run(&dir, &["generate", "9", &miner]).unwrap();
+
+    let pub_key = run(&dir, &["get-pubkey", &cold, "--wallet", "cold"]).unwrap();
+    let output = run(&dir, &["import-pubkey", pub_key.trim()]).unwrap();
+    assert_eq!(created_address(&output), cold);
+    let balances = run(&dir, &["list-balances"]).unwrap();
+    assert!(balances.contains(&format!("{} 100 (watch-only)", cold)));
+    assert!(balances.contains("total: 900, watch-only: 100"));
+
+    // Sending from the watched address writes a PSBT for the cold wallet to sign.
+    let output = run(&dir, &["send", &cold, &payee, "30"]).unwrap();
+    let file = output.split("written to ").nth(1).unwrap().split(',').next().unwrap().to_string();
+    assert!(run(&dir, &["finalize-and-broadcast", &file, "-m", &miner]).is_err());
+    run(&dir, &["sign-psbt", &file, &cold, "--wallet", "cold"]).unwrap();
+    run(&dir, &["finalize-and-broadcast", &file, "-m", &miner]).unwrap();
+    let balances = run(&dir, &["list-balances"]).unwrap();
+    assert!(balances.contains(&format!("{} 70 (watch-only)", cold)));
+    assert!(balances.contains(&format!("{} 30\n", payee)));
+
+    // Without its redeem script, nobody could sign for a P2SH address.
+    let other = run(&dir, &["get-pubkey", &payee]).unwrap();
+    let output = run(&dir, &["create-multisig", "1", other.trim(), "--wallet", "cold"]).unwrap();
+    let multisig = output.lines().next().unwrap().strip_prefix("address: ").unwrap().to_string();
+    // Its rescan finds what it was paid like for a key address.
+    run(&dir, &["send", &payee, &multisig, "5", "-m"]).unwrap();
+    let output = run(&dir, &["import-address", &multisig, "--rescan"]).unwrap();
+    assert!(output.contains(&format!("Balance of `{}`; 5", multisig)), "{}", output);
+    let err = run(&dir, &["send", &multisig, &payee, "1"]).unwrap_err();
+    assert!(err.contains("redeem script"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}